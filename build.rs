@@ -0,0 +1,31 @@
+// Capture the commit and compiler the exporter is built from, for the build_info metric
+use std::env;
+use std::process::Command;
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    match output.status.success() {
+        true => Some(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => None,
+    }
+}
+
+fn main() {
+    // Docker builds have no .git directory, so the commit can be passed in instead
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(|| output("git", &["rev-parse", "--short", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc = output(&rustc, &["--version"])
+        .and_then(|v| v.split_whitespace().nth(1).map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=BUILD_COMMIT={commit}");
+    println!("cargo:rustc-env=BUILD_RUSTC={rustc}");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,89 @@
+// Optional access log of every request, written straight to stdout so that it is neither
+// filtered by the log level nor deduplicated, for audit purposes
+use axum::{
+    extract::ConnectInfo,
+    http::{header::CONTENT_LENGTH, Request},
+    middleware::Next,
+    response::IntoResponse,
+};
+use chrono::Local;
+use serde_json::json;
+use std::io::Write;
+use std::time::Instant;
+
+use crate::listener::Peer;
+use crate::request_id::X_REQUEST_ID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Common,
+    Json,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format {
+            "common" => Some(Format::Common),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+pub async fn access_log<B>(req: Request<B>, next: Next<B>, format: Format) -> impl IntoResponse {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let version = req.version();
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<Peer>>()
+        .map(|ConnectInfo(peer)| peer.clone());
+    let remote = peer
+        .as_ref()
+        .map(|peer| peer.addr.ip().to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let subject = peer.and_then(|peer| peer.subject);
+    let request_id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+
+    let response = next.run(req).await;
+    let latency = start.elapsed();
+    let status = response.status().as_u16();
+    let size = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+
+    let line = match format {
+        // Common log format, with the latency in microseconds and request id appended
+        Format::Common => format!(
+            "{remote} - - [{}] \"{method} {uri} {version:?}\" {status} {size} {} {request_id}",
+            Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            latency.as_micros()
+        ),
+        Format::Json => json!({
+            "date": Local::now().to_rfc3339(),
+            "remote_addr": remote,
+            "method": method.as_str(),
+            "path": uri.path(),
+            "query": uri.query(),
+            "version": format!("{version:?}"),
+            "status": status,
+            "size": size.parse::<u64>().ok(),
+            "latency_seconds": latency.as_secs_f64(),
+            "request_id": request_id,
+            "client_subject": subject,
+        })
+        .to_string(),
+    };
+    let _ = writeln!(std::io::stdout().lock(), "{line}");
+
+    response
+}
@@ -0,0 +1,651 @@
+// Aggregate the line items of the invoices into totals and rates per sku. Nothing here talks
+// to Atlas or sets metrics, so that the billing logic can be tested on its own.
+use chrono::{DateTime, Duration, Utc};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::sku::UnitKind;
+use crate::state::{Compressed, LineItem, DEFAULT_CURRENCY};
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    // Number of series kept per metric, 0 keeps all
+    pub max_series: usize,
+}
+
+static OTHER: &str = "__other__";
+
+// Usage ending longer ago than this is no longer billed at the current rate, such as a
+// cluster that was terminated. Atlas meters a day at a time, and lags a few hours behind.
+const RATE_WINDOW: Duration = Duration::hours(30);
+
+// Key of the __other__ series a folded series is summed into, at first per project and sku,
+// or per org and sku when there are too many projects for that
+fn other_key(value: &Compressed, per_org: bool) -> String {
+    format!(
+        "{}/{}/{}/{}",
+        value.org_id.as_deref().unwrap_or_default(),
+        match per_org {
+            true => OTHER,
+            false => value.group_id.as_deref().unwrap_or_default(),
+        },
+        OTHER,
+        value.sku
+    )
+}
+
+// Number of series to keep, so that along with the __other__ series they are folded into
+// there are no more than max
+fn keep(values: &[(String, Compressed)], max: usize, per_org: bool) -> Option<usize> {
+    let mut keep = max.min(values.len());
+    let mut others: HashMap<String, usize> = HashMap::new();
+    for (_, value) in &values[keep..] {
+        *others.entry(other_key(value, per_org)).or_insert(0) += 1;
+    }
+    while keep + others.len() > max {
+        if keep == 0 {
+            return None;
+        }
+        keep -= 1;
+        *others
+            .entry(other_key(&values[keep].1, per_org))
+            .or_insert(0) += 1;
+    }
+    Some(keep)
+}
+
+// Orgs with generated cluster names can produce an unbounded number of series. Keep the most
+// expensive series, and fold the rest into __other__ clusters, within max series in all.
+fn limit_series(
+    map: HashMap<String, Compressed>,
+    max: usize,
+    metric: &'static str,
+) -> HashMap<String, Compressed> {
+    if max == 0 || map.len() <= max {
+        return map;
+    }
+
+    let mut values: Vec<(String, Compressed)> = map.into_iter().collect();
+    values.sort_by_key(|(_, value)| Reverse(value.total_price_cents));
+
+    let (keep, per_org) = match keep(&values, max, false) {
+        Some(keep) => (keep, false),
+        None => (keep(&values, max, true).unwrap_or(0), true),
+    };
+    let overflow = values.split_off(keep);
+
+    log::debug!(
+        "{} exceeds {} series, folding {} into {}",
+        metric,
+        max,
+        overflow.len(),
+        OTHER
+    );
+    metrics::counter!(
+        "atlas_billing_series_dropped_total",
+        overflow.len() as u64,
+        "metric" => metric
+    );
+
+    let mut map: HashMap<String, Compressed> = values.into_iter().collect();
+    for (_key, mut value) in overflow {
+        let name = other_key(&value, per_org);
+        match map.get_mut(&name) {
+            Some(k) => {
+                k.total_price_cents += value.total_price_cents;
+                k.quantity += value.quantity;
+                k.unit_price_dollars += value.unit_price_dollars;
+                k.end_date = k.end_date.clone().max(value.end_date);
+            }
+            None => {
+                value.cluster_name = Some(Arc::from(OTHER));
+                if per_org {
+                    value.group_name = Some(Arc::from(OTHER));
+                    value.group_id = Some(Arc::from(OTHER));
+                }
+                map.insert(name, value);
+            }
+        }
+    }
+
+    // Only when the orgs and sku's alone make up more than max series, are the cheapest
+    // of them left out altogether
+    if map.len() > max {
+        let mut values: Vec<(String, Compressed)> = map.into_iter().collect();
+        values.sort_by_key(|(_, value)| Reverse(value.total_price_cents));
+        let dropped = values.split_off(max);
+        log::warn!(
+            "{} has more than {} orgs and sku's, leaving out {} series",
+            metric,
+            max,
+            dropped.len()
+        );
+        map = values.into_iter().collect();
+    }
+
+    map
+}
+
+// Names repeat across thousands of line items, so each is only allocated once and shared by
+// the series it appears in
+#[derive(Debug, Default)]
+struct Interner(HashSet<Arc<str>>);
+
+impl Interner {
+    fn get(&mut self, value: &str) -> Arc<str> {
+        match self.0.get(value) {
+            Some(shared) => shared.clone(),
+            None => {
+                let shared: Arc<str> = Arc::from(value);
+                self.0.insert(shared.clone());
+                shared
+            }
+        }
+    }
+
+    fn compress(&mut self, item: &LineItem) -> Compressed {
+        Compressed {
+            org_id: item.org_id.as_deref().map(|v| self.get(v)),
+            cluster_name: item.cluster_name.as_deref().map(|v| self.get(v)),
+            quantity: item.quantity,
+            sku: self.get(&item.sku),
+            group_name: item.group_name.as_deref().map(|v| self.get(v)),
+            group_id: item.group_id.as_deref().map(|v| self.get(v)),
+            total_price_cents: item.total_price_cents,
+            unit: self.get(&item.unit),
+            unit_price_dollars: item.unit_price_dollars,
+            start_date: item.start_date.clone(),
+            end_date: item.end_date.clone(),
+            currency: self.get(item.currency.as_deref().unwrap_or(DEFAULT_CURRENCY)),
+            credit_type: item.credit_type().map(|v| self.get(v)),
+        }
+    }
+
+    // The org, project, cluster and sku of a line item, as in LineItem::key
+    fn key(&mut self, item: &LineItem) -> Key {
+        [
+            self.get(item.org_id.as_deref().unwrap_or_default()),
+            self.get(item.group_id.as_deref().unwrap_or_default()),
+            self.get(item.cluster_name.as_deref().unwrap_or_default()),
+            self.get(&item.sku),
+        ]
+    }
+}
+
+type Key = [Arc<str>; 4];
+
+// Sums up the line items per org, project, cluster and sku one at a time, as they are read
+// from the invoices, into the total so far and the rate of the most recent day
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    options: Options,
+    // Usage ending before this has no rate
+    since: Option<DateTime<Utc>>,
+    strings: Interner,
+    totals: HashMap<Key, Compressed>,
+    // Most recent usage date of each org seen so far, and the rates of that day. Orgs are
+    // not necessarily metered up to the same day, such as when an invoice lags behind.
+    rates: HashMap<Arc<str>, (String, HashMap<Key, Compressed>)>,
+    credits: HashMap<Key, Compressed>,
+}
+
+// Sum an item into a total, keeping track of the most recent usage included in it
+fn add(map: &mut HashMap<Key, Compressed>, strings: &mut Interner, key: Key, item: &LineItem) {
+    match map.get_mut(&key) {
+        Some(k) => {
+            k.total_price_cents += item.total_price_cents;
+            k.quantity += item.quantity;
+            if item.end_date > k.end_date {
+                k.end_date.clone_from(&item.end_date);
+            }
+        }
+        None => {
+            map.insert(key, strings.compress(item));
+        }
+    }
+}
+
+// The series keyed as org/project/cluster/sku
+fn series(map: HashMap<Key, Compressed>) -> HashMap<String, Compressed> {
+    map.into_iter()
+        .map(|(key, value)| (key.join("/"), value))
+        .collect()
+}
+
+impl Aggregator {
+    // Rates are taken from usage that ended within 30 hours of now. Without now, such as for
+    // an invoice saved to a file, they are taken from the most recent usage however old.
+    pub fn new(now: Option<DateTime<Utc>>, options: &Options) -> Aggregator {
+        Aggregator {
+            options: options.clone(),
+            since: now.map(|now| now - RATE_WINDOW),
+            ..Default::default()
+        }
+    }
+
+    fn is_current(&self, end_date: &str) -> bool {
+        match self.since {
+            Some(since) => DateTime::parse_from_rfc3339(end_date).is_ok_and(|end| end >= since),
+            None => true,
+        }
+    }
+
+    pub fn push(&mut self, item: &LineItem) {
+        let key = self.strings.key(item);
+
+        // Credits, discounts and other adjustments are kept apart from usage. They
+        // usually cover the whole billing period, so only usage sets the current date.
+        if item.is_credit() {
+            add(&mut self.credits, &mut self.strings, key, item);
+            return;
+        }
+
+        log::debug!("Working on {} from {}", key.join("/"), item.end_date);
+
+        // Only include metric in the rates if the end_date is recent, and the most recent
+        // one of its org
+        if self.is_current(&item.end_date) {
+            let (current_date, rates) = self.rates.entry(key[0].clone()).or_default();
+            if item.end_date > *current_date {
+                current_date.clone_from(&item.end_date);
+                rates.clear();
+            }
+            if item.end_date == *current_date {
+                // The same sku present in multiple regions has the same end date,
+                // so get the sum of all
+                match rates.get_mut(&key) {
+                    Some(k) => k.unit_price_dollars += item.unit_price_dollars,
+                    None => {
+                        rates.insert(key.clone(), self.strings.compress(item));
+                    }
+                }
+            }
+        }
+
+        // Atlas prices sku's per region, so we need to get the sum
+        add(&mut self.totals, &mut self.strings, key, item);
+    }
+
+    // The totals, rates and credits, with the number of series per metric kept in check
+    pub fn finish(self) -> Aggregation {
+        let max = self.options.max_series;
+        Aggregation {
+            totals: limit_series(series(self.totals), max, "atlas_billing_item_cents_total"),
+            rates: limit_series(
+                series(
+                    self.rates
+                        .into_values()
+                        .flat_map(|(_, rates)| rates)
+                        .collect(),
+                ),
+                max,
+                "atlas_billing_item_cents_rate",
+            ),
+            credits: series(self.credits),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Aggregation {
+    pub totals: HashMap<String, Compressed>,
+    pub rates: HashMap<String, Compressed>,
+    pub credits: HashMap<String, Compressed>,
+}
+
+// Sum up the line items per org, project, cluster and sku, into the total so far and the
+// rate of the most recent day
+pub fn aggregate(
+    line_items: &[LineItem],
+    now: Option<DateTime<Utc>>,
+    options: &Options,
+) -> (HashMap<String, Compressed>, HashMap<String, Compressed>) {
+    let mut aggregator = Aggregator::new(now, options);
+    line_items.iter().for_each(|item| aggregator.push(item));
+    let aggregation = aggregator.finish();
+    (aggregation.totals, aggregation.rates)
+}
+
+// Sum up the credits, discounts and other adjustments per org, project, cluster and sku,
+// keeping the sign they have on the invoice
+pub fn credits(line_items: &[LineItem]) -> HashMap<String, Compressed> {
+    let mut aggregator = Aggregator::default();
+    line_items.iter().for_each(|item| aggregator.push(item));
+    aggregator.finish().credits
+}
+
+// Price per hour of a rate, converting sku's priced per day or per month. Sku's billed per
+// use, such as data scanned or returned, have no hourly rate, and neither does a day or month
+// without any quantity to divide the price by.
+pub fn hourly_rate(value: &Compressed) -> Option<f64> {
+    let kind = UnitKind::from_unit(&value.unit);
+    match kind {
+        UnitKind::Hourly => Some(value.unit_price_dollars),
+        UnitKind::Daily | UnitKind::Monthly if value.quantity <= 0.0 => None,
+        UnitKind::Daily | UnitKind::Monthly => {
+            let hours = kind.hours().unwrap_or(24.0);
+            Some(value.total_price_cents as f64 / value.quantity / 100.0 / hours)
+        }
+        UnitKind::Usage => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(cluster: &str, sku: &str, unit: &str, end_date: &str, cents: i64) -> LineItem {
+        LineItem {
+            cluster_name: Some(cluster.to_string()),
+            created: "2024-05-01T00:00:00Z".to_string(),
+            end_date: end_date.to_string(),
+            quantity: 24.0,
+            group_name: Some("project".to_string()),
+            group_id: Some("group".to_string()),
+            sku: sku.to_string(),
+            start_date: "2024-05-01T00:00:00Z".to_string(),
+            total_price_cents: cents,
+            unit: unit.to_string(),
+            unit_price_dollars: cents as f64 / 2400.0,
+            org_id: Some("org".to_string()),
+            currency: None,
+        }
+    }
+
+    // A few hours after the last day of usage in the tests
+    fn now() -> Option<DateTime<Utc>> {
+        Some("2024-05-03T04:00:00Z".parse().unwrap())
+    }
+
+    const M30: &str = "ATLAS_AWS_INSTANCE_M30";
+    const KEY: &str = "org/group/cluster0/ATLAS_AWS_INSTANCE_M30";
+
+    #[test]
+    fn sums_regions() {
+        let items = [
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                1200,
+            ),
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                2400,
+            ),
+        ];
+        let (totals, rates) = aggregate(&items, now(), &Options::default());
+
+        assert_eq!(totals[KEY].total_price_cents, 3600);
+        assert_eq!(totals[KEY].quantity, 48.0);
+        assert_eq!(rates[KEY].unit_price_dollars, 1.5);
+    }
+
+    #[test]
+    fn rates_cover_the_most_recent_day() {
+        let items = [
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                1200,
+            ),
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-03T00:00:00Z",
+                2400,
+            ),
+            item(
+                "cluster1",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                1200,
+            ),
+        ];
+        let (totals, rates) = aggregate(&items, now(), &Options::default());
+
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[KEY].total_price_cents, 3600);
+        assert_eq!(totals[KEY].end_date, "2024-05-03T00:00:00Z");
+
+        // A cluster without usage on the most recent day has no rate
+        assert_eq!(rates.len(), 1);
+        assert_eq!(rates[KEY].unit_price_dollars, 1.0);
+    }
+
+    #[test]
+    fn rates_cover_the_most_recent_day_of_each_org() {
+        let mut lagging = item(
+            "cluster0",
+            M30,
+            "server hours",
+            "2024-05-02T00:00:00Z",
+            1200,
+        );
+        lagging.org_id = Some("lagging".to_string());
+        let items = [
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-03T00:00:00Z",
+                2400,
+            ),
+            lagging,
+        ];
+        let (_, rates) = aggregate(&items, now(), &Options::default());
+
+        // An org whose invoice lags a day behind keeps its rates
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates[KEY].unit_price_dollars, 1.0);
+        assert_eq!(
+            rates["lagging/group/cluster0/ATLAS_AWS_INSTANCE_M30"].unit_price_dollars,
+            0.5
+        );
+    }
+
+    #[test]
+    fn rates_leave_out_usage_older_than_30_hours() {
+        let mut terminated = item(
+            "cluster0",
+            M30,
+            "server hours",
+            "2024-05-01T00:00:00Z",
+            1200,
+        );
+        terminated.org_id = Some("terminated".to_string());
+        let items = [
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-03T00:00:00Z",
+                2400,
+            ),
+            terminated,
+        ];
+        let (totals, rates) = aggregate(&items, now(), &Options::default());
+
+        // Usage that ended 52 hours ago still counts towards the totals, but has no rate
+        assert_eq!(totals.len(), 2);
+        assert_eq!(rates.len(), 1);
+        assert_eq!(rates[KEY].unit_price_dollars, 1.0);
+
+        // Without a current time, the most recent usage of each org is used
+        let (_, rates) = aggregate(&items, None, &Options::default());
+        assert_eq!(rates.len(), 2);
+    }
+
+    #[test]
+    fn keeps_equally_named_clusters_apart() {
+        let mut other = item(
+            "cluster0",
+            M30,
+            "server hours",
+            "2024-05-02T00:00:00Z",
+            2400,
+        );
+        other.group_id = Some("other".to_string());
+        let items = [
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                1200,
+            ),
+            other,
+        ];
+        let (totals, _) = aggregate(&items, now(), &Options::default());
+
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[KEY].total_price_cents, 1200);
+        assert_eq!(
+            totals["org/other/cluster0/ATLAS_AWS_INSTANCE_M30"].total_price_cents,
+            2400
+        );
+    }
+
+    #[test]
+    fn empty_invoice() {
+        let (totals, rates) = aggregate(&[], now(), &Options::default());
+        assert!(totals.is_empty());
+        assert!(rates.is_empty());
+    }
+
+    #[test]
+    fn folds_series_beyond_max() {
+        let items = [
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                3000,
+            ),
+            item(
+                "cluster1",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                2000,
+            ),
+            item(
+                "cluster2",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                1000,
+            ),
+        ];
+        let (totals, _) = aggregate(&items, now(), &Options { max_series: 2 });
+
+        // The __other__ series counts towards the max as well
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[KEY].total_price_cents, 3000);
+        let other = &totals["org/group/__other__/ATLAS_AWS_INSTANCE_M30"];
+        assert_eq!(other.total_price_cents, 3000);
+        assert_eq!(other.cluster_name.as_deref(), Some(OTHER));
+        assert_eq!(other.group_name.as_deref(), Some("project"));
+    }
+
+    #[test]
+    fn folds_projects_when_they_exceed_max() {
+        let items: Vec<LineItem> = (0..4)
+            .map(|i| {
+                let mut item = item(
+                    &format!("cluster{i}"),
+                    M30,
+                    "server hours",
+                    "2024-05-02T00:00:00Z",
+                    1000 * (4 - i),
+                );
+                item.group_id = Some(format!("group{i}"));
+                item
+            })
+            .collect();
+        let (totals, _) = aggregate(&items, now(), &Options { max_series: 2 });
+
+        // An __other__ series per project would not fit, so they are folded per org
+        assert_eq!(totals.len(), 2);
+        assert_eq!(
+            totals["org/group0/cluster0/ATLAS_AWS_INSTANCE_M30"].total_price_cents,
+            4000
+        );
+        let other = &totals["org/__other__/__other__/ATLAS_AWS_INSTANCE_M30"];
+        assert_eq!(other.total_price_cents, 6000);
+        assert_eq!(other.group_name.as_deref(), Some(OTHER));
+    }
+
+    #[test]
+    fn converts_units_to_hourly_rates() {
+        let rate = |unit: &str, cents: i64, quantity: f64| {
+            let mut value = Compressed::from(&item("c", "SKU", unit, "", cents));
+            value.quantity = quantity;
+            hourly_rate(&value)
+        };
+
+        // Hourly sku's are priced per hour already
+        assert_eq!(rate("server hours", 2400, 24.0), Some(1.0));
+        // 2 gigabyte days at $0.24 a day
+        assert_eq!(rate("gigabyte days", 48, 2.0), Some(0.01));
+        // 1 gigabyte month at $7.30 a month of 730 hours
+        assert_eq!(rate("gigabyte months", 730, 1.0), Some(0.01));
+        assert_eq!(rate("gigabytes", 100, 1.0), None);
+        // A day without any quantity has no price per unit
+        assert_eq!(rate("gigabyte days", 0, 0.0), None);
+    }
+
+    #[test]
+    fn keeps_credits_out_of_usage() {
+        let items = [
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-02T00:00:00Z",
+                2400,
+            ),
+            item("", "CREDIT", "", "2024-05-02T00:00:00Z", -1000),
+            item("", "CREDIT", "", "2024-05-03T00:00:00Z", -500),
+            // A credit reversed in part is still a credit, and takes less off the bill
+            item("", "CREDIT", "", "2024-05-03T00:00:00Z", 200),
+            // A negative price on a usage sku is an adjustment, not usage
+            item(
+                "cluster0",
+                M30,
+                "server hours",
+                "2024-05-03T00:00:00Z",
+                -300,
+            ),
+        ];
+        let (totals, rates) = aggregate(&items, now(), &Options::default());
+
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[KEY].total_price_cents, 2400);
+        assert_eq!(rates[KEY].unit_price_dollars, 1.0);
+
+        let credits = credits(&items);
+        assert_eq!(credits.len(), 2);
+        assert_eq!(credits["org/group//CREDIT"].total_price_cents, -1300);
+        assert_eq!(
+            credits["org/group//CREDIT"].credit_type.as_deref(),
+            Some("credit")
+        );
+        assert_eq!(
+            credits["org/group//CREDIT"].end_date,
+            "2024-05-03T00:00:00Z"
+        );
+        assert_eq!(credits[KEY].total_price_cents, -300);
+        assert_eq!(credits[KEY].credit_type.as_deref(), Some("adjustment"));
+    }
+}
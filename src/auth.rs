@@ -0,0 +1,202 @@
+// Optional authentication of selected route groups, with HTTP basic auth against bcrypt
+// password hashes, as in the Prometheus web config, or with static bearer tokens as sent by
+// a Prometheus scrape config with authorization credentials
+use axum::{
+    extract::ConnectInfo,
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderValue, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use clap::ArgMatches;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error as RestError;
+use crate::listener::Peer;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub static GROUPS: &[&str] = &["api", "metrics", "health"];
+
+#[derive(Debug)]
+pub struct Auth {
+    users: HashMap<String, String>,
+    // Sha256 digests of the tokens, so that comparisons do not leak the token through timing
+    tokens: HashSet<Vec<u8>>,
+    groups: HashSet<String>,
+    // Hashes of credentials that have been verified, as bcrypt is slow on purpose
+    verified: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl Auth {
+    // Users come from basic_auth_user and basic_auth_password_hash, and from a file of
+    // user:hash lines as written by htpasswd -B
+    pub fn new(opts: &ArgMatches) -> BoxResult<Option<Arc<Auth>>> {
+        let mut users = HashMap::new();
+        if let (Some(user), Some(hash)) = (
+            opts.value_of("basic_auth_user"),
+            opts.value_of("basic_auth_password_hash"),
+        ) {
+            users.insert(user.to_string(), hash.to_string());
+        }
+        if let Some(path) = opts.value_of("basic_auth_file") {
+            let file = std::fs::read_to_string(path)
+                .map_err(|e| format!("Could not read basic_auth_file {path}: {e}"))?;
+            for line in file.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (user, hash) = line
+                    .split_once(':')
+                    .ok_or_else(|| format!("Malformed line in basic_auth_file {path}"))?;
+                users.insert(user.to_string(), hash.to_string());
+            }
+        }
+
+        let mut tokens = HashSet::new();
+        if let Some(token) = opts.value_of("bearer_token") {
+            tokens.insert(Sha256::digest(token.trim()).to_vec());
+        }
+        if let Some(path) = opts.value_of("bearer_token_file") {
+            let file = std::fs::read_to_string(path)
+                .map_err(|e| format!("Could not read bearer_token_file {path}: {e}"))?;
+            for token in file.lines().map(str::trim).filter(|t| !t.is_empty()) {
+                tokens.insert(Sha256::digest(token).to_vec());
+            }
+        }
+
+        if users.is_empty() && tokens.is_empty() {
+            return Ok(None);
+        }
+
+        // Bearer tokens are meant for scrapers, so they protect /metrics by default as well
+        let default_groups = match tokens.is_empty() {
+            true => "api",
+            false => "api,metrics",
+        };
+        let groups = opts
+            .value_of("auth_routes")
+            .unwrap_or(default_groups)
+            .split(',')
+            .map(|g| g.trim().to_string())
+            .filter(|g| !g.is_empty())
+            .collect::<HashSet<String>>();
+        if let Some(group) = groups.iter().find(|g| !GROUPS.contains(&g.as_str())) {
+            return Err(format!("Unknown auth_routes group {group}").into());
+        }
+
+        Ok(Some(Arc::new(Auth {
+            users,
+            tokens,
+            groups,
+            verified: Mutex::new(HashSet::new()),
+        })))
+    }
+
+    async fn check_basic(&self, encoded: &str) -> Result<(), String> {
+        let decoded = base64::decode(encoded).map_err(|_| "Malformed basic credentials")?;
+        let decoded = String::from_utf8(decoded).map_err(|_| "Malformed basic credentials")?;
+        let (user, password) = decoded
+            .split_once(':')
+            .ok_or("Malformed basic credentials")?;
+        let hash = self
+            .users
+            .get(user)
+            .ok_or_else(|| format!("Unknown user {user}"))?
+            .clone();
+
+        let key = Sha256::digest(format!("{user}:{password}:{hash}")).to_vec();
+        if self.verified.lock().expect("auth poisoned").contains(&key) {
+            return Ok(());
+        }
+
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map_err(|e| e.to_string())?
+            .unwrap_or(false);
+        match valid {
+            true => {
+                self.verified.lock().expect("auth poisoned").insert(key);
+                Ok(())
+            }
+            false => Err(format!("Wrong password for user {user}")),
+        }
+    }
+
+    async fn check(&self, header: Option<&HeaderValue>) -> Result<(), String> {
+        let header = header
+            .and_then(|h| h.to_str().ok())
+            .ok_or("Missing authorization header")?;
+        match header.split_once(' ') {
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                self.check_basic(credentials.trim()).await
+            }
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                match self
+                    .tokens
+                    .contains(Sha256::digest(token.trim()).as_slice())
+                {
+                    true => Ok(()),
+                    false => Err("Unknown bearer token".to_string()),
+                }
+            }
+            _ => Err("Unsupported authorization scheme".to_string()),
+        }
+    }
+}
+
+async fn authenticate<B>(req: Request<B>, next: Next<B>, auth: Arc<Auth>) -> Response {
+    match auth.check(req.headers().get(AUTHORIZATION)).await {
+        Ok(()) => next.run(req).await,
+        Err(reason) => {
+            // The subject of a client certificate tells which client failed, even without
+            // valid credentials
+            let subject = req
+                .extensions()
+                .get::<ConnectInfo<Peer>>()
+                .and_then(|ConnectInfo(peer)| peer.subject.clone());
+            log::warn!(
+                "{{\"fn\": \"authenticate\", \"path\":\"{}\", \"reason\":{}, \"client_subject\":{}}}",
+                req.uri().path(),
+                serde_json::Value::String(reason),
+                serde_json::json!(subject)
+            );
+            let mut response =
+                RestError::Http(StatusCode::UNAUTHORIZED, "Unauthorized".to_string())
+                    .into_response();
+            if !auth.users.is_empty() {
+                response.headers_mut().append(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic realm=\"mongo-atlas-billing-exporter\""),
+                );
+            }
+            if !auth.tokens.is_empty() {
+                response.headers_mut().append(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Bearer realm=\"mongo-atlas-billing-exporter\""),
+                );
+            }
+            response
+        }
+    }
+}
+
+// Require authentication on the routes of a group, when the group is selected
+pub fn protect(router: Router, auth: &Option<Arc<Auth>>, group: &str) -> Router {
+    match auth {
+        Some(auth) if auth.groups.contains(group) => {
+            let auth = auth.clone();
+            router.route_layer(middleware::from_fn(move |req, next| {
+                authenticate(req, next, auth.clone())
+            }))
+        }
+        _ => router,
+    }
+}
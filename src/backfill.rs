@@ -0,0 +1,110 @@
+use clap::ArgMatches;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+use crate::metrics::Labels;
+use crate::remote_write::{push, TimeSeries};
+use crate::sink::push_total;
+use crate::state::{parse_timestamp, Compressed};
+use crate::State;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Walk the invoices of the past months, and push the running total of each sku to a
+// Prometheus remote write endpoint, timestamped with the end date of each line item
+pub async fn backfill(state: State, opts: &ArgMatches<'_>) -> BoxResult<()> {
+    let url = opts.value_of("remote_write_url").unwrap();
+    let months: usize = opts
+        .value_of("months")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("Supplied months not in range, defaulting to 6");
+            6
+        });
+
+    // Include the current pending invoice along with the requested months, oldest first
+    let mut invoices = Vec::new();
+    for org in &state.orgs {
+        let mut ids = state.get_invoice_ids(org, months + 1).await?;
+        ids.reverse();
+        invoices.extend(ids.into_iter().map(|id| (org, id)));
+    }
+    let mut series: Vec<TimeSeries> = Vec::new();
+
+    // The pending invoice is billed through the same channel as the closed ones before it
+    let mut channels: HashMap<&String, &'static str> = HashMap::new();
+
+    for (org, id) in invoices {
+        log::info!("{{\"fn\": \"backfill\", \"invoice\":\"{}\"}}", id);
+        let data = state.get_invoice(org, &id).await?;
+        let billing_channel = match data.billing_channel() {
+            Some(billing_channel) => {
+                channels.insert(org, billing_channel);
+                billing_channel
+            }
+            None => channels.get(org).copied().unwrap_or("direct"),
+        };
+
+        // Sum up line items per day, as sku's are priced per region. Credits are left out,
+        // as they are of the exported totals.
+        let mut days: BTreeMap<String, HashMap<Labels, i64>> = BTreeMap::new();
+        for item in data
+            .flatten(org)
+            .into_iter()
+            .filter(|item| !item.is_credit())
+        {
+            let value = Compressed::from(&item);
+            *days
+                .entry(value.end_date.clone())
+                .or_default()
+                .entry(value.labels(billing_channel, &state.sanitizer))
+                .or_insert(0) += value.total_price_cents;
+        }
+
+        // Totals are cumulative over the invoice period
+        let mut totals: HashMap<(&'static str, Labels), TimeSeries> = HashMap::new();
+        let mut running: HashMap<Labels, i64> = HashMap::new();
+        for (end_date, items) in days {
+            let timestamp = match parse_timestamp(&end_date) {
+                Some(t) => t,
+                None => continue,
+            };
+
+            for (labels, cents) in items {
+                let total = running.entry(labels.clone()).or_insert(0);
+                *total += cents;
+
+                // Named like the live series, so that the history joins up with them
+                let mut points = Vec::new();
+                push_total(
+                    &mut points,
+                    (
+                        "atlas_billing_item_cents_total",
+                        "atlas_billing_item_dollars_total",
+                    ),
+                    state.dollar_metrics,
+                    labels,
+                    *total as f64,
+                    Some(timestamp),
+                );
+                for point in state.named(points) {
+                    totals
+                        .entry((point.name, point.labels.clone()))
+                        .or_insert_with(|| TimeSeries::new(point.name, &point.labels))
+                        .samples
+                        .push((point.value, timestamp));
+                }
+            }
+        }
+
+        series.extend(totals.into_values());
+    }
+
+    log::info!(
+        "{{\"fn\": \"backfill\", \"series\":\"{}\", \"url\":\"{}\"}}",
+        series.len(),
+        url
+    );
+    push(url, &series).await
+}
@@ -0,0 +1,290 @@
+// Write the daily aggregated line items to a BigQuery table, so that Atlas cost can be joined
+// with other data in SQL. The last few days are written again on every refresh, as Atlas
+// keeps adding usage to a day after it has ended.
+use chrono::{Duration, NaiveDate, Utc};
+use reqwest::header::AUTHORIZATION;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::sink::{Emit, Sink, Snapshot};
+use crate::sku::category;
+use crate::state::Billing;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+static API: &str = "https://bigquery.googleapis.com/bigquery/v2";
+static METADATA_TOKEN: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+// Days within this many days of today are written again, until Atlas has stopped adding to them
+const REWRITE_DAYS: i64 = 3;
+
+// Columns of the table, along with their types
+static COLUMNS: &[(&str, &str)] = &[
+    ("usage_date", "DATE"),
+    ("org_id", "STRING"),
+    ("group_id", "STRING"),
+    ("group_name", "STRING"),
+    ("cluster_name", "STRING"),
+    ("sku", "STRING"),
+    ("category", "STRING"),
+    ("unit", "STRING"),
+    ("billing_channel", "STRING"),
+    ("quantity", "FLOAT64"),
+    ("total_price_cents", "INT64"),
+];
+
+#[derive(Debug, Clone)]
+pub struct BigQuery {
+    project: String,
+    dataset: String,
+    table: String,
+    token_file: Option<String>,
+    client: reqwest::Client,
+    // Last usage date present in the table, looked up on the first push
+    last: Arc<Mutex<Option<String>>>,
+}
+
+// A query parameter value, which BigQuery takes as a string whatever its type
+fn parameter_value(value: &Value) -> Value {
+    match value {
+        Value::String(s) => json!({ "value": s }),
+        Value::Null => json!({}),
+        other => json!({ "value": other.to_string() }),
+    }
+}
+
+fn array_parameter(name: &str, kind: &str, values: Vec<Value>) -> Value {
+    json!({
+        "name": name,
+        "parameterType": {"type": "ARRAY", "arrayType": {"type": kind}},
+        "parameterValue": {"arrayValues": values.iter().map(parameter_value).collect::<Vec<Value>>()},
+    })
+}
+
+fn rows_parameter(rows: &[Value]) -> Value {
+    let struct_types: Vec<Value> = COLUMNS
+        .iter()
+        .map(|(name, kind)| json!({"name": name, "type": {"type": kind}}))
+        .collect();
+    let values: Vec<Value> = rows
+        .iter()
+        .map(|row| {
+            let fields: serde_json::Map<String, Value> = COLUMNS
+                .iter()
+                .map(|(name, _)| (name.to_string(), parameter_value(&row[name])))
+                .collect();
+            json!({ "structValues": fields })
+        })
+        .collect();
+    json!({
+        "name": "rows",
+        "parameterType": {"type": "ARRAY", "arrayType": {"type": "STRUCT", "structTypes": struct_types}},
+        "parameterValue": {"arrayValues": values},
+    })
+}
+
+impl BigQuery {
+    // The table is given as project.dataset.table
+    pub fn new(table: &str, token_file: Option<&str>) -> BoxResult<Self> {
+        let parts: Vec<&str> = table.split('.').collect();
+        if parts.len() != 3 {
+            return Err(
+                format!("bigquery_table {table} is not of the form project.dataset.table").into(),
+            );
+        }
+
+        Ok(BigQuery {
+            project: parts[0].to_string(),
+            dataset: parts[1].to_string(),
+            table: parts[2].to_string(),
+            token_file: token_file.map(|t| t.to_string()),
+            client: reqwest::Client::new(),
+            last: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    // Read the token from the supplied file on every push, as access tokens expire after an
+    // hour and are kept fresh by whatever writes the file. Otherwise get one for the attached
+    // service account from the metadata server, as on GCE and GKE with workload identity.
+    async fn access_token(&self) -> BoxResult<String> {
+        if let Some(path) = &self.token_file {
+            let token = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| format!("Could not read bigquery_token_file {path}: {e}"))?;
+            return Ok(token.trim().to_string());
+        }
+
+        let value: Value = self
+            .client
+            .get(METADATA_TOKEN)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        match value["access_token"].as_str() {
+            Some(token) => Ok(token.to_string()),
+            None => Err("metadata server did not return an access_token".into()),
+        }
+    }
+
+    async fn query(&self, token: &str, query: &str, parameters: Vec<Value>) -> BoxResult<Value> {
+        let value: Value = self
+            .client
+            .post(format!("{API}/projects/{}/queries", self.project))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .json(&json!({
+                "query": query,
+                "useLegacySql": false,
+                "parameterMode": "NAMED",
+                "queryParameters": parameters,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if let Some(errors) = value.get("errors") {
+            log::error!("BigQuery query errors: {}", errors);
+            return Err("BigQuery rejected the query".into());
+        }
+        Ok(value)
+    }
+
+    async fn last_day(&self, token: &str) -> BoxResult<Option<String>> {
+        let query = format!(
+            "SELECT FORMAT_DATE('%Y-%m-%d', MAX(usage_date)) FROM `{}.{}.{}`",
+            self.project, self.dataset, self.table
+        );
+        let value = self.query(token, &query, vec![]).await?;
+
+        Ok(value["rows"][0]["f"][0]["v"]
+            .as_str()
+            .map(|v| v.to_string()))
+    }
+
+    pub async fn push(&self, billing: &Billing) -> BoxResult<()> {
+        let token = self.access_token().await?;
+
+        // Hold the lock across the write, so that concurrent refreshes do not write a day twice
+        let mut last = self.last.lock().await;
+        if last.is_none() {
+            *last = self.last_day(&token).await?;
+        }
+
+        // The days after the last one written, along with the recent days that may have changed
+        let today = Utc::now().date_naive();
+        let recent = today - Duration::days(REWRITE_DAYS);
+        let from = last
+            .as_deref()
+            .and_then(|last| NaiveDate::parse_from_str(last, "%Y-%m-%d").ok())
+            .map(|last| (last + Duration::days(1)).min(recent).to_string());
+        let rows = daily_rows(billing, from.as_deref(), &today.to_string());
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let days: BTreeSet<String> = rows.keys().map(|(day, _)| day.clone()).collect();
+        let orgs: BTreeSet<String> = rows
+            .values()
+            .filter_map(|row| row["org_id"].as_str().map(|org| org.to_string()))
+            .collect();
+        let newest = days.iter().max().cloned();
+        let rows: Vec<Value> = rows.into_values().collect();
+
+        // Replace the days of these orgs as a whole, so that a day is never written twice
+        let table = format!("`{}.{}.{}`", self.project, self.dataset, self.table);
+        let columns = COLUMNS
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>()
+            .join(", ");
+        let query = format!(
+            "BEGIN TRANSACTION;\n\
+             DELETE FROM {table} WHERE usage_date IN UNNEST(@days) AND org_id IN UNNEST(@orgs);\n\
+             INSERT INTO {table} ({columns}) SELECT {columns} FROM UNNEST(@rows);\n\
+             COMMIT TRANSACTION;"
+        );
+        let parameters = vec![
+            array_parameter("days", "DATE", days.into_iter().map(Value::from).collect()),
+            array_parameter(
+                "orgs",
+                "STRING",
+                orgs.into_iter().map(Value::from).collect(),
+            ),
+            rows_parameter(&rows),
+        ];
+        self.query(&token, &query, parameters).await?;
+
+        log::info!(
+            "{{\"fn\": \"bigquery_push\", \"table\":\"{}.{}.{}\", \"rows\":{}}}",
+            self.project,
+            self.dataset,
+            self.table,
+            rows.len()
+        );
+        if newest > *last {
+            *last = newest;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for BigQuery {
+    fn name(&self) -> &'static str {
+        "BigQuery"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move {
+            if snapshot.is_new() {
+                self.push(snapshot.billing).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// Sum up the line items per day and sku, for the days from the given one and before today
+fn daily_rows(
+    billing: &Billing,
+    from: Option<&str>,
+    today: &str,
+) -> BTreeMap<(String, String), Value> {
+    let mut rows: BTreeMap<(String, String), Value> = BTreeMap::new();
+    for item in &billing.line_items {
+        let day: String = item.start_date.chars().take(10).collect();
+        if day.as_str() >= today || from.is_some_and(|from| day.as_str() < from) {
+            continue;
+        }
+
+        let row = rows.entry((day.clone(), item.key())).or_insert_with(|| {
+            json!({
+                "usage_date": day,
+                "org_id": item.org_id,
+                "group_id": item.group_id,
+                "group_name": item.group_name,
+                "cluster_name": item.cluster_name,
+                "sku": item.sku,
+                "category": category(&item.sku),
+                "unit": item.unit,
+                "billing_channel": billing.channel(item.org_id.as_deref()),
+                "quantity": 0.0,
+                "total_price_cents": 0,
+            })
+        });
+
+        // Atlas prices sku's per region, so the same sku can show up several times a day
+        row["quantity"] = json!(row["quantity"].as_f64().unwrap_or_default() + item.quantity);
+        row["total_price_cents"] =
+            json!(row["total_price_cents"].as_i64().unwrap_or_default() + item.total_price_cents);
+    }
+    rows
+}
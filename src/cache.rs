@@ -0,0 +1,89 @@
+// Keep the last successful aggregation on disk, so that a restarted exporter has data to
+// serve before its first fetch from Atlas completes
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::sku::credit_type;
+use crate::state::{Billing, Compressed, LineItem};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheFile {
+    fetched: i64,
+    billing_channel: String,
+    period_start: String,
+    period_end: String,
+    totals: HashMap<String, Compressed>,
+    rates: HashMap<String, Compressed>,
+    line_items: Vec<LineItem>,
+    #[serde(default)]
+    credits: HashMap<String, Compressed>,
+    #[serde(default)]
+    channels: HashMap<String, String>,
+}
+
+fn channel(channel: &str) -> &'static str {
+    match channel {
+        "marketplace" => "marketplace",
+        _ => "direct",
+    }
+}
+
+// Returns the cached aggregation with the time it was fetched, in milliseconds
+pub async fn load(path: &str) -> BoxResult<(Billing, i64)> {
+    let bytes = tokio::fs::read(path).await?;
+    let mut file: CacheFile = serde_json::from_slice(&bytes)?;
+    // Caches written before credits kept their sign hold the amount taken off the bill
+    for value in file
+        .credits
+        .values_mut()
+        .filter(|value| value.credit_type.is_none())
+    {
+        value.total_price_cents = -value.total_price_cents.abs();
+        value.credit_type = credit_type(&value.sku, value.total_price_cents).map(Arc::from);
+    }
+    let billing = Billing {
+        billing_channel: channel(&file.billing_channel),
+        period_start: file.period_start,
+        period_end: file.period_end,
+        totals: file.totals,
+        rates: file.rates,
+        credits: file.credits,
+        line_items: file.line_items,
+        channels: file
+            .channels
+            .iter()
+            .map(|(org, c)| (org.clone(), channel(c)))
+            .collect(),
+        sources: HashMap::new(),
+        validators: HashMap::new(),
+        fingerprints: HashMap::new(),
+    };
+    Ok((billing, file.fetched))
+}
+
+// Write to a temporary file first, so that a crash never leaves a truncated cache behind
+pub async fn save(path: &str, billing: &Billing, fetched: i64) -> BoxResult<()> {
+    let file = CacheFile {
+        fetched,
+        billing_channel: billing.billing_channel.to_string(),
+        period_start: billing.period_start.clone(),
+        period_end: billing.period_end.clone(),
+        totals: billing.totals.clone(),
+        rates: billing.rates.clone(),
+        credits: billing.credits.clone(),
+        line_items: billing.line_items.clone(),
+        channels: billing
+            .channels
+            .iter()
+            .map(|(org, c)| (org.clone(), c.to_string()))
+            .collect(),
+    };
+    let tmp = format!("{path}.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(&file)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
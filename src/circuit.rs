@@ -0,0 +1,179 @@
+// Stop calling Atlas for a while after repeated failures, so that an outage on their side
+// is not made worse by every scrape retrying, and the logs are not flooded with the same error
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::Error as RestError;
+
+#[derive(Debug)]
+pub struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+    // Set while a single request is let through to see whether Atlas has recovered
+    trial: Option<Instant>,
+}
+
+impl Breaker {
+    pub fn new(config: &Config) -> Option<Arc<Breaker>> {
+        let threshold = config.circuit_breaker_threshold;
+        match threshold > 0 {
+            true => Some(Arc::new(Breaker {
+                threshold,
+                cooldown: Duration::from_secs(config.circuit_breaker_cooldown),
+                circuit: Mutex::new(Circuit::default()),
+            })),
+            false => None,
+        }
+    }
+
+    // Refuse requests while open. Once the cool down has passed, one request at a time is
+    // let through, and its outcome decides whether the circuit closes again.
+    pub fn allow(&self) -> Result<(), RestError> {
+        let now = Instant::now();
+        let mut circuit = self.circuit.lock().expect("circuit breaker poisoned");
+        match circuit.open_until {
+            Some(until) if now < until => Err(RestError::CircuitOpen),
+            Some(_) => match circuit.trial {
+                // A trial that never reported back, such as a cancelled scrape, is retried
+                Some(started) if now.duration_since(started) < self.cooldown => {
+                    Err(RestError::CircuitOpen)
+                }
+                _ => {
+                    circuit.trial = Some(now);
+                    Ok(())
+                }
+            },
+            None => Ok(()),
+        }
+    }
+
+    // Record the outcome of a request, with the error if it failed
+    pub fn record(&self, error: Option<&RestError>) {
+        let mut circuit = self.circuit.lock().expect("circuit breaker poisoned");
+        match error {
+            Some(e) if e.unavailable() => {
+                circuit.failures += 1;
+                if circuit.trial.is_some() || circuit.failures >= self.threshold {
+                    if circuit.open_until.is_none() {
+                        log::warn!(
+                            "{{\"fn\": \"circuit_breaker\", \"msg\":\"opened after {} failures, pausing calls to Atlas for {}s\"}}",
+                            circuit.failures,
+                            self.cooldown.as_secs()
+                        );
+                        metrics::increment_counter!("atlas_api_circuit_opened_total");
+                    }
+                    circuit.open_until = Some(Instant::now() + self.cooldown);
+                    circuit.trial = None;
+                }
+            }
+            // Any answer from Atlas, including a rejection, shows that it is reachable
+            _ => {
+                if circuit.open_until.is_some() {
+                    log::info!("{{\"fn\": \"circuit_breaker\", \"msg\":\"closed\"}}");
+                }
+                *circuit = Circuit::default();
+            }
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.circuit
+            .lock()
+            .expect("circuit breaker poisoned")
+            .open_until
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32, cooldown: Duration) -> Breaker {
+        Breaker {
+            threshold,
+            cooldown,
+            circuit: Mutex::new(Circuit::default()),
+        }
+    }
+
+    const COOLDOWN: Duration = Duration::from_millis(20);
+
+    fn unavailable() -> Option<&'static RestError> {
+        Some(&RestError::UnknownCode(503))
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = breaker(2, Duration::from_secs(60));
+
+        breaker.record(unavailable());
+        assert!(breaker.allow().is_ok());
+        assert!(!breaker.is_open());
+
+        breaker.record(unavailable());
+        assert!(matches!(breaker.allow(), Err(RestError::CircuitOpen)));
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn rejections_do_not_open() {
+        let breaker = breaker(1, Duration::from_secs(60));
+
+        breaker.record(Some(&RestError::Unauthorized));
+        assert!(breaker.allow().is_ok());
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn successful_trial_closes() {
+        let breaker = breaker(1, COOLDOWN);
+        breaker.record(unavailable());
+        assert!(breaker.allow().is_err());
+
+        // Once the cool down has passed, a single request is let through
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_err());
+
+        breaker.record(None);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn failed_trial_opens_again() {
+        let breaker = breaker(3, COOLDOWN);
+        (0..3).for_each(|_| breaker.record(unavailable()));
+
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow().is_ok());
+
+        // A failing trial opens the circuit right away, regardless of the threshold
+        breaker.record(unavailable());
+        assert!(breaker.is_open());
+        assert!(breaker.allow().is_err());
+    }
+
+    #[test]
+    fn cancelled_trial_is_retried() {
+        let breaker = breaker(1, COOLDOWN);
+        breaker.record(unavailable());
+
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow().is_ok());
+
+        // The trial never reports back, so another one is let through after the cool down
+        assert!(breaker.allow().is_err());
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow().is_ok());
+    }
+}
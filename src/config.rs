@@ -0,0 +1,152 @@
+// Settings of the Atlas client and of what is done with each refresh. The defaults are
+// those of the command line, so that embedding State only takes the credentials and orgs.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub public_key: String,
+    pub private_key: String,
+    pub orgs: Vec<String>,
+    pub atlas_url: String,
+    // Read instead of fetching from Atlas, for a single org
+    pub invoice_file: Option<String>,
+    pub record_dir: Option<String>,
+    pub replay_dir: Option<String>,
+
+    // Client, in seconds where not stated otherwise
+    pub timeout: u64,
+    pub user_agent: String,
+    pub max_response_bytes: u64,
+    pub org_concurrency: usize,
+    // Requests per minute, zero disables pacing
+    pub atlas_rate_limit: u32,
+    pub atlas_rate_limit_burst: u32,
+    // Consecutive failures, zero disables the circuit breaker
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: u64,
+    // Fall back to HTTPS_PROXY and NO_PROXY when unset
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // Zero disables the limit
+    pub max_connections: usize,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: u64,
+    pub dns_cache: bool,
+    pub dns_min_ttl: u64,
+    pub dns_max_ttl: u64,
+    // Entries of host=ip
+    pub dns_overrides: Vec<String>,
+
+    // Series
+    pub cost_explorer_days: Option<i64>,
+    pub timestamps: bool,
+    pub dollar_metrics: bool,
+    // Name the cost series after the minor and major units of their currency
+    pub currency_agnostic_names: bool,
+    pub series_ttl: u64,
+    // Zero disables the limit
+    pub max_series: usize,
+    pub label_lowercase: bool,
+    pub label_replace_invalid: bool,
+    pub label_max_length: usize,
+    pub normalize_currency: Option<String>,
+    // Either static or ecb
+    pub exchange_rate_source: String,
+    // Pairs of currency=rate
+    pub exchange_rates: Option<String>,
+    pub ecb_url: String,
+
+    // Snapshots
+    pub parquet_dir: Option<String>,
+    pub bigquery_table: Option<String>,
+    pub bigquery_token_file: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_prefix: Option<String>,
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+    pub mongodb_uri: Option<String>,
+    pub mongodb_database: String,
+    pub mongodb_collection: String,
+    pub sqlite_path: Option<String>,
+    pub sqlite_retention_days: i64,
+
+    // Serving
+    pub serve_stale: bool,
+    pub cache_file: Option<String>,
+    // Zero disables the check
+    pub max_data_age: i64,
+}
+
+impl Config {
+    pub fn new(public_key: &str, private_key: &str, orgs: &[&str]) -> Config {
+        Config {
+            public_key: public_key.to_string(),
+            private_key: private_key.to_string(),
+            orgs: orgs.iter().map(|org| org.to_string()).collect(),
+            ..Config::default()
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            public_key: String::new(),
+            private_key: String::new(),
+            orgs: Vec::new(),
+            atlas_url: "https://cloud.mongodb.com".to_string(),
+            invoice_file: None,
+            record_dir: None,
+            replay_dir: None,
+            timeout: 60,
+            user_agent: concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string(),
+            max_response_bytes: 67108864,
+            org_concurrency: 4,
+            atlas_rate_limit: 100,
+            atlas_rate_limit_burst: 10,
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: 60,
+            proxy: None,
+            no_proxy: None,
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+            max_connections: 0,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: 90,
+            dns_cache: false,
+            dns_min_ttl: 0,
+            dns_max_ttl: 86400,
+            dns_overrides: Vec::new(),
+            cost_explorer_days: None,
+            timestamps: false,
+            dollar_metrics: false,
+            currency_agnostic_names: false,
+            series_ttl: 1,
+            max_series: 0,
+            label_lowercase: false,
+            label_replace_invalid: false,
+            label_max_length: 0,
+            normalize_currency: None,
+            exchange_rate_source: "static".to_string(),
+            exchange_rates: None,
+            ecb_url: "https://www.ecb.europa.eu/stats/eurofxref/eurofxref-daily.xml".to_string(),
+            parquet_dir: None,
+            bigquery_table: None,
+            bigquery_token_file: None,
+            s3_bucket: None,
+            s3_prefix: None,
+            s3_region: "us-east-1".to_string(),
+            s3_endpoint: None,
+            mongodb_uri: None,
+            mongodb_database: "atlas_billing".to_string(),
+            mongodb_collection: "snapshots".to_string(),
+            sqlite_path: None,
+            sqlite_retention_days: 90,
+            serve_stale: false,
+            cache_file: None,
+            max_data_age: 7200,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// CORS headers, so that browser based tools on other origins can call the api directly
+use axum::http::{HeaderName, HeaderValue, Method};
+use clap::ArgMatches;
+use std::error::Error;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::request_id::X_REQUEST_ID;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+// With specific origins, credentials such as basic auth are allowed as well, which browsers
+// refuse for any origin
+pub fn layer(opts: &ArgMatches) -> BoxResult<Option<CorsLayer>> {
+    let origins = match opts.value_of("cors_allowed_origins") {
+        Some(origins) => origins,
+        None => return Ok(None),
+    };
+
+    let layer = match origins.trim() {
+        "*" => CorsLayer::new().allow_origin(AllowOrigin::any()),
+        origins => CorsLayer::new()
+            .allow_origin(AllowOrigin::list(
+                list(origins)
+                    .map(|origin| {
+                        HeaderValue::from_str(origin)
+                            .map_err(|_| format!("Supplied CORS origin {origin} is not valid"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ))
+            .allow_credentials(true),
+    };
+
+    let methods = match opts.value_of("cors_allowed_methods").unwrap().trim() {
+        "*" => AllowMethods::mirror_request(),
+        methods => AllowMethods::list(
+            list(methods)
+                .map(|method| {
+                    Method::from_bytes(method.to_uppercase().as_bytes())
+                        .map_err(|_| format!("Supplied CORS method {method} is not valid"))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+
+    let headers = match opts.value_of("cors_allowed_headers").unwrap().trim() {
+        "*" => AllowHeaders::mirror_request(),
+        headers => AllowHeaders::list(
+            list(headers)
+                .map(|header| {
+                    HeaderName::from_bytes(header.as_bytes())
+                        .map_err(|_| format!("Supplied CORS header {header} is not valid"))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+
+    Ok(Some(
+        layer
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .expose_headers([HeaderName::from_static(X_REQUEST_ID)]),
+    ))
+}
@@ -0,0 +1,186 @@
+use chrono::{Duration, Utc};
+use hyper::header::HeaderMap;
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::error::Error as RestError;
+use crate::metrics::Point;
+use crate::State;
+
+static URL_V2: &str = "/api/atlas/v2";
+static ACCEPT_V2: &str = "application/vnd.atlas.2023-01-01+json";
+
+// Number of times to poll for the results of a usage query before giving up, and how long
+// to wait in between
+const POLL_ATTEMPTS: u32 = 60;
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Usage is only reported per day, so there is no use querying it on every refresh
+const USAGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+// Atlas answers the token with 102 Processing until the results of the query are ready.
+// hyper drops informational responses and keeps waiting for a final one, so a poll that
+// hits the timeout is taken as still processing as well.
+const PROCESSING: u16 = 102;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageToken {
+    token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    usage_details: Vec<UsageDetail>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageDetail {
+    organization_id: Option<String>,
+    group_name: Option<String>,
+    cluster_name: Option<String>,
+    service: Option<String>,
+    usage_amount: f64,
+    usage_date: String,
+}
+
+impl State {
+    // Query the usage of each org in turn, as an api key may not see the others from the
+    // first org
+    pub async fn get_usage(&self, days: i64) -> Result<Usage, RestError> {
+        let mut usage_details = Vec::new();
+        for org in &self.orgs {
+            let usage = self.get_org_usage(org, days).await?;
+            usage_details.extend(usage.usage_details.into_iter().map(|detail| UsageDetail {
+                organization_id: detail.organization_id.or_else(|| Some(org.clone())),
+                ..detail
+            }));
+        }
+        Ok(Usage { usage_details })
+    }
+
+    // The Cost Explorer API works in two steps, first a usage query is created, which
+    // returns a token that can be polled until the results are ready
+    async fn get_org_usage(&self, org: &str, days: i64) -> Result<Usage, RestError> {
+        let today = Utc::now().date_naive();
+        let start = today - Duration::days(days);
+
+        let path = format!("orgs/{org}/billing/costExplorer/usage");
+        let body = json!({
+            "organizations": [org],
+            "startDate": start.format("%Y-%m-%d").to_string(),
+            "endDate": today.format("%Y-%m-%d").to_string(),
+            "groupBy": "clusters",
+        });
+
+        let response = self
+            .request(
+                Method::POST,
+                &format!("{}{URL_V2}/{path}", self.atlas_url),
+                &path,
+                Some(body.to_string()),
+                Some(ACCEPT_V2),
+                &HeaderMap::new(),
+            )
+            .await?;
+        let bytes = self.read(response).await?;
+        let token: UsageToken = serde_json::from_slice(&bytes)?;
+
+        let path = format!("{}/{}", path, token.token);
+        for attempt in 1..=POLL_ATTEMPTS {
+            let uri = format!("{}{URL_V2}/{path}", self.atlas_url);
+            let headers = HeaderMap::new();
+            let request = self.request(Method::GET, &uri, &path, None, Some(ACCEPT_V2), &headers);
+            let response = match tokio::time::timeout(self.timeout, request).await {
+                Ok(response) => response?,
+                // The wait has already been spent on the timeout
+                Err(_) => {
+                    log::debug!("Usage query timed out, attempt {}", attempt);
+                    continue;
+                }
+            };
+
+            if response.status().as_u16() == PROCESSING {
+                log::debug!("Usage query not ready yet, attempt {}", attempt);
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+            let bytes = self.read(response).await?;
+            return Ok(serde_json::from_slice::<Usage>(&bytes)?);
+        }
+
+        log::error!(
+            "Usage query did not finish after {} attempts",
+            POLL_ATTEMPTS
+        );
+        Err(RestError::NotFound)
+    }
+
+    // Query the usage and keep it for the refreshes that follow
+    pub async fn update_usage(&self, days: i64) -> Result<(), RestError> {
+        let usage = self.get_usage(days).await?;
+        *self.usage.write().expect("usage poisoned") = Some(usage);
+        Ok(())
+    }
+
+    // Keep the usage up to date in the background, as a query takes a while to finish
+    pub fn start_cost_explorer(&self) {
+        let days = match self.cost_explorer_days {
+            Some(days) => days,
+            None => return,
+        };
+        let state = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let wait = match state.update_usage(days).await {
+                    Ok(()) => USAGE_INTERVAL,
+                    Err(e) => {
+                        log::error!("Failed to get Cost Explorer usage: {}", e);
+                        RETRY_INTERVAL
+                    }
+                };
+                tokio::time::sleep(wait).await;
+            }
+        });
+    }
+
+    // The series of the last usage queried, if any
+    pub fn usage_points(&self) -> Vec<Point> {
+        let usage = self.usage.read().expect("usage poisoned").clone();
+        let usage = match usage {
+            Some(usage) => usage,
+            None => return Vec::new(),
+        };
+
+        let mut points = Vec::new();
+        for detail in usage.usage_details {
+            let labels = vec![
+                ("org_id", detail.organization_id.unwrap_or(self.org.clone())),
+                // Sanitized like the billing series, so that both join on the same names
+                (
+                    "cluster_name",
+                    self.sanitizer
+                        .apply(detail.cluster_name.as_deref().unwrap_or_default()),
+                ),
+                (
+                    "group_name",
+                    self.sanitizer
+                        .apply(detail.group_name.as_deref().unwrap_or_default()),
+                ),
+                ("service", detail.service.unwrap_or("".to_string())),
+                ("usage_date", detail.usage_date),
+            ];
+            points.push(Point::new(
+                "atlas_billing_usage_daily_cents",
+                labels,
+                detail.usage_amount * 100.0,
+                None,
+            ));
+        }
+        points
+    }
+}
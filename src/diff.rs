@@ -0,0 +1,200 @@
+// Compare two invoices and print the change in cost per cluster and sku, largest first, to
+// find out why a bill jumped without going through Prometheus
+use clap::ArgMatches;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+
+use crate::report::amount;
+use crate::state::{Data, LineItem, DEFAULT_CURRENCY};
+use crate::State;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Project and cluster, sku and currency
+type Key = (String, String, String);
+
+struct Side {
+    period: (String, String),
+    items: Vec<LineItem>,
+}
+
+impl Side {
+    fn new(data: Data, org: &str) -> Side {
+        Side {
+            period: data.period(),
+            items: data.flatten(org),
+        }
+    }
+
+    // Cost per project and cluster, then sku, in the currency the org is billed in
+    fn costs(&self) -> HashMap<Key, i64> {
+        let mut costs = HashMap::new();
+        for item in &self.items {
+            let name = format!(
+                "{} / {}",
+                item.group_name.as_deref().unwrap_or("-"),
+                item.cluster_name.as_deref().unwrap_or("-")
+            );
+            *costs
+                .entry((name, item.sku.clone(), item.currency()))
+                .or_insert(0) += item.total_price_cents;
+        }
+        costs
+    }
+}
+
+fn change(cents: i64, currency: &str) -> String {
+    match cents {
+        0 => amount(0, currency),
+        c if c > 0 => format!("+{}", amount(c, currency)),
+        c => format!("-{}", amount(-c, currency)),
+    }
+}
+
+fn totals(costs: &HashMap<Key, i64>) -> BTreeMap<&str, i64> {
+    let mut totals = BTreeMap::new();
+    for ((_, _, currency), cents) in costs {
+        *totals.entry(currency.as_str()).or_insert(0) += cents;
+    }
+    totals
+}
+
+// Each currency is totalled on its own, as amounts in different currencies do not add up
+fn joined<F: Fn(i64, &str) -> String>(totals: &BTreeMap<&str, i64>, format: F) -> String {
+    match totals.is_empty() {
+        true => format(0, DEFAULT_CURRENCY),
+        false => totals
+            .iter()
+            .map(|(currency, cents)| format(*cents, currency))
+            .collect::<Vec<String>>()
+            .join(", "),
+    }
+}
+
+fn render(before: &Side, after: &Side, top: usize) -> String {
+    let (costs_before, costs_after) = (before.costs(), after.costs());
+    let (total_before, total_after) = (totals(&costs_before), totals(&costs_after));
+    let mut total_change = total_after.clone();
+    for (currency, cents) in &total_before {
+        *total_change.entry(currency).or_insert(0) -= cents;
+    }
+
+    let keys: BTreeSet<&Key> = costs_before.keys().chain(costs_after.keys()).collect();
+    let mut rows: Vec<(&Key, i64, i64)> = keys
+        .into_iter()
+        .map(|key| {
+            (
+                key,
+                costs_before.get(key).copied().unwrap_or(0),
+                costs_after.get(key).copied().unwrap_or(0),
+            )
+        })
+        .filter(|(_, before, after)| before != after)
+        .collect();
+    rows.sort_by_key(|(key, before, after)| (Reverse((after - before).abs()), *key));
+    let hidden = match top > 0 && rows.len() > top {
+        true => rows.split_off(top).len(),
+        false => 0,
+    };
+
+    let name_width = rows
+        .iter()
+        .map(|((name, _, _), _, _)| name.len())
+        .chain(["Project / Cluster".len()])
+        .max()
+        .unwrap_or(0);
+    let sku_width = rows
+        .iter()
+        .map(|((_, sku, _), _, _)| sku.len())
+        .chain(["SKU".len()])
+        .max()
+        .unwrap_or(0);
+
+    let mut out = format!(
+        "Before: {} to {}, {}\nAfter:  {} to {}, {}\nChange: {}\n\n",
+        before.period.0,
+        before.period.1,
+        joined(&total_before, amount),
+        after.period.0,
+        after.period.1,
+        joined(&total_after, amount),
+        joined(&total_change, change),
+    );
+    out.push_str(&format!(
+        "{:<name_width$}  {:<sku_width$}  {:>12}  {:>12}  {:>12}\n",
+        "Project / Cluster", "SKU", "Before", "After", "Change"
+    ));
+    for ((name, sku, currency), before, after) in rows {
+        out.push_str(&format!(
+            "{:<name_width$}  {:<sku_width$}  {:>12}  {:>12}  {:>12}\n",
+            name,
+            sku,
+            amount(before, currency),
+            amount(after, currency),
+            change(after - before, currency)
+        ));
+    }
+    if hidden > 0 {
+        out.push_str(&format!("... and {hidden} smaller changes\n"));
+    }
+    out
+}
+
+fn top(opts: &ArgMatches) -> usize {
+    opts.value_of("top").unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("Supplied top not in range, defaulting to 20");
+        20
+    })
+}
+
+// Compare two invoices saved from Atlas, which needs no credentials
+pub fn files(opts: &ArgMatches) -> BoxResult<String> {
+    let mut sides = Vec::new();
+    for path in [
+        opts.value_of("before").unwrap(),
+        opts.value_of("after").unwrap(),
+    ] {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Could not read invoice {path}: {e}"))?;
+        let data: Data = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Could not parse invoice {path}: {e}"))?;
+        sides.push(Side::new(data, ""));
+    }
+    Ok(render(&sides[0], &sides[1], top(opts)))
+}
+
+// Compare the oldest of the most recent invoices of each org with the newest
+pub async fn months(state: State, opts: &ArgMatches<'_>) -> BoxResult<()> {
+    let months: usize = opts
+        .value_of("months")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("Supplied months not in range, defaulting to 2");
+            2
+        });
+
+    let mut before: Option<Side> = None;
+    let mut after: Option<Side> = None;
+    for org in &state.orgs {
+        let ids = state.get_closed_invoice_ids(org, months.max(2)).await?;
+        let (newest, oldest) = match (ids.first(), ids.last()) {
+            (Some(newest), Some(oldest)) if newest != oldest => (newest, oldest),
+            _ => return Err(format!("Org {org} has fewer than two invoices to compare").into()),
+        };
+        for (side, id) in [(&mut before, oldest), (&mut after, newest)] {
+            let data = Side::new(state.get_invoice(org, id).await?, org);
+            match side {
+                Some(side) => side.items.extend(data.items),
+                None => *side = Some(data),
+            }
+        }
+    }
+
+    match (before, after) {
+        (Some(before), Some(after)) => print!("{}", render(&before, &after, top(opts))),
+        _ => return Err("No invoices to compare".into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// Resolve the hosts the Atlas client connects to, with static overrides for split-horizon
+// setups, and optionally a caching resolver instead of the system one
+use hickory_resolver::config::ResolverOpts;
+use hickory_resolver::TokioAsyncResolver;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::config::Config;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Clone)]
+pub struct Resolver {
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    cache: Option<Arc<TokioAsyncResolver>>,
+    system: GaiResolver,
+}
+
+impl Resolver {
+    pub fn new(config: &Config) -> BoxResult<Resolver> {
+        let mut overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for entry in &config.dns_overrides {
+            let (host, ip) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Supplied dns_override {entry} is not host=ip"))?;
+            let ip = ip
+                .trim()
+                .parse()
+                .map_err(|_| format!("Supplied dns_override {entry} has no valid ip"))?;
+            overrides
+                .entry(host.trim().to_lowercase())
+                .or_default()
+                .push(ip);
+        }
+
+        // Cached for the ttl of each record, clamped to the configured bounds
+        let cache = match config.dns_cache {
+            true => {
+                let (system, mut resolver_opts): (_, ResolverOpts) =
+                    hickory_resolver::system_conf::read_system_conf()?;
+                resolver_opts.positive_min_ttl = Some(Duration::from_secs(config.dns_min_ttl));
+                resolver_opts.positive_max_ttl = Some(Duration::from_secs(config.dns_max_ttl));
+                Some(Arc::new(TokioAsyncResolver::tokio(system, resolver_opts)))
+            }
+            false => None,
+        };
+
+        Ok(Resolver {
+            overrides: Arc::new(overrides),
+            cache,
+            system: GaiResolver::new(),
+        })
+    }
+
+    // The port is filled in by the connector
+    pub async fn resolve(&self, host: &str) -> BoxResult<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, 0)]);
+        }
+        if let Some(ips) = self.overrides.get(&host.to_lowercase()) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect());
+        }
+        match &self.cache {
+            Some(cache) => Ok(cache
+                .lookup_ip(host)
+                .await?
+                .iter()
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect()),
+            None => Ok(self
+                .system
+                .clone()
+                .call(host.parse::<Name>()?)
+                .await?
+                .collect()),
+        }
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = BoxResult<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<BoxResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.resolve(name.as_str()).await?;
+            match addrs.is_empty() {
+                true => Err(format!("No addresses found for {}", name.as_str()).into()),
+                false => Ok(addrs.into_iter()),
+            }
+        })
+    }
+}
@@ -1,19 +1,28 @@
 //use serde_json::error::Error as SerdeError;
+#[cfg(feature = "cli")]
 use axum::{
     body::{self},
-    http::StatusCode,
+    http::header::CONTENT_TYPE,
     response::{IntoResponse, Response},
 };
+use hyper::StatusCode;
+use serde_json::json;
 use std::fmt;
 
+#[cfg(feature = "cli")]
+use crate::request_id;
+
 #[derive(Debug)]
 pub enum Error {
     Forbidden,
     Unauthorized,
     NotFound,
-    UnknownCode,
-    UnexpectedCode,
+    UnknownCode(u16),
+    UnexpectedCode(u16),
     MissingHeader,
+    TooLarge(u64),
+    CircuitOpen,
+    Http(StatusCode, String),
     Hyper(hyper::Error),
     Digest(digest_auth::Error),
     SerdeJson(serde_json::Error),
@@ -22,35 +31,114 @@ pub enum Error {
 
 impl std::error::Error for Error {}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl Error {
+    // Failures talking to Atlas are reported as a bad gateway, so that they can be told
+    // apart from the exporter itself failing
+    pub fn status(&self) -> StatusCode {
         match *self {
-            Error::Forbidden => f.write_str("{\"error\": \"Status: Forbidden\"}"),
-            Error::UnknownCode => f.write_str("{\"error\": \"Caught bad status code\"}"),
-            Error::Unauthorized => f.write_str("{\"error\": \"Status: Unauthorized\"}"),
-            Error::NotFound => f.write_str("{\"error\": \"Status: Not found\"}"),
-            Error::UnexpectedCode => {
-                f.write_str("{\"error\": \"Unexpected status code received\"}")
-            }
-            Error::MissingHeader => {
-                f.write_str("{\"error\": \"Missing expected response header\"}")
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Http(status, _) => status,
+            Error::Forbidden
+            | Error::Unauthorized
+            | Error::UnknownCode(_)
+            | Error::UnexpectedCode(_)
+            | Error::MissingHeader
+            | Error::TooLarge(_)
+            | Error::Hyper(_)
+            | Error::SerdeJson(_) => StatusCode::BAD_GATEWAY,
+            Error::Digest(_) | Error::InvalidHeaderValue(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Short label for the kind of failure, for use in metrics
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::Forbidden => "forbidden",
+            Error::Unauthorized => "unauthorized",
+            Error::NotFound => "not_found",
+            Error::UnknownCode(_) => "unknown_code",
+            Error::UnexpectedCode(_) => "unexpected_code",
+            Error::MissingHeader => "missing_header",
+            Error::TooLarge(_) => "too_large",
+            Error::CircuitOpen => "circuit_open",
+            Error::Http(_, _) => "http",
+            Error::Hyper(_) => "connection",
+            Error::Digest(_) => "digest",
+            Error::SerdeJson(_) => "json",
+            Error::InvalidHeaderValue(_) => "invalid_header",
+        }
+    }
+
+    // The status code Atlas answered with, if it answered at all
+    pub fn upstream_status(&self) -> Option<u16> {
+        match *self {
+            Error::Forbidden => Some(403),
+            Error::Unauthorized => Some(401),
+            Error::NotFound => Some(404),
+            Error::UnknownCode(code) | Error::UnexpectedCode(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    // Whether Atlas failed to answer at all, or is having trouble, rather than rejecting
+    // the request
+    pub fn unavailable(&self) -> bool {
+        match *self {
+            Error::Hyper(_) => true,
+            Error::UnknownCode(code) | Error::UnexpectedCode(code) => code == 429 || code >= 500,
+            _ => false,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            Error::Forbidden => "Status: Forbidden".to_string(),
+            Error::UnknownCode(code) => format!("Caught bad status code {code}"),
+            Error::Unauthorized => "Status: Unauthorized".to_string(),
+            Error::NotFound => "Status: Not found".to_string(),
+            Error::UnexpectedCode(code) => format!("Unexpected status code {code} received"),
+            Error::MissingHeader => "Missing expected response header".to_string(),
+            Error::CircuitOpen => "Calls to Atlas are paused after repeated failures".to_string(),
+            Error::TooLarge(max) => {
+                format!("Response from Atlas exceeds the limit of {max} bytes")
             }
-            Error::Hyper(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
-            Error::SerdeJson(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
-            Error::Digest(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
-            Error::InvalidHeaderValue(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
+            Error::Http(_, ref msg) => msg.clone(),
+            Error::Hyper(ref err) => err.to_string(),
+            Error::SerdeJson(ref err) => err.to_string(),
+            Error::Digest(ref err) => err.to_string(),
+            Error::InvalidHeaderValue(ref err) => err.to_string(),
         }
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", json!({ "error": self.message() }))
+    }
+}
+
+// Body shared by every error response, also used for panics caught outside of handlers
+#[cfg(feature = "cli")]
+pub fn body(status: StatusCode, message: &str) -> String {
+    json!({
+        "error": message,
+        "code": status.as_u16(),
+        "request_id": request_id::current(),
+    })
+    .to_string()
+}
+
+#[cfg(feature = "cli")]
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let payload = self.to_string();
-        let body = body::boxed(body::Full::from(payload));
+        let status = self.status();
+        let payload = body(status, &self.message());
 
         Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(body)
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body::boxed(body::Full::from(payload)))
             .unwrap()
     }
 }
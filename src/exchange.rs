@@ -0,0 +1,303 @@
+// Convert the billing series into a single currency, so that orgs billed in different
+// currencies can be summed in one dashboard. The native series are left as they are.
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::body::to_bytes;
+use hyper::header::USER_AGENT;
+use hyper::{Body, Request};
+
+use crate::config::Config;
+use crate::https::{create_verified_https_client, HttpsClient};
+use crate::metrics::{Labels, Point};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Value of one unit of each currency in the normalized currency
+type Rates = HashMap<String, f64>;
+
+type Fetch<'a> = Pin<Box<dyn Future<Output = BoxResult<Rates>> + Send + 'a>>;
+
+// The cost series that are converted, and the names of their converted series
+const NORMALIZED: [(&str, &str); 7] = [
+    (
+        "atlas_billing_item_cents_total",
+        "atlas_billing_item_normalized_cents_total",
+    ),
+    (
+        "atlas_billing_item_dollars_total",
+        "atlas_billing_item_normalized_dollars_total",
+    ),
+    (
+        "atlas_billing_item_cents_rate",
+        "atlas_billing_item_normalized_cents_rate",
+    ),
+    (
+        "atlas_billing_cluster_backup_cents_total",
+        "atlas_billing_cluster_backup_normalized_cents_total",
+    ),
+    (
+        "atlas_billing_cluster_backup_dollars_total",
+        "atlas_billing_cluster_backup_normalized_dollars_total",
+    ),
+    (
+        "atlas_billing_credits_cents",
+        "atlas_billing_credits_normalized_cents",
+    ),
+    (
+        "atlas_billing_credits_dollars",
+        "atlas_billing_credits_normalized_dollars",
+    ),
+];
+
+fn normalized(name: &str) -> Option<&'static str> {
+    NORMALIZED
+        .iter()
+        .find(|(native, _)| *native == name)
+        .map(|(_, normalized)| *normalized)
+}
+
+// The ECB publishes reference rates once every working day
+const ECB_TTL: Duration = Duration::from_secs(6 * 3600);
+
+pub trait RateSource: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn rates<'a>(&'a self, target: &'a str) -> Fetch<'a>;
+
+    // How long fetched rates are used before fetching them again
+    fn ttl(&self) -> Option<Duration>;
+}
+
+// Rates given on the command line as currency=rate pairs
+#[derive(Debug)]
+pub struct Static {
+    rates: Rates,
+}
+
+impl Static {
+    fn new(rates: Option<&str>) -> BoxResult<Static> {
+        let mut parsed = Rates::new();
+        for pair in rates
+            .unwrap_or_default()
+            .split(',')
+            .filter(|p| !p.trim().is_empty())
+        {
+            let (currency, rate) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Supplied exchange_rates {pair} is not currency=rate"))?;
+            let rate: f64 = rate
+                .trim()
+                .parse()
+                .map_err(|_| format!("Supplied exchange_rates {pair} has no valid rate"))?;
+            parsed.insert(currency.trim().to_uppercase(), rate);
+        }
+        Ok(Static { rates: parsed })
+    }
+}
+
+impl RateSource for Static {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    fn rates<'a>(&'a self, _target: &'a str) -> Fetch<'a> {
+        Box::pin(async move { Ok(self.rates.clone()) })
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Euro foreign exchange reference rates of the European Central Bank, fetched through the
+// same proxy and resolver as Atlas. The certificate of the ECB is always verified, as the
+// rates change every normalized series.
+#[derive(Debug)]
+pub struct Ecb {
+    url: String,
+    client: HttpsClient,
+    user_agent: String,
+    timeout: Duration,
+}
+
+// The daily file lists <Cube currency='USD' rate='1.0823'/> entries, in units per euro
+fn parse_ecb(body: &str) -> Rates {
+    let attribute = |cube: &str, name: &str| -> Option<String> {
+        let start = cube.find(&format!("{name}='"))? + name.len() + 2;
+        let end = cube[start..].find('\'')? + start;
+        Some(cube[start..end].to_string())
+    };
+    let mut rates: Rates = body
+        .split("<Cube")
+        .filter_map(|cube| {
+            let currency = attribute(cube, "currency")?;
+            let rate = attribute(cube, "rate")?.parse().ok()?;
+            Some((currency, rate))
+        })
+        .collect();
+    rates.insert("EUR".to_string(), 1.0);
+    rates
+}
+
+impl RateSource for Ecb {
+    fn name(&self) -> &'static str {
+        "ecb"
+    }
+
+    fn rates<'a>(&'a self, target: &'a str) -> Fetch<'a> {
+        Box::pin(async move {
+            let request = Request::get(&self.url)
+                .header(USER_AGENT, &self.user_agent)
+                .body(Body::empty())?;
+            // A hung fetch would otherwise hold up the refresh it is part of
+            let body = tokio::time::timeout(self.timeout, async {
+                let response = self.client.request(request).await?;
+                if !response.status().is_success() {
+                    return Err(format!("The ECB answered {}", response.status()).into());
+                }
+                Ok::<_, Box<dyn Error + Send + Sync>>(to_bytes(response.into_body()).await?)
+            })
+            .await
+            .map_err(|_| "Timed out fetching the ECB rates")??;
+            let per_euro = parse_ecb(&String::from_utf8_lossy(&body));
+            let target_per_euro = per_euro
+                .get(target)
+                .copied()
+                .ok_or_else(|| format!("The ECB publishes no rate for {target}"))?;
+            Ok(per_euro
+                .into_iter()
+                .map(|(currency, rate)| (currency, target_per_euro / rate))
+                .collect())
+        })
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        Some(ECB_TTL)
+    }
+}
+
+#[derive(Debug)]
+pub struct Exchange {
+    target: String,
+    source: Box<dyn RateSource>,
+    // The last rates fetched, kept when fetching fails
+    rates: Mutex<Option<(Instant, Rates)>>,
+}
+
+impl Exchange {
+    pub fn new(config: &Config) -> BoxResult<Option<Exchange>> {
+        let target = match &config.normalize_currency {
+            Some(target) => target.trim().to_uppercase(),
+            None => return Ok(None),
+        };
+        let source: Box<dyn RateSource> = match config.exchange_rate_source.as_str() {
+            "ecb" => Box::new(Ecb {
+                url: config.ecb_url.clone(),
+                client: create_verified_https_client(config)?,
+                user_agent: config.user_agent.clone(),
+                timeout: Duration::from_secs(config.timeout),
+            }),
+            _ => Box::new(Static::new(config.exchange_rates.as_deref())?),
+        };
+        log::info!(
+            "{{\"fn\": \"exchange\", \"currency\":\"{}\", \"source\":\"{}\"}}",
+            target,
+            source.name()
+        );
+        Ok(Some(Exchange {
+            target,
+            source,
+            rates: Mutex::new(None),
+        }))
+    }
+
+    async fn rates(&self) -> BoxResult<Rates> {
+        let cached = self.rates.lock().expect("exchange rates poisoned").clone();
+        if let Some((fetched, rates)) = &cached {
+            if self.source.ttl().is_none_or(|ttl| fetched.elapsed() < ttl) {
+                return Ok(rates.clone());
+            }
+        }
+
+        match self.source.rates(&self.target).await {
+            Ok(mut rates) => {
+                rates.insert(self.target.clone(), 1.0);
+                *self.rates.lock().expect("exchange rates poisoned") =
+                    Some((Instant::now(), rates.clone()));
+                Ok(rates)
+            }
+            Err(e) => match cached {
+                Some((_, rates)) => {
+                    log::error!("Failed to fetch exchange rates, using the last ones: {}", e);
+                    Ok(rates)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    // The labels of a native series, in the normalized currency
+    fn labels(&self, labels: Labels) -> Labels {
+        labels
+            .into_iter()
+            .flat_map(|(k, v)| match k {
+                "currency" => vec![("currency", self.target.clone()), ("native_currency", v)],
+                _ => vec![(k, v)],
+            })
+            .collect()
+    }
+
+    // The cost series among the billing series, in the normalized currency. Converted on
+    // every refresh, as the rates change even while the invoices do not.
+    pub async fn points(&self, series: &[Point]) -> BoxResult<Vec<Point>> {
+        let rates = self.rates().await?;
+        let mut points = Vec::new();
+        let currency = |point: &Point| {
+            point
+                .labels
+                .iter()
+                .find(|(name, _)| *name == "currency")
+                .map(|(_, currency)| currency.clone())
+        };
+
+        // Series in a currency without a rate are only exported in that currency
+        let currencies: BTreeSet<String> = series
+            .iter()
+            .filter(|point| normalized(point.name).is_some())
+            .filter_map(currency)
+            .collect();
+        for currency in currencies {
+            match rates.get(&currency) {
+                Some(value) => points.push(Point::new(
+                    "atlas_billing_exchange_rate_ratio",
+                    vec![
+                        ("currency", currency),
+                        ("normalized_currency", self.target.clone()),
+                    ],
+                    *value,
+                    None,
+                )),
+                None => log::warn!("No exchange rate from {} to {}", currency, self.target),
+            }
+        }
+
+        for point in series {
+            let rate = currency(point).and_then(|currency| rates.get(&currency).copied());
+            if let (Some(name), Some(rate)) = (normalized(point.name), rate) {
+                points.push(Point::new(
+                    name,
+                    self.labels(point.labels.clone()),
+                    point.value * rate,
+                    point.timestamp,
+                ));
+            }
+        }
+        Ok(points)
+    }
+}
@@ -0,0 +1,147 @@
+use crate::sku::{category, credit_type};
+use crate::state::{Billing, Compressed, LineItem};
+
+static CSV_HEADER: &str = "org_id,group_id,group_name,cluster_name,category,sku,quantity,unit,total_price_cents,start_date,end_date,currency\n";
+
+// Quote fields that contain separators, quotes or newlines
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_row(value: &Compressed) -> String {
+    let fields = [
+        value.org_id.as_deref().unwrap_or_default().to_string(),
+        value.group_id.as_deref().unwrap_or_default().to_string(),
+        value.group_name.as_deref().unwrap_or_default().to_string(),
+        value
+            .cluster_name
+            .as_deref()
+            .unwrap_or_default()
+            .to_string(),
+        category(&value.sku).to_string(),
+        value.sku.to_string(),
+        value.quantity.to_string(),
+        value.unit.to_string(),
+        value.total_price_cents.to_string(),
+        value.start_date.clone(),
+        value.end_date.clone(),
+        value.currency.to_string(),
+    ];
+
+    let mut row = fields
+        .iter()
+        .map(|f| csv_field(f))
+        .collect::<Vec<String>>()
+        .join(",");
+    row.push('\n');
+    row
+}
+
+// The aggregated line items as csv lines, starting with the header
+pub fn csv_lines(billing: &Billing) -> Vec<String> {
+    let mut keys: Vec<&String> = billing.totals.keys().collect();
+    keys.sort();
+
+    let mut lines = vec![CSV_HEADER.to_string()];
+    lines.extend(keys.into_iter().map(|key| csv_row(&billing.totals[key])));
+    lines
+}
+
+// Columns of the FinOps Open Cost and Usage Specification covered by Atlas invoices
+static FOCUS_HEADER: &str = "BilledCost,BillingAccountId,BillingAccountName,BillingCurrency,BillingPeriodEnd,BillingPeriodStart,ChargeCategory,ChargeClass,ChargeDescription,ChargeFrequency,ChargePeriodEnd,ChargePeriodStart,ConsumedQuantity,ConsumedUnit,ContractedCost,ContractedUnitPrice,EffectiveCost,InvoiceIssuerName,ListCost,ListUnitPrice,PricingCategory,PricingQuantity,PricingUnit,ProviderName,PublisherName,ResourceId,ResourceName,ResourceType,ServiceCategory,ServiceName,SkuId,SubAccountId,SubAccountName\n";
+
+fn focus_service_category(sku: &str) -> &'static str {
+    match category(sku) {
+        "backup" | "online_archive" => "Storage",
+        "data_federation" => "Analytics",
+        _ => "Databases",
+    }
+}
+
+fn focus_row(billing: &Billing, item: &LineItem) -> String {
+    let cost = format!("{:.2}", item.total_price_cents as f64 / 100.0);
+    let list_cost = format!("{:.2}", item.quantity * item.unit_price_dollars);
+    let (resource_id, resource_type) = match &item.cluster_name {
+        Some(cluster) => (
+            format!(
+                "{}/{}",
+                item.group_id.as_deref().unwrap_or_default(),
+                cluster
+            ),
+            "Cluster",
+        ),
+        None => ("".to_string(), ""),
+    };
+    let charge_category = match credit_type(&item.sku, item.total_price_cents) {
+        Some("adjustment") => "Adjustment",
+        Some(_) => "Credit",
+        None => "Usage",
+    };
+    let issuer = match billing.billing_channel {
+        "marketplace" => "Cloud Marketplace",
+        _ => "MongoDB, Inc.",
+    };
+
+    // Invoices carry no org name, so the org is named by its id. Atlas has no separate
+    // negotiated prices, so the contracted cost is the billed cost, and corrections of
+    // earlier periods are not told apart from other charges.
+    let fields = [
+        cost.clone(),
+        item.org_id.clone().unwrap_or_default(),
+        item.org_id.clone().unwrap_or_default(),
+        item.currency(),
+        billing.period_end.clone(),
+        billing.period_start.clone(),
+        charge_category.to_string(),
+        "".to_string(),
+        item.sku.clone(),
+        "Usage-Based".to_string(),
+        item.end_date.clone(),
+        item.start_date.clone(),
+        item.quantity.to_string(),
+        item.unit.clone(),
+        cost.clone(),
+        item.unit_price_dollars.to_string(),
+        cost,
+        issuer.to_string(),
+        list_cost,
+        item.unit_price_dollars.to_string(),
+        "Standard".to_string(),
+        item.quantity.to_string(),
+        item.unit.clone(),
+        "MongoDB".to_string(),
+        "MongoDB".to_string(),
+        resource_id,
+        item.cluster_name.clone().unwrap_or_default(),
+        resource_type.to_string(),
+        focus_service_category(&item.sku).to_string(),
+        "MongoDB Atlas".to_string(),
+        item.sku.clone(),
+        item.group_id.clone().unwrap_or_default(),
+        item.group_name.clone().unwrap_or_default(),
+    ];
+
+    let mut row = fields
+        .iter()
+        .map(|f| csv_field(f))
+        .collect::<Vec<String>>()
+        .join(",");
+    row.push('\n');
+    row
+}
+
+// The raw line items mapped to FOCUS columns, one charge per line item
+pub fn focus_lines(billing: &Billing) -> Vec<String> {
+    let mut lines = vec![FOCUS_HEADER.to_string()];
+    lines.extend(
+        billing
+            .line_items
+            .iter()
+            .map(|item| focus_row(billing, item)),
+    );
+    lines
+}
@@ -0,0 +1,43 @@
+// Subcommands that fetch the billing data a single time and print it, for cron jobs,
+// debugging and smoke tests, without starting the server
+use clap::ArgMatches;
+use std::error::Error;
+use std::sync::atomic::Ordering;
+
+use crate::State;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Always ask Atlas, rather than serving the disk cache from the last run
+async fn refresh(state: &State) -> BoxResult<()> {
+    state.from_disk.store(false, Ordering::SeqCst);
+    if let Some(days) = state.cost_explorer_days {
+        if let Err(e) = state.update_usage(days).await {
+            log::error!("Failed to get Cost Explorer usage: {}", e);
+        }
+    }
+    state.get_metrics().await?;
+    Ok(())
+}
+
+pub async fn fetch(state: State, opts: &ArgMatches<'_>) -> BoxResult<()> {
+    refresh(&state).await?;
+    match opts.value_of("output") {
+        Some("json") => println!(
+            "{}",
+            serde_json::to_string_pretty(&*state.cached_billing().await?)?
+        ),
+        _ => print!("{}", state.render()),
+    }
+    Ok(())
+}
+
+pub async fn report(state: State, opts: &ArgMatches<'_>) -> BoxResult<()> {
+    refresh(&state).await?;
+    let billing = state.cached_billing().await?;
+    match opts.value_of("format") {
+        Some("html") => print!("{}", crate::report::render(&billing)),
+        _ => print!("{}", crate::report::text(&billing)),
+    }
+    Ok(())
+}
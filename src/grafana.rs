@@ -0,0 +1,340 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::metrics::agnostic;
+use crate::report::daily;
+use crate::state::{parse_timestamp, Billing, LineItem};
+
+// Metrics offered to the Grafana simple-json and Infinity datasources
+static TARGETS: &[&str] = &["line_items", "totals", "daily_cluster_cents"];
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Search {
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Range {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Target {
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Query {
+    pub range: Option<Range>,
+    #[serde(default)]
+    pub targets: Vec<Target>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Annotations {
+    pub range: Option<Range>,
+    #[serde(default)]
+    pub annotation: Value,
+}
+
+impl Range {
+    fn contains(&self, timestamp: i64) -> bool {
+        match (parse_timestamp(&self.from), parse_timestamp(&self.to)) {
+            (Some(from), Some(to)) => from <= timestamp && timestamp <= to,
+            _ => true,
+        }
+    }
+}
+
+fn in_range(range: &Option<Range>, timestamp: i64) -> bool {
+    range
+        .as_ref()
+        .map(|r| r.contains(timestamp))
+        .unwrap_or(true)
+}
+
+pub fn search(search: &Search) -> Vec<&'static str> {
+    TARGETS
+        .iter()
+        .filter(|target| target.contains(search.target.as_str()))
+        .copied()
+        .collect()
+}
+
+fn line_item_row(item: &LineItem) -> Value {
+    json!([
+        item.org_id,
+        item.group_id,
+        item.group_name,
+        item.cluster_name,
+        item.sku,
+        item.start_date,
+        item.end_date,
+        item.quantity,
+        item.unit,
+        item.unit_price_dollars,
+        item.total_price_cents,
+        item.currency()
+    ])
+}
+
+fn columns() -> Value {
+    json!([
+        {"text": "org_id", "type": "string"},
+        {"text": "group_id", "type": "string"},
+        {"text": "group_name", "type": "string"},
+        {"text": "cluster_name", "type": "string"},
+        {"text": "sku", "type": "string"},
+        {"text": "start_date", "type": "time"},
+        {"text": "end_date", "type": "time"},
+        {"text": "quantity", "type": "number"},
+        {"text": "unit", "type": "string"},
+        {"text": "unit_price_dollars", "type": "number"},
+        {"text": "total_price_cents", "type": "number"},
+        {"text": "currency", "type": "string"}
+    ])
+}
+
+fn daily_series(billing: &Billing, range: &Option<Range>) -> Vec<Value> {
+    daily(billing)
+        .into_iter()
+        .map(|(cluster, days)| {
+            let datapoints: Vec<Value> = days
+                .into_iter()
+                .filter_map(|(date, cents)| {
+                    parse_timestamp(&format!("{date}T00:00:00Z")).map(|ts| (ts, cents))
+                })
+                .filter(|(ts, _)| in_range(range, *ts))
+                .map(|(ts, cents)| json!([cents, ts]))
+                .collect();
+            json!({ "target": cluster, "datapoints": datapoints })
+        })
+        .collect()
+}
+
+// Answer each target of the query, unknown targets are skipped
+pub fn query(billing: &Billing, query: &Query) -> Vec<Value> {
+    let mut results = Vec::new();
+    for target in &query.targets {
+        match target.target.as_str() {
+            "line_items" => results.push(json!({
+                "type": "table",
+                "columns": columns(),
+                "rows": billing.line_items.iter().map(line_item_row).collect::<Vec<Value>>(),
+            })),
+            "totals" => {
+                let mut keys: Vec<&String> = billing.totals.keys().collect();
+                keys.sort();
+                let rows: Vec<Value> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let total = &billing.totals[key];
+                        json!([
+                            total.org_id,
+                            total.group_id,
+                            total.group_name,
+                            total.cluster_name,
+                            total.sku,
+                            total.start_date,
+                            total.end_date,
+                            total.quantity,
+                            total.unit,
+                            total.unit_price_dollars,
+                            total.total_price_cents,
+                            &*total.currency
+                        ])
+                    })
+                    .collect();
+                results.push(json!({ "type": "table", "columns": columns(), "rows": rows }));
+            }
+            "daily_cluster_cents" => results.extend(daily_series(billing, &query.range)),
+            other => log::warn!("{{\"fn\": \"query\", \"msg\":\"unknown target {other}\"}}"),
+        }
+    }
+    results
+}
+
+// Mark the start and end of the billing period
+pub fn annotations(billing: &Billing, request: &Annotations) -> Vec<Value> {
+    [
+        (&billing.period_start, "Billing period start"),
+        (&billing.period_end, "Billing period end"),
+    ]
+    .into_iter()
+    .filter(|(date, _)| !date.is_empty())
+    .filter_map(|(date, title)| parse_timestamp(date).map(|ts| (ts, title)))
+    .filter(|(ts, _)| in_range(&request.range, *ts))
+    .map(|(ts, title)| {
+        json!({
+            "annotation": request.annotation,
+            "time": ts,
+            "title": title,
+            "text": format!("{} billing", billing.billing_channel),
+        })
+    })
+    .collect()
+}
+
+// Filter applied to every panel query, driven by the dashboard variables
+static FILTER: &str = "org_id=~\"$org_id\",group_name=~\"$group_name\"";
+
+// Currencies Grafana has a unit for. Amounts in any other currency are shown as plain numbers,
+// with the currency in the series name.
+const CURRENCIES: &[&str] = &["USD", "EUR", "GBP", "JPY", "CHF", "INR", "BRL", "SEK"];
+
+// Every series name ends with its currency label, which picks the unit it is shown in
+fn currency_units() -> Vec<Value> {
+    CURRENCIES
+        .iter()
+        .map(|currency| {
+            json!({
+                "matcher": {"id": "byRegexp", "options": format!("^(.* )?{currency}$")},
+                "properties": [{"id": "unit", "value": format!("currency{currency}")}],
+            })
+        })
+        .collect()
+}
+
+fn panel(id: u64, title: &str, kind: &str, expr: String, legend: &str, grid: Value) -> Value {
+    json!({
+        "id": id,
+        "title": title,
+        "type": kind,
+        "datasource": {"type": "prometheus", "uid": "${datasource}"},
+        "gridPos": grid,
+        "fieldConfig": {
+            "defaults": {"unit": "short", "decimals": 2},
+            "overrides": currency_units(),
+        },
+        "targets": [{
+            "refId": "A",
+            "datasource": {"type": "prometheus", "uid": "${datasource}"},
+            "expr": expr,
+            "legendFormat": legend,
+            "instant": kind != "timeseries",
+            "format": if kind == "table" { "table" } else { "time_series" },
+        }],
+    })
+}
+
+fn variable(name: &str, label: &str, total: &str) -> Value {
+    json!({
+        "name": name,
+        "type": "query",
+        "datasource": {"type": "prometheus", "uid": "${datasource}"},
+        "query": format!("label_values({total}, {label})"),
+        "refresh": 2,
+        "includeAll": true,
+        "multi": true,
+        "allValue": ".*",
+        "current": {"text": "All", "value": "$__all"},
+    })
+}
+
+// A ready to import dashboard for the metrics served on /metrics. Amounts are only summed
+// within a currency, and shown in the unit of that currency.
+pub fn dashboard(agnostic_names: bool) -> Value {
+    let name = |name: &'static str| match agnostic_names {
+        true => agnostic(name),
+        false => name,
+    };
+    let total = format!("{}{{{FILTER}}}", name("atlas_billing_item_cents_total"));
+    // The rate metric is the price of a single unit, such as one server, so the cost per day
+    // is taken from how much the totals grew over the last day instead
+    let daily = format!("clamp_min(delta({total}[1d]), 0)");
+    let backup = format!(
+        "{}{{{FILTER}}}",
+        name("atlas_billing_cluster_backup_cents_total")
+    );
+
+    let panels = vec![
+        panel(
+            1,
+            "Month to date",
+            "stat",
+            format!("sum by (currency) ({total}) / 100"),
+            "{{currency}}",
+            json!({"h": 4, "w": 6, "x": 0, "y": 0}),
+        ),
+        panel(
+            2,
+            "Current hourly cost",
+            "stat",
+            format!("sum by (currency) ({daily}) / 100 / 24"),
+            "{{currency}}",
+            json!({"h": 4, "w": 6, "x": 6, "y": 0}),
+        ),
+        panel(
+            3,
+            "Projected month",
+            "stat",
+            format!("sum by (currency) ({total}) / 100 + sum by (currency) ({daily}) / 100 * scalar(days_in_month() - day_of_month())"),
+            "{{currency}}",
+            json!({"h": 4, "w": 6, "x": 12, "y": 0}),
+        ),
+        panel(
+            4,
+            "Backup month to date",
+            "stat",
+            format!("sum by (currency) ({backup}) / 100"),
+            "{{currency}}",
+            json!({"h": 4, "w": 6, "x": 18, "y": 0}),
+        ),
+        panel(
+            5,
+            "Cost per cluster",
+            "timeseries",
+            format!("sum by (group_name, cluster_name, currency) ({total}) / 100"),
+            "{{group_name}}/{{cluster_name}} {{currency}}",
+            json!({"h": 8, "w": 12, "x": 0, "y": 4}),
+        ),
+        panel(
+            6,
+            "Hourly cost per cluster",
+            "timeseries",
+            format!("sum by (group_name, cluster_name, currency) ({daily}) / 100 / 24"),
+            "{{group_name}}/{{cluster_name}} {{currency}}",
+            json!({"h": 8, "w": 12, "x": 12, "y": 4}),
+        ),
+        panel(
+            7,
+            "Cost per category",
+            "bargauge",
+            format!("sum by (category, currency) ({total}) / 100"),
+            "{{category}} {{currency}}",
+            json!({"h": 8, "w": 8, "x": 0, "y": 12}),
+        ),
+        panel(
+            8,
+            "Top sku's",
+            "table",
+            format!("topk(20, sum by (group_name, cluster_name, sku, currency) ({total}) / 100)"),
+            "__auto",
+            json!({"h": 8, "w": 16, "x": 8, "y": 12}),
+        ),
+    ];
+
+    json!({
+        "title": "MongoDB Atlas Billing",
+        "uid": "atlas-billing",
+        "tags": ["mongodb", "atlas", "billing"],
+        "timezone": "utc",
+        "schemaVersion": 39,
+        "refresh": "5m",
+        "time": {"from": "now-30d", "to": "now"},
+        "panels": panels,
+        "templating": {"list": [
+            {
+                "name": "datasource",
+                "type": "datasource",
+                "query": "prometheus",
+                "current": {},
+            },
+            variable("org_id", "org_id", name("atlas_billing_item_cents_total")),
+            variable("group_name", "group_name", name("atlas_billing_item_cents_total")),
+        ]},
+    })
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::metrics::Point;
+use crate::sink::{Emit, Sink, Snapshot};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Pushes the billing series over the Graphite plaintext protocol
+#[derive(Debug, Clone)]
+pub struct Graphite {
+    address: String,
+    prefix: String,
+}
+
+// Graphite uses dots as path separator, so anything but plain characters is replaced
+fn node(value: &str) -> String {
+    if value.is_empty() {
+        return "none".to_string();
+    }
+    value
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+impl Graphite {
+    pub fn new(host: &str, port: &str, prefix: &str) -> Self {
+        Graphite {
+            address: format!("{host}:{port}"),
+            prefix: prefix.trim_matches('.').to_string(),
+        }
+    }
+
+    // Path of a series is the prefix, the metric name, then each label value in order
+    fn line(&self, point: &Point, now: i64) -> String {
+        let mut path: Vec<String> = Vec::new();
+        if !self.prefix.is_empty() {
+            path.push(self.prefix.clone());
+        }
+        path.push(point.name.to_string());
+        path.extend(point.labels.iter().map(|(_, v)| node(v)));
+
+        let timestamp = point.timestamp.map(|ms| ms / 1000).unwrap_or(now);
+        format!("{} {} {}\n", path.join("."), point.value, timestamp)
+    }
+
+    pub async fn push(&self, points: &[Point]) -> BoxResult<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        let body: String = points.iter().map(|p| self.line(p, now)).collect();
+
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(body.as_bytes()).await?;
+        stream.shutdown().await?;
+
+        log::info!(
+            "{{\"fn\": \"graphite_push\", \"address\":\"{}\", \"points\":{}}}",
+            self.address,
+            points.len()
+        );
+        Ok(())
+    }
+}
+
+impl Sink for Graphite {
+    fn name(&self) -> &'static str {
+        "Graphite"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move { self.push(snapshot.points).await })
+    }
+}
@@ -1,28 +1,209 @@
 use axum::{
+    body::{Bytes, Full, StreamBody},
     extract::{Extension, OriginalUri},
-    http::StatusCode,
-    response::IntoResponse,
+    http::header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE},
+    http::{HeaderMap, Response, StatusCode},
+    response::{Html, IntoResponse},
     Json,
 };
 use clap::{crate_description, crate_name, crate_version};
 use metrics_exporter_prometheus::PrometheusHandle;
 use serde_json::json;
 use serde_json::Value;
+use std::any::Any;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tower::load_shed::error::Overloaded;
+use tower::BoxError;
 
-use crate::error::Error as RestError;
+use crate::error::{self, Error as RestError};
+use crate::export::{csv_lines, focus_lines};
+use crate::grafana;
+use crate::metrics::{expose, Family, Format};
+use crate::state::Billing;
 use crate::State;
 
 // This is required in order to get the method from the request
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct RequestMethod(pub hyper::Method);
 
 pub async fn metrics(
     Extension(recorder_handle): Extension<PrometheusHandle>,
     Extension(state): Extension<State>,
-) -> Result<String, RestError> {
+    headers: HeaderMap,
+) -> impl IntoResponse {
     log::info!("{{\"fn\": \"metrics\", \"method\":\"get\"}}");
-    state.get_metrics().await?;
-    Ok(recorder_handle.render())
+    billing_metrics(state, headers, &recorder_handle.render(), Family::All).await
+}
+
+pub async fn metrics_total(
+    Extension(state): Extension<State>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    log::info!("{{\"fn\": \"metrics_total\", \"method\":\"get\"}}");
+    billing_metrics(state, headers, "", Family::Total).await
+}
+
+pub async fn metrics_rate(
+    Extension(state): Extension<State>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    log::info!("{{\"fn\": \"metrics_rate\", \"method\":\"get\"}}");
+    billing_metrics(state, headers, "", Family::Rate).await
+}
+
+async fn billing_metrics(
+    state: State,
+    headers: HeaderMap,
+    recorder: &str,
+    family: Family,
+) -> impl IntoResponse {
+    // Without billing data to serve, the exporter's own series still are, so that a failed
+    // fetch is scraped as atlas_billing_scrape_success 0 rather than as a failed scrape
+    let family = match state.get_metrics().await {
+        Ok(()) => family,
+        Err(e) => {
+            log::error!("Failed to refresh billing metrics: {}", e);
+            Family::Exporter
+        }
+    };
+
+    let accept = headers.get(ACCEPT).and_then(|h| h.to_str().ok());
+    let format = Format::negotiate(accept);
+    let body = expose(format, recorder, &state.registry, family);
+
+    ([(CONTENT_TYPE, format.content_type())], body)
+}
+
+pub async fn billing(Extension(state): Extension<State>) -> Result<Json<Arc<Billing>>, RestError> {
+    log::info!("{{\"fn\": \"billing\", \"method\":\"get\"}}");
+    Ok(Json(state.get_billing().await?))
+}
+
+// The last aggregation, so that browsing the dashboard does not fetch from Atlas
+pub async fn billing_cached(
+    Extension(state): Extension<State>,
+) -> Result<Json<Arc<Billing>>, RestError> {
+    log::info!("{{\"fn\": \"billing_cached\", \"method\":\"get\"}}");
+    Ok(Json(state.cached_billing().await?))
+}
+
+pub async fn billing_csv(
+    Extension(state): Extension<State>,
+) -> Result<impl IntoResponse, RestError> {
+    log::info!("{{\"fn\": \"billing_csv\", \"method\":\"get\"}}");
+    let billing = state.get_billing().await?;
+
+    let lines = csv_lines(&billing)
+        .into_iter()
+        .map(Ok::<String, std::convert::Infallible>);
+    let body = StreamBody::new(futures::stream::iter(lines));
+
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"billing.csv\""),
+        ],
+        body,
+    ))
+}
+
+pub async fn billing_focus(
+    Extension(state): Extension<State>,
+) -> Result<impl IntoResponse, RestError> {
+    log::info!("{{\"fn\": \"billing_focus\", \"method\":\"get\"}}");
+    let billing = state.get_billing().await?;
+
+    let lines = focus_lines(&billing)
+        .into_iter()
+        .map(Ok::<String, std::convert::Infallible>);
+    let body = StreamBody::new(futures::stream::iter(lines));
+
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"focus.csv\""),
+        ],
+        body,
+    ))
+}
+
+pub async fn report(Extension(state): Extension<State>) -> Result<Html<String>, RestError> {
+    log::info!("{{\"fn\": \"report\", \"method\":\"get\"}}");
+    let billing = state.get_billing().await?;
+    Ok(Html(crate::report::render(&billing)))
+}
+
+pub async fn billing_daily(Extension(state): Extension<State>) -> Result<Json<Value>, RestError> {
+    log::info!("{{\"fn\": \"billing_daily\", \"method\":\"get\"}}");
+    let billing = state.cached_billing().await?;
+    Ok(Json(json!({ "clusters": crate::report::daily(&billing) })))
+}
+
+pub async fn dashboard() -> Html<&'static str> {
+    log::info!("{{\"fn\": \"dashboard\", \"method\":\"get\"}}");
+    Html(include_str!("dashboard.html"))
+}
+
+// Grafana tests the datasource with a plain get on its url
+pub async fn grafana_test() -> Json<Value> {
+    log::info!("{{\"fn\": \"grafana_test\", \"method\":\"get\"}}");
+    Json(json!({ "msg": "Ok"}))
+}
+
+pub async fn grafana_search(Json(payload): Json<grafana::Search>) -> Json<Vec<&'static str>> {
+    log::info!("{{\"fn\": \"grafana_search\", \"method\":\"post\"}}");
+    Json(grafana::search(&payload))
+}
+
+pub async fn grafana_query(
+    Extension(state): Extension<State>,
+    Json(payload): Json<grafana::Query>,
+) -> Result<Json<Vec<Value>>, RestError> {
+    log::info!("{{\"fn\": \"grafana_query\", \"method\":\"post\"}}");
+    let billing = state.cached_billing().await?;
+    Ok(Json(grafana::query(&billing, &payload)))
+}
+
+pub async fn grafana_annotations(
+    Extension(state): Extension<State>,
+    Json(payload): Json<grafana::Annotations>,
+) -> Result<Json<Vec<Value>>, RestError> {
+    log::info!("{{\"fn\": \"grafana_annotations\", \"method\":\"post\"}}");
+    let billing = state.cached_billing().await?;
+    Ok(Json(grafana::annotations(&billing, &payload)))
+}
+
+pub async fn grafana_dashboard(Extension(state): Extension<State>) -> Json<Value> {
+    log::info!("{{\"fn\": \"grafana_dashboard\", \"method\":\"get\"}}");
+    Json(grafana::dashboard(state.currency_agnostic_names))
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(serde::Deserialize, Debug)]
+pub struct HistoryQuery {
+    days: Option<i64>,
+}
+
+#[cfg(feature = "sqlite")]
+pub async fn history(
+    Extension(state): Extension<State>,
+    axum::extract::Query(query): axum::extract::Query<HistoryQuery>,
+) -> Result<Json<Value>, RestError> {
+    log::info!("{{\"fn\": \"history\", \"method\":\"get\"}}");
+    let sqlite = state.sqlite.as_ref().ok_or_else(|| {
+        RestError::Http(StatusCode::NOT_FOUND, "sqlite_path is not set".to_string())
+    })?;
+
+    let since = chrono::Utc::now().timestamp_millis() - query.days.unwrap_or(30) * 86_400_000;
+    match sqlite.history(since).await {
+        Ok(refreshes) => Ok(Json(json!({ "refreshes": refreshes }))),
+        Err(e) => Err(RestError::Http(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            e.to_string(),
+        )),
+    }
 }
 
 pub async fn health() -> Json<Value> {
@@ -30,6 +211,79 @@ pub async fn health() -> Json<Value> {
     Json(json!({ "msg": "Healthy"}))
 }
 
+// Ready once the initial fetch has completed, and as long as Atlas accepts the credentials
+async fn readiness(state: &State) -> Result<(), String> {
+    if !state.ready.load(Ordering::SeqCst) {
+        return Err("Waiting for initial fetch from Atlas".to_string());
+    }
+    state.validate_credentials().await
+}
+
+fn probe(result: Result<(), String>, msg: &str) -> Result<Json<Value>, RestError> {
+    match result {
+        Ok(()) => Ok(Json(json!({ "msg": msg }))),
+        Err(reason) => Err(RestError::Http(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Not {}: {}", msg.to_lowercase(), reason),
+        )),
+    }
+}
+
+pub async fn ready(Extension(state): Extension<State>) -> impl IntoResponse {
+    log::info!("{{\"fn\": \"ready\", \"method\":\"get\"}}");
+    probe(readiness(&state).await, "Ready")
+}
+
+// Like ready, but also requires the billing data to be fresh
+pub async fn readyz(Extension(state): Extension<State>) -> impl IntoResponse {
+    log::info!("{{\"fn\": \"readyz\", \"method\":\"get\"}}");
+    let result = match readiness(&state).await {
+        Ok(()) => state.check_fresh(),
+        Err(e) => Err(e),
+    };
+    probe(result, "Ready")
+}
+
+// Alive as long as the runtime keeps ticking, regardless of Atlas, so that a long Atlas
+// outage does not get the pod restarted
+pub async fn livez(Extension(state): Extension<State>) -> impl IntoResponse {
+    probe(state.check_heartbeat(), "Alive")
+}
+
+// Report on the fetches from Atlas, so that an unreachable Atlas can be told apart from
+// the exporter being down
+pub async fn health_deep(Extension(state): Extension<State>) -> Json<Value> {
+    log::info!("{{\"fn\": \"health_deep\", \"method\":\"get\"}}");
+    let health = &state.fetch_health;
+    let error_streak = health.error_streak.load(Ordering::SeqCst);
+    let last_error = health
+        .last_error
+        .read()
+        .expect("fetch health poisoned")
+        .clone();
+    let fetched = state.fetched.load(Ordering::SeqCst);
+
+    let (last_success, seconds_since_success) = match fetched {
+        0 => (None, None),
+        ms => (
+            chrono::DateTime::from_timestamp_millis(ms).map(|t| t.to_rfc3339()),
+            Some((chrono::Utc::now().timestamp_millis() - ms) / 1000),
+        ),
+    };
+
+    Json(json!({
+        "msg": if error_streak == 0 { "Healthy" } else { "Atlas unreachable" },
+        "atlas": {
+            "last_fetch": if error_streak == 0 { "ok" } else { "error" },
+            // Errors render as json objects already
+            "last_error": last_error.map(|e| serde_json::from_str::<Value>(&e).unwrap_or(Value::String(e))),
+            "error_streak": error_streak,
+            "last_success": last_success,
+            "seconds_since_success": seconds_since_success,
+        }
+    }))
+}
+
 pub async fn root() -> Json<Value> {
     log::info!("{{\"fn\": \"root\", \"method\":\"get\"}}");
     Json(
@@ -41,22 +295,76 @@ pub async fn help() -> Json<Value> {
     log::info!("{{\"fn\": \"help\", \"method\":\"get\"}}");
     let payload = json!({"paths": {
             "/health": "Get the health of the api",
+            "/health/deep": "Get the status of the last fetch from Atlas",
+            "/livez": "Get whether the process is alive and responsive",
+            "/readyz": "Get whether the exporter is ready and its billing data is fresh",
+            "/ready": "Get whether the initial fetch has completed and the Atlas credentials are valid",
             "/metrics": "Get Elastic Billing Metrics",
+            "/metrics/total": "Get only the billing totals",
+            "/metrics/rate": "Get only the billing rates",
+            "/api/v1/billing": "Get the billing totals and rates as json",
+            "/api/v1/billing.csv": "Get the billing totals as csv",
+            "/api/v1/billing/focus.csv": "Get the billing line items as FOCUS csv",
+            "/report": "Get a html summary of the current billing period",
+            "/dashboard": "Browse the cached billing data",
+            "/api/v1/billing/cached": "Get the cached billing totals and rates as json",
+            "/api/v1/billing/daily": "Get the cached daily cost per cluster as json",
+            "/grafana/search": "Grafana json datasource search",
+            "/grafana/query": "Grafana json datasource query, backed by the cached invoice",
+            "/grafana/annotations": "Grafana json datasource annotations for the billing period",
+            "/grafana/dashboard.json": "Get a Grafana dashboard for the exporter metrics",
+            "/api/v1/history": "Get the org totals of past refreshes, with the sqlite feature",
             "/help": "Show this help message"
         }
     });
     Json(payload)
 }
 
-pub async fn handler_404(OriginalUri(original_uri): OriginalUri) -> impl IntoResponse {
+// Turn a panic in a handler into a 500 instead of losing the connection, the panic hook
+// has logged the backtrace already
+pub fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response<Full<Bytes>> {
+    let details = match err.downcast_ref::<String>() {
+        Some(s) => s.clone(),
+        None => match err.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => "Unknown panic".to_string(),
+        },
+    };
+    log::error!(
+        "{{\"fn\": \"handle_panic\", \"panic\":{}}}",
+        Value::String(details)
+    );
+
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(error::body(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "HTTP 500 Internal Server Error",
+        )))
+        .unwrap()
+}
+
+// Requests beyond the concurrency limit are shed rather than queued behind slow fetches
+pub async fn handle_overload(err: BoxError) -> RestError {
+    match err.is::<Overloaded>() {
+        true => {
+            metrics::increment_counter!("atlas_billing_http_requests_shed_total");
+            RestError::Http(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many concurrent requests".to_string(),
+            )
+        }
+        false => RestError::Http(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+pub async fn handler_404(OriginalUri(original_uri): OriginalUri) -> RestError {
     let parts = original_uri.into_parts();
     let path_and_query = parts.path_and_query.expect("Missing post path and query");
     log::info!(
         "{{\"fn\": \"handler_404\", \"method\":\"get\", \"path\":\"{}\"}}",
         path_and_query
     );
-    (
-        StatusCode::NOT_FOUND,
-        "{\"error_code\": 404, \"message\": \"HTTP 404 Not Found\"}",
-    )
+    RestError::Http(StatusCode::NOT_FOUND, "HTTP 404 Not Found".to_string())
 }
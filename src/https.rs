@@ -1,28 +1,392 @@
+use async_compression::tokio::bufread::GzipDecoder;
 use core::time::Duration;
+use futures::TryStreamExt;
+use hyper::client::connect::{Connected, Connection};
 use hyper::client::HttpConnector;
-use hyper::Body;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::service::Service;
+use hyper::{Body, Response, Uri};
 use hyper_tls::HttpsConnector;
-use native_tls::TlsConnector;
+use native_tls::{Certificate, Identity, TlsConnector};
 use std::error::Error;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_socks::IntoTargetAddr;
+use tokio_util::io::{ReaderStream, StreamReader};
 
-pub type HttpsClient = hyper::client::Client<HttpsConnector<HttpConnector>, Body>;
+use crate::config::Config;
+use crate::dns::Resolver;
+
+pub type HttpsClient = hyper::client::Client<HttpsConnector<Connector>, Body>;
 type BoxResult<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
-pub fn create_https_client(timeout: u64) -> BoxResult<HttpsClient> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyKind {
+    Http,
+    // Resolving Atlas locally, or by the proxy with socks5h
+    Socks5,
+    Socks5h,
+}
+
+// A proxy that connections to Atlas are tunneled through, with CONNECT for http proxies,
+// except to hosts matching no_proxy
+#[derive(Debug)]
+pub struct Proxy {
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    no_proxy: Vec<String>,
+}
+
+// The setting, or the variables curl and most other clients honor
+fn flag_or_env(value: Option<&str>, vars: &[&str]) -> Option<String> {
+    value
+        .map(str::to_string)
+        .or_else(|| vars.iter().find_map(|var| std::env::var(var).ok()))
+        .filter(|value| !value.trim().is_empty())
+}
+
+impl Proxy {
+    pub fn new(config: &Config) -> BoxResult<Option<Proxy>> {
+        let url = match flag_or_env(config.proxy.as_deref(), &["HTTPS_PROXY", "https_proxy"]) {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let uri: Uri = url
+            .trim()
+            .parse()
+            .map_err(|_| "Supplied proxy is not a valid url".to_string())?;
+        let (kind, default_port) = match uri.scheme_str() {
+            Some("http") | None => (ProxyKind::Http, 80),
+            Some("socks5") => (ProxyKind::Socks5, 1080),
+            Some("socks5h") => (ProxyKind::Socks5h, 1080),
+            Some(scheme) => return Err(format!("Unsupported proxy scheme {scheme}").into()),
+        };
+        let authority = uri.authority().ok_or("Supplied proxy has no host")?;
+
+        // Credentials in the url are percent-encoded
+        let credentials = match authority.as_str().rsplit_once('@') {
+            Some((userinfo, _)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                let decode = |part| percent_encoding::percent_decode_str(part).decode_utf8();
+                Some((decode(user)?.to_string(), decode(password)?.to_string()))
+            }
+            None => None,
+        };
+
+        let no_proxy = flag_or_env(config.no_proxy.as_deref(), &["NO_PROXY", "no_proxy"])
+            .unwrap_or_default()
+            .split(',')
+            .map(|host| {
+                host.trim()
+                    .trim_start_matches("*.")
+                    .trim_start_matches('.')
+                    .to_lowercase()
+            })
+            .filter(|host| !host.is_empty())
+            .collect();
+
+        Ok(Some(Proxy {
+            kind,
+            host: authority.host().to_string(),
+            port: authority.port_u16().unwrap_or(default_port),
+            credentials,
+            no_proxy,
+        }))
+    }
+
+    // Hosts in no_proxy match themselves and their subdomains, and * matches any host
+    fn bypass(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.no_proxy
+            .iter()
+            .any(|entry| entry == "*" || host == *entry || host.ends_with(&format!(".{entry}")))
+    }
+
+    async fn tunnel(
+        &self,
+        mut http: HttpConnector<Resolver>,
+        resolver: Resolver,
+        dst: Uri,
+    ) -> BoxResult<TcpStream> {
+        let host = dst.host().ok_or("Missing host to connect to")?;
+        let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+        let proxy: Uri = format!("http://{}:{}", self.host, self.port).parse()?;
+        let stream = http.call(proxy).await.map_err(|e| {
+            format!(
+                "Could not connect to proxy {}:{}: {}",
+                self.host, self.port, e
+            )
+        })?;
+
+        match self.kind {
+            ProxyKind::Http => self.connect(stream, host, port).await,
+            ProxyKind::Socks5 => {
+                let mut addr = resolver
+                    .resolve(host)
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| format!("Could not resolve {host}"))?;
+                addr.set_port(port);
+                self.socks(stream, addr).await
+            }
+            ProxyKind::Socks5h => self.socks(stream, (host, port)).await,
+        }
+    }
+
+    // Credentials are sent to http proxies as basic auth
+    async fn connect(&self, mut stream: TcpStream, host: &str, port: u16) -> BoxResult<TcpStream> {
+        let mut connect = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some((user, password)) = &self.credentials {
+            connect.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{user}:{password}"))
+            ));
+        }
+        connect.push_str("\r\n");
+        stream.write_all(connect.as_bytes()).await?;
+
+        // The proxy answers with a status line and headers, then relays the tunnel
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            if head.len() > 8192 {
+                return Err("Proxy response to CONNECT is too long".into());
+            }
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                return Err("Proxy closed the connection during CONNECT".into());
+            }
+            head.extend_from_slice(&buf[..read]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status = head.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some("200") => Ok(stream),
+            _ => Err(format!("Proxy refused CONNECT to {host}:{port}: {status}").into()),
+        }
+    }
+
+    async fn socks<'t, T: IntoTargetAddr<'t>>(
+        &self,
+        stream: TcpStream,
+        target: T,
+    ) -> BoxResult<TcpStream> {
+        let stream = match &self.credentials {
+            Some((user, password)) => {
+                Socks5Stream::connect_with_password_and_socket(stream, target, user, password)
+                    .await?
+            }
+            None => Socks5Stream::connect_with_socket(stream, target).await?,
+        };
+        Ok(stream.into_inner())
+    }
+}
+
+// A connection, counted against max_connections until it is closed
+pub struct Conn {
+    stream: TcpStream,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Connection for Conn {
+    fn connected(&self) -> Connected {
+        self.stream.connected()
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Connects directly, or through the proxy when one is configured, waiting for a connection
+// to close first when max_connections are open
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector<Resolver>,
+    resolver: Resolver,
+    proxy: Option<Arc<Proxy>>,
+    timeout: Duration,
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl Service<Uri> for Connector {
+    type Response = Conn;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = BoxResult<Conn>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<BoxResult<()>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        let resolver = self.resolver.clone();
+        let proxy = self.proxy.clone();
+        let timeout = self.timeout;
+        let connections = self.connections.clone();
+        Box::pin(async move {
+            let permit = match connections {
+                Some(connections) => Some(connections.acquire_owned().await?),
+                None => None,
+            };
+            let stream = match proxy {
+                Some(proxy) if !proxy.bypass(dst.host().unwrap_or_default()) => {
+                    tokio::time::timeout(timeout, proxy.tunnel(http, resolver, dst))
+                        .await
+                        .map_err(|_| "Timed out connecting through proxy")??
+                }
+                _ => http.call(dst).await?,
+            };
+            Ok(Conn {
+                stream,
+                _permit: permit,
+            })
+        })
+    }
+}
+
+// Atlas compresses responses when asked to. The body is decompressed as it is read, so that
+// size limits apply to the decompressed invoice.
+pub fn decompress(response: Response<Body>) -> Response<Body> {
+    let gzip = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+        .map(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"))
+        .unwrap_or(false);
+    if !gzip {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+    let reader = StreamReader::new(body.map_err(std::io::Error::other));
+    let decoded = ReaderStream::new(GzipDecoder::new(reader));
+    Response::from_parts(parts, Body::wrap_stream(decoded))
+}
+
+fn ca_certificates(path: &str) -> BoxResult<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).map_err(|e| format!("Could not open ca_file {path}: {e}"))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in ca_file {path}").into());
+    }
+    certs
+        .iter()
+        .map(|cert| Ok(Certificate::from_der(cert)?))
+        .collect()
+}
+
+// The client for Atlas, which only verifies certificates when a ca_file is given
+pub fn create_https_client(config: &Config) -> BoxResult<HttpsClient> {
+    https_client(config, config.ca_file.is_none())
+}
+
+// A client for anything but Atlas, which always verifies certificates
+pub fn create_verified_https_client(config: &Config) -> BoxResult<HttpsClient> {
+    https_client(config, false)
+}
+
+fn https_client(config: &Config, accept_invalid_certs: bool) -> BoxResult<HttpsClient> {
+    let mut tls = TlsConnector::builder();
+    // Verify Atlas, or a TLS intercepting proxy in front of it, against the system roots
+    // and the certificates in the file
+    if let Some(path) = &config.ca_file {
+        for cert in ca_certificates(path)? {
+            tls.add_root_certificate(cert);
+        }
+    }
     // All this junk is needed to ensure that we can connect to an endpoint with bad certs/hostname
-    let tls = TlsConnector::builder()
-        .danger_accept_invalid_hostnames(true)
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    if accept_invalid_certs {
+        tls.danger_accept_invalid_hostnames(true)
+            .danger_accept_invalid_certs(true);
+    }
+
+    // Presented to an egress gateway, or anything else in the way, that requires client certificates
+    if let (Some(cert), Some(key)) = (&config.client_cert, &config.client_key) {
+        let cert =
+            std::fs::read(cert).map_err(|e| format!("Could not read client_cert {cert}: {e}"))?;
+        let key =
+            std::fs::read(key).map_err(|e| format!("Could not read client_key {key}: {e}"))?;
+        tls.identity(Identity::from_pkcs8(&cert, &key)?);
+    }
+    let tls = tls.build()?;
 
-    let mut http = hyper::client::HttpConnector::new();
+    let resolver = Resolver::new(config)?;
+    let mut http = HttpConnector::new_with_resolver(resolver.clone());
 
     // Create timeout Duration
-    let timeout = Duration::new(timeout, 0);
+    let timeout = Duration::new(config.timeout, 0);
     http.set_connect_timeout(Some(timeout));
 
     http.enforce_http(false);
-    let https: hyper_tls::HttpsConnector<hyper::client::HttpConnector> =
-        hyper_tls::HttpsConnector::from((http, tls.into()));
-    Ok(hyper::Client::builder().build::<_, hyper::Body>(https))
+
+    let proxy = Proxy::new(config)?;
+    if let Some(proxy) = &proxy {
+        log::info!(
+            "{{\"fn\": \"create_https_client\", \"proxy\":\"{}:{}\", \"kind\":\"{}\"}}",
+            proxy.host,
+            proxy.port,
+            format!("{:?}", proxy.kind).to_lowercase()
+        );
+    }
+    let max_connections = config.max_connections;
+    let connector = Connector {
+        http,
+        resolver,
+        proxy: proxy.map(Arc::new),
+        timeout,
+        connections: (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections))),
+    };
+
+    // Idle connections are kept open for reuse by later fetches
+    let mut builder = hyper::Client::builder();
+    builder.pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout));
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        builder.pool_max_idle_per_host(max_idle);
+    }
+
+    let https = HttpsConnector::from((connector, tls.into()));
+    Ok(builder.build::<_, hyper::Body>(https))
 }
@@ -0,0 +1,99 @@
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::metrics::Point;
+use crate::sink::{Emit, Sink, Snapshot};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Writes the billing series in line protocol to the InfluxDB v2 write api, which is
+// also served by VictoriaMetrics
+#[derive(Debug, Clone)]
+pub struct Influx {
+    url: String,
+    org: Option<String>,
+    bucket: String,
+    token: Option<String>,
+    client: reqwest::Client,
+}
+
+// Tag keys and values need commas, equal signs and spaces escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn line(point: &Point, now: i64) -> String {
+    let mut line = escape(point.name);
+    for (k, v) in point.labels.iter().filter(|(_, v)| !v.is_empty()) {
+        line.push_str(&format!(",{}={}", escape(k), escape(v)));
+    }
+    let timestamp = point.timestamp.unwrap_or(now);
+    line.push_str(&format!(" value={} {}\n", point.value, timestamp));
+    line
+}
+
+impl Influx {
+    pub fn new(url: &str, org: Option<&str>, bucket: &str, token: Option<&str>) -> Self {
+        Influx {
+            url: format!("{}/api/v2/write", url.trim_end_matches('/')),
+            org: org.map(|o| o.to_string()),
+            bucket: bucket.to_string(),
+            token: token.map(|t| t.to_string()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn push(&self, points: &[Point]) -> BoxResult<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+
+        let body: String = points.iter().map(|p| line(p, now)).collect();
+
+        let mut query = vec![("bucket", self.bucket.as_str()), ("precision", "ms")];
+        if let Some(org) = &self.org {
+            query.push(("org", org));
+        }
+
+        let mut request = self
+            .client
+            .post(&self.url)
+            .query(&query)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Token {token}"));
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            log::error!("InfluxDB write failed with {}: {}", status, text);
+            return Err(format!("InfluxDB returned {status}").into());
+        }
+
+        log::info!(
+            "{{\"fn\": \"influx_push\", \"url\":\"{}\", \"points\":{}}}",
+            self.url,
+            points.len()
+        );
+        Ok(())
+    }
+}
+
+impl Sink for Influx {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move { self.push(snapshot.points).await })
+    }
+}
@@ -0,0 +1,118 @@
+//! Prometheus exporter for MongoDB Atlas billing, along with the client it is built on.
+//!
+//! [`State`] holds the Atlas client, which signs requests with digest auth, fetches the
+//! invoices of the configured orgs, and aggregates their line items into totals and rates
+//! per sku. It is built from a [`Config`], whose defaults are those of the exporter, so
+//! that only the credentials and orgs need to be set. The aggregation itself is a plain
+//! function, [`aggregate::aggregate`], over the line items.
+//!
+//! ```no_run
+//! use mongo_atlas_billing_exporter::{Config, State};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! let config = Config {
+//!     series_ttl: 3,
+//!     ..Config::new("public", "private", &["org"])
+//! };
+//! let state = State::new(config).await?;
+//!
+//! // Fetch the current invoice of every org, and aggregate it
+//! let billing = state.get_billing().await?;
+//! for (key, total) in &billing.totals {
+//!     println!("{key}: {} cents", total.total_price_cents);
+//! }
+//!
+//! // Or refresh the billing series, as the exporter does on a scrape
+//! state.get_metrics().await?;
+//! print!("{}", state.render());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The command line, the HTTP server, the mock server and the push based sinks are behind
+//! the default `cli` feature. The binary is a thin wrapper around [`cli::run`], which turns
+//! the options into a [`Config`] with [`cli::config`].
+
+#[cfg(feature = "cli")]
+mod access_log;
+pub mod aggregate;
+#[cfg(feature = "cli")]
+mod auth;
+#[cfg(feature = "cli")]
+mod backfill;
+mod bigquery;
+mod cache;
+mod circuit;
+#[cfg(feature = "cli")]
+pub mod cli;
+mod config;
+#[cfg(feature = "cli")]
+mod cors;
+mod cost_explorer;
+#[cfg(feature = "cli")]
+mod diff;
+mod dns;
+pub mod error;
+mod exchange;
+#[cfg(feature = "cli")]
+mod export;
+#[cfg(feature = "cli")]
+mod fetch;
+#[cfg(feature = "cli")]
+mod grafana;
+#[cfg(feature = "cli")]
+mod graphite;
+#[cfg(feature = "cli")]
+mod handlers;
+mod https;
+#[cfg(feature = "cli")]
+mod influx;
+#[cfg(feature = "cli")]
+mod listener;
+#[cfg(feature = "cli")]
+mod logging;
+mod metrics;
+#[cfg(feature = "cli")]
+mod mock;
+#[cfg(feature = "mongodb")]
+mod mongo;
+#[cfg(feature = "cli")]
+mod otlp;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "cli")]
+mod process;
+#[cfg(feature = "cli")]
+mod proto;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+#[cfg(feature = "cli")]
+mod rate_limit;
+mod recording;
+#[cfg(feature = "cli")]
+mod remote_write;
+#[cfg(feature = "cli")]
+mod report;
+#[cfg(feature = "cli")]
+mod request_id;
+#[cfg(feature = "cli")]
+mod rules;
+mod s3;
+mod sanitize;
+mod sink;
+mod sku;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+pub mod state;
+#[cfg(feature = "cli")]
+mod statsd;
+#[cfg(not(feature = "simd-json"))]
+mod stream;
+mod throttle;
+mod trace_context;
+#[cfg(feature = "cli")]
+mod validate;
+
+pub use config::Config;
+pub use error::Error;
+pub use state::{Billing, Data, LineItem, State};
@@ -0,0 +1,477 @@
+// Serve the app, over TLS with rustls when configured, optionally requiring client
+// certificates signed by a given CA, and tell handlers who is connecting
+use axum::extract::connect_info::Connected;
+use axum::Router;
+use clap::ArgMatches;
+use hyper::server::accept::Accept;
+use notify::{RecursiveMode, Watcher};
+use std::error::Error;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::{Instant, Sleep};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RELOAD_DELAY: Duration = Duration::from_secs(1);
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// The remote address of a connection, and the subject of its client certificate if any
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub subject: Option<String>,
+}
+
+impl Connected<&Conn> for Peer {
+    fn connect_info(target: &Conn) -> Self {
+        target.peer.clone()
+    }
+}
+
+fn client_subject(stream: &TlsStream<TcpStream>) -> Option<String> {
+    stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| x509_parser::parse_x509_certificate(&cert.0).ok())
+        .map(|(_, cert)| cert.subject().to_string())
+}
+
+// Limits on connections, against clients holding them open by sending or reading slowly
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub header_read_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub max_header_bytes: usize,
+}
+
+impl Limits {
+    pub fn new(opts: &ArgMatches) -> Limits {
+        let seconds = |name: &str, default: u64| -> u64 {
+            opts.value_of(name).unwrap().parse().unwrap_or_else(|_| {
+                eprintln!("Supplied {name} not in range, defaulting to {default}");
+                default
+            })
+        };
+        let enabled = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        let max_header_bytes: usize = opts
+            .value_of("max_header_bytes")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|_| {
+                eprintln!("Supplied max_header_bytes not in range, defaulting to 16384");
+                16384
+            });
+
+        Limits {
+            header_read_timeout: Duration::from_secs(seconds("header_read_timeout", 10).max(1)),
+            idle_timeout: enabled(seconds("idle_timeout", 120)),
+            write_timeout: enabled(seconds("write_timeout", 30)),
+            // hyper cannot read requests with a buffer smaller than this
+            max_header_bytes: max_header_bytes.max(8192),
+        }
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+// An accepted connection, closed once nothing has been received or sent for the idle
+// timeout, or once a write has been blocked on the client for the write timeout
+pub struct Conn {
+    stream: Stream,
+    peer: Peer,
+    idle_timeout: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+    write_timeout: Option<Duration>,
+    write: Option<Pin<Box<Sleep>>>,
+}
+
+impl Conn {
+    fn new(stream: Stream, peer: Peer, limits: &Limits) -> Conn {
+        Conn {
+            stream,
+            peer,
+            idle_timeout: limits.idle_timeout,
+            idle: limits.idle_timeout.map(|t| Box::pin(tokio::time::sleep(t))),
+            write_timeout: limits.write_timeout,
+            write: None,
+        }
+    }
+
+    fn touch(&mut self) {
+        if let (Some(idle), Some(timeout)) = (&mut self.idle, self.idle_timeout) {
+            idle.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(idle) = &mut self.idle {
+            if idle.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection idle",
+                )));
+            }
+        }
+        Poll::Pending
+    }
+
+    fn poll_write_timeout<T>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        if let Some(timeout) = self.write_timeout {
+            let write = self
+                .write
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+            if write.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "write timed out",
+                )));
+            }
+        }
+        Poll::Pending
+    }
+
+    fn written<T>(&mut self, result: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        self.write = None;
+        self.touch();
+        result
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = match &mut this.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        };
+        match result {
+            Poll::Pending => this.poll_idle(cx),
+            ready => {
+                this.touch();
+                ready
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = match &mut this.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, data),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, data),
+        };
+        match result {
+            Poll::Pending => this.poll_write_timeout(cx),
+            ready => this.written(ready),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = match &mut this.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, data),
+            Stream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, data),
+        };
+        match result {
+            Poll::Pending => this.poll_write_timeout(cx),
+            ready => this.written(ready),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.stream {
+            Stream::Plain(stream) => stream.is_write_vectored(),
+            Stream::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = match &mut this.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        };
+        match result {
+            Poll::Pending => this.poll_write_timeout(cx),
+            ready => this.written(ready),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+fn certificates(path: &str) -> BoxResult<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).map_err(|e| format!("Could not open certificate {path}: {e}"))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    match certs.is_empty() {
+        true => Err(format!("No certificates found in {path}").into()),
+        false => Ok(certs.into_iter().map(Certificate).collect()),
+    }
+}
+
+fn private_key(path: &str) -> BoxResult<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).map_err(|e| format!("Could not open private key {path}: {e}"))?,
+    );
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("No private key found in {path}").into()),
+        }
+    }
+}
+
+// With a client CA, only clients presenting a certificate signed by it can connect
+pub fn server_config(cert: &str, key: &str, client_ca: Option<&str>) -> BoxResult<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certificates(ca)? {
+                roots.add(&cert)?;
+            }
+            builder.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certificates(cert)?, private_key(key)?)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+// The config in use, swapped out when the certificate files change
+pub type SharedConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+}
+
+impl TlsFiles {
+    pub fn load(&self) -> BoxResult<ServerConfig> {
+        server_config(&self.cert, &self.key, self.client_ca.as_deref())
+    }
+
+    // Load the config, and reload it whenever one of the files changes, such as when
+    // cert-manager rotates a certificate. Parent directories are watched, as Kubernetes
+    // swaps mounted secrets by replacing a symlink next to the files.
+    pub fn watch(self) -> BoxResult<SharedConfig> {
+        let config: SharedConfig = Arc::new(RwLock::new(Arc::new(self.load()?)));
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if event.is_ok() {
+                    let _ = tx.send(());
+                }
+            })?;
+        let mut dirs: Vec<PathBuf> = [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|file| Path::new(file).canonicalize().ok())
+            .filter_map(|file| file.parent().map(Path::to_path_buf))
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in &dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+
+        let shared = config.clone();
+        tokio::spawn(async move {
+            // Keep the watcher alive for as long as events are handled
+            let _watcher = watcher;
+            while rx.recv().await.is_some() {
+                // Let a rotation that touches several files settle before reloading
+                tokio::time::sleep(RELOAD_DELAY).await;
+                while rx.try_recv().is_ok() {}
+
+                match self.load() {
+                    Ok(reloaded) => {
+                        *shared.write().expect("tls config poisoned") = Arc::new(reloaded);
+                        log::info!("{{\"fn\": \"tls_reload\", \"cert\":\"{}\"}}", self.cert);
+                    }
+                    // Keep serving the previous certificate until the files are valid again
+                    Err(e) => log::error!(
+                        "{{\"fn\": \"tls_reload\", \"error\":{}}}",
+                        serde_json::Value::String(e.to_string())
+                    ),
+                }
+            }
+        });
+
+        Ok(config)
+    }
+}
+
+// Accept connections and complete any TLS handshakes concurrently, so that a slow or failing
+// handshake does not hold up other clients
+fn incoming(
+    listener: TcpListener,
+    tls: Option<SharedConfig>,
+    limits: Limits,
+) -> impl Accept<Conn = Conn, Error = io::Error> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<io::Result<Conn>>(64);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let config = match &tls {
+                Some(config) => config.read().expect("tls config poisoned").clone(),
+                None => {
+                    let peer = Peer {
+                        addr,
+                        subject: None,
+                    };
+                    let conn = Conn::new(Stream::Plain(stream), peer, &limits);
+                    if tx.send(Ok(conn)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let acceptor = TlsAcceptor::from(config);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let peer = Peer {
+                            addr,
+                            subject: client_subject(&stream),
+                        };
+                        let conn = Conn::new(Stream::Tls(Box::new(stream)), peer, &limits);
+                        let _ = tx.send(Ok(conn)).await;
+                    }
+                    Ok(Err(e)) => log::warn!(
+                        "{{\"fn\": \"tls_handshake\", \"remote\":\"{}\", \"error\":{}}}",
+                        addr,
+                        serde_json::Value::String(e.to_string())
+                    ),
+                    Err(_) => log::warn!(
+                        "{{\"fn\": \"tls_handshake\", \"remote\":\"{}\", \"error\":\"timed out\"}}",
+                        addr
+                    ),
+                }
+            });
+        }
+    });
+
+    hyper::server::accept::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|conn| (conn, rx))
+    }))
+}
+
+// Bind a listener ahead of serving it, so that every address is known to be free before any
+// of them starts serving
+pub fn bind(addr: SocketAddr) -> BoxResult<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)
+        .map_err(|e| format!("Could not listen on {addr}: {e}"))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+// Listeners passed in by systemd socket activation, as sd_listen_fds does, when the
+// LISTEN_PID and LISTEN_FDS it sets are meant for this process
+pub fn activated() -> BoxResult<Option<Vec<std::net::TcpListener>>> {
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if pid != Some(std::process::id()) || fds < 1 {
+        return Ok(None);
+    }
+
+    let listeners = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .map(|fd| {
+            // systemd hands over the descriptors from 3 onwards, each of them taken only once
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener
+                .local_addr()
+                .map_err(|e| format!("Socket {fd} from systemd is not a TCP listener: {e}"))?;
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect::<BoxResult<Vec<_>>>()?;
+    Ok(Some(listeners))
+}
+
+// Serve the app until stop changes, over TLS when a config is given
+pub async fn serve(
+    listener: std::net::TcpListener,
+    app: Router,
+    tls: Option<SharedConfig>,
+    limits: Limits,
+    mut stop: watch::Receiver<bool>,
+) -> BoxResult<()> {
+    let addr = listener.local_addr()?;
+    match tls.is_some() {
+        true => println!("Listening on {addr} with TLS"),
+        false => println!("Listening on {addr}"),
+    }
+    axum::Server::builder(incoming(TcpListener::from_std(listener)?, tls, limits))
+        .http1_header_read_timeout(limits.header_read_timeout)
+        .http1_max_buf_size(limits.max_header_bytes)
+        .http2_max_header_list_size(limits.max_header_bytes as u32)
+        .serve(app.into_make_service_with_connect_info::<Peer>())
+        .with_graceful_shutdown(async move {
+            let _ = stop.changed().await;
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,381 @@
+// Log through tracing, so that each line carries the spans it was logged in, such as the
+// request being handled or the refresh in progress. Lines logged with the log macros are
+// forwarded to tracing as well.
+use chrono::Local;
+use clap::ArgMatches;
+use opentelemetry::global;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::otlp;
+
+// Collects the message of an event, and any other fields it carries
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name if name.starts_with("log.") => (),
+            name => {
+                self.fields
+                    .insert(name.to_string(), Value::String(value.to_string()));
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Json,
+    Plain,
+    Logfmt,
+}
+
+// Repeated identical errors and warnings, such as during an Atlas outage, are logged burst
+// times, then once per interval along with the number of lines suppressed in between
+struct Dedup {
+    burst: u64,
+    interval: Duration,
+    seen: Mutex<HashMap<String, Seen>>,
+}
+
+struct Seen {
+    count: u64,
+    suppressed: u64,
+    last_logged: Instant,
+    last_seen: Instant,
+}
+
+impl Dedup {
+    // Returns the number of suppressed lines to report, or None when the line is suppressed
+    fn check(&self, message: &str) -> Option<u64> {
+        self.check_at(message, Instant::now())
+    }
+
+    fn check_at(&self, message: &str, now: Instant) -> Option<u64> {
+        let mut seen = self.seen.lock().expect("log dedup poisoned");
+
+        // Forget messages that have not recurred within the interval
+        if seen.len() > 1000 {
+            seen.retain(|_, s| now.duration_since(s.last_seen) < self.interval);
+        }
+
+        let entry = seen.entry(message.to_string()).or_insert(Seen {
+            count: 0,
+            suppressed: 0,
+            last_logged: now,
+            last_seen: now,
+        });
+        // A message that recurs after a quiet interval starts a new burst, which reports the
+        // lines suppressed at the end of the previous one
+        if now.duration_since(entry.last_seen) >= self.interval {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last_seen = now;
+
+        if entry.count <= self.burst || now.duration_since(entry.last_logged) >= self.interval {
+            entry.last_logged = now;
+            Some(std::mem::take(&mut entry.suppressed))
+        } else {
+            entry.suppressed += 1;
+            None
+        }
+    }
+}
+
+struct Format {
+    kind: LogFormat,
+    dedup: Option<Dedup>,
+}
+
+// Quote logfmt values that would otherwise not parse back
+fn logfmt_value(value: &Value) -> String {
+    let value = match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    match value.is_empty()
+        || value.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control())
+    {
+        true => format!("{value:?}"),
+        false => value,
+    }
+}
+
+impl<S> FormatEvent<S, JsonFields> for Format
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let date = Local::now().format("%Y-%m-%dT%H:%M:%S%.6f%:z").to_string();
+
+        // Spans outermost first, with their fields as recorded by JsonFields
+        let mut spans: Vec<(&'static str, Map<String, Value>)> = Vec::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|f| serde_json::from_str(&f.fields).ok())
+                    .unwrap_or_default();
+                spans.push((span.name(), fields));
+            }
+        }
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
+        if let (Some(dedup), true) = (&self.dedup, *metadata.level() <= Level::WARN) {
+            match dedup.check(&fields.message) {
+                None => return Ok(()),
+                Some(0) => (),
+                Some(suppressed) => {
+                    fields
+                        .fields
+                        .insert("suppressed".to_string(), Value::from(suppressed));
+                }
+            }
+        }
+
+        match self.kind {
+            // Messages that are json already are kept as an object, anything else as a string
+            LogFormat::Json => {
+                let mut record = Map::new();
+                record.insert("date".to_string(), Value::String(date));
+                record.insert(
+                    "level".to_string(),
+                    Value::String(metadata.level().to_string()),
+                );
+                if !spans.is_empty() {
+                    let spans = spans
+                        .into_iter()
+                        .map(|(name, f)| (name.to_string(), Value::Object(f)));
+                    record.insert("spans".to_string(), Value::Object(spans.collect()));
+                }
+                if !fields.fields.is_empty() {
+                    record.insert("fields".to_string(), Value::Object(fields.fields));
+                }
+                let log = serde_json::from_str::<Value>(&fields.message)
+                    .ok()
+                    .filter(Value::is_object)
+                    .unwrap_or(Value::String(fields.message));
+                record.insert("log".to_string(), log);
+                writeln!(writer, "{}", Value::Object(record))
+            }
+            LogFormat::Plain => {
+                write!(writer, "{date} {:>5} ", metadata.level())?;
+                for (name, f) in &spans {
+                    let f: Vec<String> = f
+                        .iter()
+                        .map(|(k, v)| format!("{k}={}", logfmt_value(v)))
+                        .collect();
+                    write!(writer, "{name}{{{}}}:", f.join(" "))?;
+                }
+                if !spans.is_empty() {
+                    write!(writer, " ")?;
+                }
+                write!(writer, "{}", fields.message)?;
+                for (k, v) in &fields.fields {
+                    write!(writer, " {k}={}", logfmt_value(v))?;
+                }
+                writeln!(writer)
+            }
+            LogFormat::Logfmt => {
+                write!(
+                    writer,
+                    "time={date} level={}",
+                    metadata.level().as_str().to_lowercase()
+                )?;
+                for (name, f) in &spans {
+                    for (k, v) in f {
+                        write!(writer, " {name}.{k}={}", logfmt_value(v))?;
+                    }
+                }
+                for (k, v) in &fields.fields {
+                    write!(writer, " {k}={}", logfmt_value(v))?;
+                }
+                writeln!(
+                    writer,
+                    " msg={}",
+                    logfmt_value(&Value::String(fields.message))
+                )
+            }
+        }
+    }
+}
+
+// RUST_LOG overrides the log_level. With an otlp_traces_endpoint, spans are
+// exported as well, and the returned provider has to be shut down to flush them on exit.
+pub fn init(opts: &ArgMatches) -> Option<TracerProvider> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(opts.value_of("log_level").unwrap_or("info")));
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let format = match opts.value_of("log_format") {
+        Some("plain") => LogFormat::Plain,
+        Some("logfmt") => LogFormat::Logfmt,
+        _ => LogFormat::Json,
+    };
+
+    let burst: u64 = opts
+        .value_of("log_dedup_burst")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("Supplied log_dedup_burst not in range, defaulting to 5");
+            5
+        });
+    let interval: u64 = opts
+        .value_of("log_dedup_interval")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("Supplied log_dedup_interval not in range, defaulting to 60");
+            60
+        });
+    let dedup = match interval > 0 {
+        true => Some(Dedup {
+            burst,
+            interval: Duration::from_secs(interval),
+            seen: Mutex::new(HashMap::new()),
+        }),
+        false => None,
+    };
+
+    let provider = opts.value_of("otlp_traces_endpoint").and_then(|endpoint| {
+        match otlp::tracer_provider(endpoint, opts.value_of("otlp_traces_headers")) {
+            Ok(provider) => Some(provider),
+            Err(e) => {
+                eprintln!("Failed to set up OTLP trace export: {e}");
+                None
+            }
+        }
+    });
+    let traces = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(otlp::tracer(provider)));
+
+    // Keep stdout for the output of the subcommands that print
+    let writer = match opts.is_present("once")
+        || matches!(
+            opts.subcommand_name(),
+            Some("fetch" | "report" | "validate" | "diff" | "rules" | "alerts")
+        ) {
+        true => BoxMakeWriter::new(std::io::stderr),
+        false => BoxMakeWriter::new(std::io::stdout),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(Format {
+                    kind: format,
+                    dedup,
+                })
+                .with_writer(writer),
+        )
+        .with(traces)
+        .init();
+
+    provider
+}
+
+pub fn shutdown(provider: Option<TracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            log::error!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedup() -> Dedup {
+        Dedup {
+            burst: 2,
+            interval: Duration::from_secs(60),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn logs_a_burst_then_suppresses() {
+        let dedup = dedup();
+        let now = Instant::now();
+
+        assert_eq!(dedup.check_at("error", now), Some(0));
+        assert_eq!(dedup.check_at("error", now), Some(0));
+        assert_eq!(dedup.check_at("error", now), None);
+        assert_eq!(dedup.check_at("error", now), None);
+
+        // Other messages are counted on their own
+        assert_eq!(dedup.check_at("other", now), Some(0));
+    }
+
+    #[test]
+    fn reports_suppressed_lines_once_per_interval() {
+        let dedup = dedup();
+        let now = Instant::now();
+        for _ in 0..5 {
+            dedup.check_at("error", now);
+        }
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(dedup.check_at("error", later), None);
+        assert_eq!(
+            dedup.check_at("error", now + Duration::from_secs(61)),
+            Some(4)
+        );
+        assert_eq!(dedup.check_at("error", now + Duration::from_secs(62)), None);
+    }
+
+    #[test]
+    fn reports_suppressed_lines_when_a_burst_restarts() {
+        let dedup = dedup();
+        let now = Instant::now();
+        for _ in 0..5 {
+            dedup.check_at("error", now);
+        }
+
+        // Quiet for longer than the interval, so the count resets without losing the 3
+        let later = now + Duration::from_secs(120);
+        assert_eq!(dedup.check_at("error", later), Some(3));
+        assert_eq!(dedup.check_at("error", later), Some(0));
+        assert_eq!(dedup.check_at("error", later), None);
+    }
+}
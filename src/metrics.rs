@@ -1,13 +1,24 @@
+#[cfg(feature = "cli")]
 use axum::{http::Request, middleware::Next, response::IntoResponse};
+#[cfg(feature = "cli")]
 use core::time::Duration;
+#[cfg(feature = "cli")]
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+#[cfg(feature = "cli")]
 use metrics_util::MetricKindMask;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "cli")]
 use std::time::Instant;
 
+#[cfg(feature = "cli")]
 pub fn setup_metrics_recorder() -> PrometheusHandle {
     const EXPONENTIAL_SECONDS: &[f64] = &[
         0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
     ];
+    // Atlas requests time out after --timeout seconds, 60 by default
+    const ATLAS_SECONDS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
 
     PrometheusBuilder::new()
         .idle_timeout(
@@ -19,10 +30,16 @@ pub fn setup_metrics_recorder() -> PrometheusHandle {
             EXPONENTIAL_SECONDS,
         )
         .unwrap()
+        .set_buckets_for_metric(
+            Matcher::Full("atlas_api_request_duration_seconds".to_string()),
+            ATLAS_SECONDS,
+        )
+        .unwrap()
         .install_recorder()
         .unwrap()
 }
 
+#[cfg(feature = "cli")]
 pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
     let start = Instant::now();
     let path = req.uri().path().to_owned();
@@ -46,3 +63,454 @@ pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoRespon
 
     response
 }
+
+pub type Labels = Vec<(&'static str, String)>;
+
+#[cfg(feature = "cli")]
+static OPENMETRICS: &str = "application/openmetrics-text";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Prometheus,
+    OpenMetrics,
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+#[cfg(feature = "cli")]
+impl Format {
+    // Pick the exposition format from the Accept header sent by the scraper
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        match accept {
+            #[cfg(feature = "protobuf")]
+            Some(accept) if accept.contains("application/vnd.google.protobuf") => Format::Protobuf,
+            Some(accept) if accept.contains(OPENMETRICS) => Format::OpenMetrics,
+            _ => Format::Prometheus,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            #[cfg(feature = "protobuf")]
+            Format::Protobuf => crate::protobuf::CONTENT_TYPE,
+        }
+    }
+}
+
+// Totals and rates can be scraped separately, as they are useful at different frequencies.
+// Only the server scrapes anything but all of them.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    All,
+    Total,
+    Rate,
+    // Only the series about the exporter itself, without any billing data
+    Exporter,
+}
+
+// Series carrying billing data, rather than describing the exporter
+fn is_billing(name: &str) -> bool {
+    [
+        "item_",
+        "cluster_backup_",
+        "credits_",
+        "usage_daily_",
+        "exchange_rate_",
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(&format!("atlas_billing_{prefix}")))
+}
+
+impl Family {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Family::All => true,
+            Family::Total => !name.ends_with("_rate"),
+            Family::Rate => name.ends_with("_rate"),
+            Family::Exporter => !is_billing(name),
+        }
+    }
+}
+
+// Kept out of the recorder, where idle gauges are removed after a few seconds
+#[cfg(feature = "cli")]
+fn build_info() -> String {
+    format!(
+        "# HELP atlas_billing_exporter_build_info The version, commit and rustc version the exporter was built from\n\
+         # TYPE atlas_billing_exporter_build_info gauge\n\
+         atlas_billing_exporter_build_info{{version=\"{}\",commit=\"{}\",rustc=\"{}\"}} 1\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("BUILD_COMMIT"),
+        env!("BUILD_RUSTC")
+    )
+}
+
+// Combine the recorder output with the billing series into a single exposition
+#[cfg(feature = "cli")]
+pub fn expose(
+    format: Format,
+    recorder: &str,
+    registry: &BillingRegistry,
+    family: Family,
+) -> Vec<u8> {
+    let recorder = &(build_info() + &crate::process::render() + recorder);
+    match format {
+        Format::Prometheus => {
+            (recorder.to_string() + &registry.render(format, family)).into_bytes()
+        }
+        Format::OpenMetrics => {
+            to_openmetrics(recorder, &registry.render(format, family)).into_bytes()
+        }
+        #[cfg(feature = "protobuf")]
+        Format::Protobuf => crate::protobuf::encode(
+            &(recorder.to_string() + &registry.render(Format::Prometheus, family)),
+        ),
+    }
+}
+
+// The recorder only renders the Prometheus text format. OpenMetrics names counter families
+// without their _total suffix, and requires the exposition to be terminated with # EOF.
+#[cfg(feature = "cli")]
+fn to_openmetrics(recorder: &str, billing: &str) -> String {
+    let counters: Vec<&str> = recorder
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|line| line.strip_suffix(" counter"))
+        .collect();
+
+    let mut output = String::new();
+    for line in recorder.lines() {
+        let mut parts: Vec<&str> = line.splitn(4, ' ').collect();
+        if parts.len() >= 3 && parts[0] == "#" && counters.contains(&parts[2]) {
+            if let Some(family) = parts[2].strip_suffix("_total") {
+                parts[2] = family;
+            }
+        }
+        output.push_str(&parts.join(" "));
+        output.push('\n');
+    }
+
+    output.push_str(billing);
+    output.push_str("# EOF\n");
+    output
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    name: &'static str,
+    labels: Labels,
+}
+
+#[derive(Debug, Clone)]
+struct Sample {
+    value: f64,
+    timestamp: Option<i64>,
+    refresh: u64,
+}
+
+// A sample of the series computed by a refresh, as handed to every sink
+#[derive(Debug, Clone)]
+pub struct Point {
+    pub name: &'static str,
+    pub labels: Labels,
+    pub value: f64,
+    pub timestamp: Option<i64>,
+}
+
+impl Point {
+    pub fn new(name: &'static str, labels: Labels, value: f64, timestamp: Option<i64>) -> Self {
+        Point {
+            name,
+            labels,
+            value,
+            timestamp,
+        }
+    }
+}
+
+// Billing series are kept outside of the metrics recorder, as the recorder has no way
+// to attach a timestamp to a sample, or to remove a series once set
+#[derive(Debug, Clone)]
+pub struct BillingRegistry {
+    series: Arc<RwLock<BTreeMap<SeriesKey, Sample>>>,
+    refresh: Arc<AtomicU64>,
+    ttl: u64,
+}
+
+impl BillingRegistry {
+    // Series are kept for ttl refreshes after they were last set
+    pub fn new(ttl: u64) -> Self {
+        BillingRegistry {
+            series: Arc::new(RwLock::new(BTreeMap::new())),
+            refresh: Arc::new(AtomicU64::new(0)),
+            ttl: ttl.max(1),
+        }
+    }
+
+    pub fn set(&self, name: &'static str, labels: Labels, value: f64, timestamp: Option<i64>) {
+        let refresh = self.refresh.load(Ordering::SeqCst);
+        let mut series = self.series.write().expect("billing registry poisoned");
+        series.insert(
+            SeriesKey { name, labels },
+            Sample {
+                value,
+                timestamp,
+                refresh,
+            },
+        );
+    }
+
+    // Start a new refresh, series set from here on are tracked as part of it
+    pub fn start_refresh(&self) -> u64 {
+        self.refresh.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // Remove series that have not been set within the last ttl refreshes, such as
+    // clusters that have been terminated, or projects that have been decommissioned
+    pub fn expire(&self) {
+        let refresh = self.refresh.load(Ordering::SeqCst);
+        let mut series = self.series.write().expect("billing registry poisoned");
+        series.retain(|key, sample| {
+            let stale = refresh.saturating_sub(sample.refresh) >= self.ttl;
+            if stale {
+                log::info!(
+                    "{{\"fn\": \"expire\", \"metric\":\"{}\", \"labels\":\"{}\"}}",
+                    key.name,
+                    key.labels
+                        .iter()
+                        .map(|(k, v)| format!("{k}={v}"))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                );
+            }
+            !stale
+        });
+    }
+
+    pub fn set_points(&self, points: &[Point]) {
+        for point in points {
+            self.set(
+                point.name,
+                point.labels.clone(),
+                point.value,
+                point.timestamp,
+            );
+        }
+    }
+
+    // Render the billing series of a family in the requested exposition format
+    pub fn render(&self, format: Format, family: Family) -> String {
+        let series = self.series.read().expect("billing registry poisoned");
+        let mut output = String::new();
+        let mut current = "";
+
+        for (key, sample) in series.iter().filter(|(key, _)| family.matches(key.name)) {
+            if key.name != current {
+                current = key.name;
+                output.push_str(&format!("# HELP {} {}\n", key.name, help(key.name)));
+                output.push_str(&format!("# TYPE {} gauge\n", key.name));
+
+                // OpenMetrics only allows a unit when the metric name ends with it. The
+                // amounts are hundredths of the currency label, which is cents for USD.
+                if format == Format::OpenMetrics && key.name.ends_with("_cents") {
+                    output.push_str(&format!("# UNIT {} cents\n", key.name));
+                }
+            }
+
+            let labels: Vec<String> = key
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            match labels.is_empty() {
+                true => output.push_str(&format!("{} {}", key.name, sample.value)),
+                false => output.push_str(&format!(
+                    "{}{{{}}} {}",
+                    key.name,
+                    labels.join(","),
+                    sample.value
+                )),
+            }
+            match (sample.timestamp, format) {
+                // OpenMetrics timestamps are in seconds
+                (Some(timestamp), Format::OpenMetrics) => {
+                    output.push_str(&format!(" {}.{:03}", timestamp / 1000, timestamp % 1000));
+                }
+                (Some(timestamp), _) => {
+                    output.push_str(&format!(" {timestamp}"));
+                }
+                (None, _) => (),
+            }
+            output.push('\n');
+        }
+
+        output
+    }
+}
+
+// Names of the cost series in the minor and major units of their currency label, exported
+// instead with --currency_agnostic_names, as cents and dollars only fit some currencies.
+// The rates are already in major units per hour, despite their name.
+const AGNOSTIC: &[(&str, &str)] = &[
+    (
+        "atlas_billing_item_cents_total",
+        "atlas_billing_item_minor_total",
+    ),
+    (
+        "atlas_billing_item_dollars_total",
+        "atlas_billing_item_major_total",
+    ),
+    (
+        "atlas_billing_item_cents_rate",
+        "atlas_billing_item_major_rate",
+    ),
+    (
+        "atlas_billing_cluster_backup_cents_total",
+        "atlas_billing_cluster_backup_minor_total",
+    ),
+    (
+        "atlas_billing_cluster_backup_dollars_total",
+        "atlas_billing_cluster_backup_major_total",
+    ),
+    ("atlas_billing_credits_cents", "atlas_billing_credits_minor"),
+    (
+        "atlas_billing_credits_dollars",
+        "atlas_billing_credits_major",
+    ),
+    (
+        "atlas_billing_item_normalized_cents_total",
+        "atlas_billing_item_normalized_minor_total",
+    ),
+    (
+        "atlas_billing_item_normalized_dollars_total",
+        "atlas_billing_item_normalized_major_total",
+    ),
+    (
+        "atlas_billing_item_normalized_cents_rate",
+        "atlas_billing_item_normalized_major_rate",
+    ),
+    (
+        "atlas_billing_cluster_backup_normalized_cents_total",
+        "atlas_billing_cluster_backup_normalized_minor_total",
+    ),
+    (
+        "atlas_billing_cluster_backup_normalized_dollars_total",
+        "atlas_billing_cluster_backup_normalized_major_total",
+    ),
+    (
+        "atlas_billing_credits_normalized_cents",
+        "atlas_billing_credits_normalized_minor",
+    ),
+    (
+        "atlas_billing_credits_normalized_dollars",
+        "atlas_billing_credits_normalized_major",
+    ),
+    (
+        "atlas_billing_usage_daily_cents",
+        "atlas_billing_usage_daily_minor",
+    ),
+    // Only found in the recording rules
+    ("atlas_billing_daily_cents", "atlas_billing_daily_minor"),
+    (
+        "atlas_billing_projected_cents",
+        "atlas_billing_projected_minor",
+    ),
+];
+
+pub fn agnostic(name: &'static str) -> &'static str {
+    AGNOSTIC
+        .iter()
+        .find(|(cents, _)| *cents == name)
+        .map(|(_, agnostic)| *agnostic)
+        .unwrap_or(name)
+}
+
+// Swap the names in generated rules or queries. No name is part of another, so the order
+// of the replacements does not matter.
+#[cfg(feature = "cli")]
+pub fn agnostic_names(text: &str) -> String {
+    AGNOSTIC
+        .iter()
+        .fold(text.to_string(), |text, (cents, agnostic)| {
+            text.replace(cents, agnostic)
+        })
+}
+
+pub fn help(name: &str) -> &'static str {
+    // The currency agnostic names share the help of the names they replace
+    let name = AGNOSTIC
+        .iter()
+        .find(|(_, agnostic)| *agnostic == name)
+        .map(|(cents, _)| *cents)
+        .unwrap_or(name);
+    match name {
+        "atlas_billing_item_cents_total" => {
+            "Atlas billing total cost per sku, in hundredths of the currency label"
+        }
+        "atlas_billing_item_dollars_total" => "Atlas billing total cost per sku, in the currency label",
+        "atlas_billing_cluster_backup_dollars_total" => {
+            "Atlas backup cost per cluster, across all backup sku's, in the currency label"
+        }
+        "atlas_billing_item_normalized_dollars_total" => {
+            "Atlas billing total cost per sku, in the normalized currency"
+        }
+        "atlas_billing_item_cents_rate" => {
+            "Atlas billing price per unit of a sku, such as one server, in the currency label per hour"
+        }
+        "atlas_billing_cluster_backup_cents_total" => {
+            "Atlas backup cost per cluster, across all backup sku's, in hundredths of the currency label"
+        }
+        "atlas_billing_credits_cents" => {
+            "Atlas credits, discounts and other adjustments taken off the bill, in hundredths of the currency label"
+        }
+        "atlas_billing_credits_dollars" => {
+            "Atlas credits, discounts and other adjustments taken off the bill, in the currency label"
+        }
+        "atlas_billing_item_normalized_cents_total" => {
+            "Atlas billing total cost per sku, in hundredths of the normalized currency"
+        }
+        "atlas_billing_item_normalized_cents_rate" => {
+            "Atlas billing price per unit of a sku, such as one server, in the normalized currency per hour"
+        }
+        "atlas_billing_cluster_backup_normalized_cents_total" => {
+            "Atlas backup cost per cluster, across all backup sku's, in hundredths of the normalized currency"
+        }
+        "atlas_billing_cluster_backup_normalized_dollars_total" => {
+            "Atlas backup cost per cluster, across all backup sku's, in the normalized currency"
+        }
+        "atlas_billing_credits_normalized_cents" => {
+            "Atlas credits, discounts and other adjustments taken off the bill, in hundredths of the normalized currency"
+        }
+        "atlas_billing_credits_normalized_dollars" => {
+            "Atlas credits, discounts and other adjustments taken off the bill, in the normalized currency"
+        }
+        "atlas_billing_exchange_rate_ratio" => {
+            "Value of one unit of a currency in the normalized currency"
+        }
+        "atlas_billing_usage_daily_cents" => "Atlas daily usage cost from the Cost Explorer API",
+        "atlas_billing_data_stale" => {
+            "Whether the last fetch from Atlas failed and stale data is served"
+        }
+        "atlas_billing_scrape_success" => {
+            "Whether the last refresh fetched billing data from Atlas successfully"
+        }
+        "atlas_billing_last_successful_fetch_timestamp_seconds" => {
+            "Unix time of the last successful fetch of billing data from Atlas"
+        }
+        "atlas_api_circuit_open" => "Whether calls to Atlas are paused after repeated failures",
+        "atlas_billing_data_age_seconds" => {
+            "Seconds since the end of the newest line item in the invoice"
+        }
+        _ => "Atlas billing metric",
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
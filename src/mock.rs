@@ -0,0 +1,429 @@
+// A stand-in for the Atlas invoice api, serving generated invoices for a few scenarios, so
+// that integration tests and demos can run the exporter with --atlas_url and no credentials
+use axum::{
+    extract::{Extension, Path, Query},
+    http::header::{HeaderMap, AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use clap::ArgMatches;
+use hyper::server::conn::Http;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Sku's billed for every cluster each day, with the unit and price per unit in dollars
+const SKUS: [(&str, &str, f64); 3] = [
+    ("ATLAS_AWS_INSTANCE_M30", "server hours", 0.54),
+    ("ATLAS_AWS_BACKUP_SNAPSHOT_STORAGE", "gigabyte days", 0.0047),
+    ("ATLAS_AWS_DATA_TRANSFER_SAME_REGION", "gigabytes", 0.01),
+];
+
+#[derive(Debug)]
+struct Mock {
+    scenario: String,
+    line_items: usize,
+    requests: AtomicU64,
+    // Usage query tokens polled so far, each answered with 102 Processing the first time
+    // by processing below
+    polled: Mutex<HashSet<String>>,
+}
+
+fn line_item(cluster: &str, group: usize, day: NaiveDate, sku: usize, quantity: f64) -> Value {
+    let (sku, unit, price) = SKUS[sku];
+    json!({
+        "clusterName": cluster,
+        "created": format!("{}T00:00:00Z", day + Duration::days(1)),
+        "startDate": format!("{day}T00:00:00Z"),
+        "endDate": format!("{}T00:00:00Z", day + Duration::days(1)),
+        "groupId": format!("{:024x}", group + 1),
+        "groupName": format!("project-{group}"),
+        "quantity": quantity,
+        "sku": sku,
+        "totalPriceCents": (quantity * price * 100.0).round() as u64,
+        "unit": unit,
+        "unitPriceDollars": price,
+    })
+}
+
+fn invoice(mock: &Mock, org: &str, id: &str, start: NaiveDate, end: NaiveDate) -> Value {
+    let days: Vec<NaiveDate> = start
+        .iter_days()
+        .take_while(|day| *day < end.min(Utc::now().date_naive()))
+        .collect();
+
+    let mut items: Vec<Value> = Vec::new();
+    match mock.scenario.as_str() {
+        "empty" => (),
+        "huge" => {
+            for i in 0..mock.line_items {
+                let day = days.get(i % days.len().max(1)).copied().unwrap_or(start);
+                let cluster = format!("cluster-{}", i / SKUS.len() % 1000);
+                items.push(line_item(&cluster, i % 20, day, i % SKUS.len(), 24.0));
+            }
+        }
+        _ => {
+            for day in &days {
+                for (group, cluster) in ["orders", "inventory", "analytics"].iter().enumerate() {
+                    items.push(line_item(cluster, group % 2, *day, 0, 24.0));
+                    items.push(line_item(
+                        cluster,
+                        group % 2,
+                        *day,
+                        1,
+                        40.0 * (group + 1) as f64,
+                    ));
+                    items.push(line_item(cluster, group % 2, *day, 2, 3.5));
+                }
+            }
+        }
+    }
+
+    let mut credits: i64 = 0;
+    if mock.scenario == "negative_credits" {
+        credits = 2500;
+        items.push(json!({
+            "created": format!("{start}T00:00:00Z"),
+            "startDate": format!("{start}T00:00:00Z"),
+            "endDate": format!("{end}T00:00:00Z"),
+            "quantity": 1,
+            "sku": "CREDIT",
+            "totalPriceCents": -credits,
+            "unit": "",
+            "unitPriceDollars": -(credits as f64) / 100.0,
+        }));
+    }
+
+    let subtotal: i64 = items
+        .iter()
+        .filter_map(|item| item["totalPriceCents"].as_i64())
+        .sum();
+    // Closed invoices bill the usage, unless it is passed on to a cloud marketplace
+    let (status, billed) = match (id, mock.scenario.as_str()) {
+        ("pending", _) => ("PENDING", 0),
+        (_, "marketplace") => ("CLOSED", 0),
+        _ => ("CLOSED", (subtotal - credits).max(0)),
+    };
+    json!({
+        "id": id,
+        "orgId": org,
+        "created": format!("{start}T00:00:00Z"),
+        "startDate": format!("{start}T00:00:00Z"),
+        "endDate": format!("{end}T00:00:00Z"),
+        "amountBilledCents": billed,
+        "amountPaidCents": billed,
+        "creditsCents": credits,
+        "subtotalCents": subtotal,
+        "statusName": status,
+        "lineItems": items,
+        "linkedInvoices": [],
+    })
+}
+
+// Ask for digest auth like Atlas does, accepting any answer, and turn away every other
+// request in the rate_limited scenario
+fn refuse(mock: &Mock, headers: &HeaderMap) -> Option<Response> {
+    if !headers.contains_key(AUTHORIZATION) {
+        return Some((
+            StatusCode::UNAUTHORIZED,
+            [(
+                WWW_AUTHENTICATE,
+                "Digest realm=\"MMS Public API\", domain=\"\", nonce=\"mock\", algorithm=MD5, qop=\"auth\", stale=false",
+            )],
+        )
+            .into_response());
+    }
+    let count = mock.requests.fetch_add(1, Ordering::SeqCst);
+    if mock.scenario == "rate_limited" && count.is_multiple_of(2) {
+        return Some((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, "1")],
+            Json(
+                json!({ "error": 429, "errorCode": "RATE_LIMITED", "reason": "Too Many Requests" }),
+            ),
+        )
+            .into_response());
+    }
+    None
+}
+
+// Closed invoices listed before the pending one
+static CLOSED_MONTHS: usize = 12;
+
+// The pending invoice covers the current month, and closed-N the month N months before it
+fn month(id: &str) -> (NaiveDate, NaiveDate) {
+    let today = Utc::now().date_naive();
+    let first = |day: NaiveDate| day.with_day(1).expect("first of the month");
+    let mut start = first(today);
+    let mut end = first(start + Duration::days(32));
+    let back: usize = match id {
+        "pending" => 0,
+        id => id
+            .strip_prefix("closed-")
+            .and_then(|back| back.parse().ok())
+            .unwrap_or(1),
+    };
+    for _ in 0..back {
+        end = start;
+        start = first(start - Duration::days(1));
+    }
+    (start, end)
+}
+
+async fn org(
+    Extension(mock): Extension<Arc<Mock>>,
+    Path(org): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    log::info!("{{\"fn\": \"mock_org\", \"org\":\"{}\"}}", org);
+    if let Some(response) = refuse(&mock, &headers) {
+        return response;
+    }
+    Json(json!({ "id": org, "name": "Mock Org", "isDeleted": false })).into_response()
+}
+
+// The pending invoice followed by the closed ones, newest first like Atlas
+async fn invoices(
+    Extension(mock): Extension<Arc<Mock>>,
+    Path(org): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    log::info!("{{\"fn\": \"mock_invoices\", \"org\":\"{}\"}}", org);
+    if let Some(response) = refuse(&mock, &headers) {
+        return response;
+    }
+    let count: usize = query
+        .get("itemsPerPage")
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(100);
+    let results: Vec<Value> = (0..=CLOSED_MONTHS)
+        .take(count)
+        .map(|back| match back {
+            0 => json!({ "id": "pending", "statusName": "PENDING" }),
+            back => json!({ "id": format!("closed-{back}"), "statusName": "CLOSED" }),
+        })
+        .collect();
+    Json(json!({
+        "results": results,
+        "totalCount": CLOSED_MONTHS + 1,
+    }))
+    .into_response()
+}
+
+async fn get_invoice(
+    Extension(mock): Extension<Arc<Mock>>,
+    Path((org, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    log::info!(
+        "{{\"fn\": \"mock_invoice\", \"org\":\"{}\", \"invoice\":\"{}\"}}",
+        org,
+        id
+    );
+    if let Some(response) = refuse(&mock, &headers) {
+        return response;
+    }
+    let (start, end) = month(&id);
+    Json(invoice(&mock, &org, &id, start, end)).into_response()
+}
+
+// Cost Explorer usage queries are answered with a token to poll for the results
+async fn create_usage(
+    Extension(mock): Extension<Arc<Mock>>,
+    Path(org): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    log::info!("{{\"fn\": \"mock_create_usage\", \"org\":\"{}\"}}", org);
+    if let Some(response) = refuse(&mock, &headers) {
+        return response;
+    }
+    Json(json!({ "token": format!("usage-{org}") })).into_response()
+}
+
+async fn get_usage(
+    Extension(mock): Extension<Arc<Mock>>,
+    Path((org, token)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    log::info!(
+        "{{\"fn\": \"mock_usage\", \"org\":\"{}\", \"token\":\"{}\"}}",
+        org,
+        token
+    );
+    if let Some(response) = refuse(&mock, &headers) {
+        return response;
+    }
+    let yesterday = Utc::now().date_naive() - Duration::days(1);
+    let details: Vec<Value> = ["orders", "inventory", "analytics"]
+        .iter()
+        .enumerate()
+        .map(|(group, cluster)| {
+            json!({
+                "clusterName": cluster,
+                "groupName": format!("project-{}", group % 2),
+                "service": "Clusters",
+                "usageAmount": 12.96,
+                "usageDate": yesterday.to_string(),
+            })
+        })
+        .collect();
+    Json(json!({ "usageDetails": details })).into_response()
+}
+
+// hyper does not send informational responses, so the first authenticated poll of each
+// usage query is answered with 102 Processing before the connection reaches the app. The
+// connection is then held open, as the final response would only come once the query is
+// ready.
+async fn processing(mock: &Mock, stream: &TcpStream) -> bool {
+    let mut buf = vec![0; 8192];
+    let head = loop {
+        let read = match stream.peek(&mut buf).await {
+            Ok(0) | Err(_) => return false,
+            Ok(read) => read,
+        };
+        let head = String::from_utf8_lossy(&buf[..read]);
+        if head.contains("\r\n\r\n") || read == buf.len() {
+            break head.to_lowercase();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    let token = head
+        .split_whitespace()
+        .nth(1)
+        .filter(|_| head.starts_with("get ") && head.contains("\r\nauthorization:"))
+        .and_then(|path| path.split_once("/billing/costexplorer/usage/"))
+        .map(|(_, token)| token.to_string());
+    match token {
+        Some(token) => mock.polled.lock().expect("polled poisoned").insert(token),
+        None => false,
+    }
+}
+
+// Serve each request on its own connection, so that processing sees every one of them
+async fn run(listener: TcpListener, mock: Arc<Mock>) {
+    let app = app(mock.clone());
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let mock = mock.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            if processing(&mock, &stream).await {
+                log::info!("{{\"fn\": \"mock_usage\", \"status\":102}}");
+                let _ = stream.write_all(b"HTTP/1.1 102 Processing\r\n\r\n").await;
+                let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+                return;
+            }
+            let _ = Http::new()
+                .http1_keep_alive(false)
+                .serve_connection(stream, app)
+                .await;
+        });
+    }
+}
+
+fn app(mock: Arc<Mock>) -> Router {
+    Router::new()
+        .route("/api/atlas/v1.0/orgs/:org", get(org))
+        .route("/api/atlas/v1.0/orgs/:org/invoices", get(invoices))
+        .route("/api/atlas/v1.0/orgs/:org/invoices/:id", get(get_invoice))
+        .route(
+            "/api/atlas/v2/orgs/:org/billing/costExplorer/usage",
+            post(create_usage),
+        )
+        .route(
+            "/api/atlas/v2/orgs/:org/billing/costExplorer/usage/:token",
+            get(get_usage),
+        )
+        .layer(Extension(mock))
+}
+
+pub async fn serve(opts: &ArgMatches<'_>) -> BoxResult<()> {
+    let port: u16 = opts.value_of("port").unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("Supplied port not in range, defaulting to 8081");
+        8081
+    });
+    let line_items: usize = opts
+        .value_of("line_items")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("Supplied line_items not in range, defaulting to 50000");
+            50000
+        });
+    let mock = Arc::new(Mock {
+        scenario: opts.value_of("scenario").unwrap().to_string(),
+        line_items,
+        requests: AtomicU64::new(0),
+        polled: Mutex::new(HashSet::new()),
+    });
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    log::info!(
+        "{{\"fn\": \"mock_server\", \"addr\":\"{}\", \"scenario\":\"{}\"}}",
+        addr,
+        mock.scenario
+    );
+    let listener = TcpListener::bind(addr).await?;
+    tokio::select! {
+        _ = run(listener, mock) => (),
+        _ = crate::cli::shutdown_signal() => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, State};
+
+    #[tokio::test]
+    async fn polls_usage_past_processing() {
+        let mock = Arc::new(Mock {
+            scenario: "default".to_string(),
+            line_items: 0,
+            requests: AtomicU64::new(0),
+            polled: Mutex::new(HashSet::new()),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener");
+        let addr = listener.local_addr().expect("listener address");
+        tokio::spawn(run(listener, mock.clone()));
+
+        let orgs = ["5f0000000000000000000001", "5f0000000000000000000002"];
+        let config = Config {
+            atlas_url: format!("http://{addr}"),
+            timeout: 1,
+            ..Config::new("public", "private", &orgs)
+        };
+        let state = State::new(config).await.expect("state against the mock");
+        state.update_usage(7).await.expect("usage after processing");
+
+        // The first poll of each org was answered with 102 Processing
+        assert_eq!(mock.polled.lock().unwrap().len(), 2);
+        let points = state.usage_points();
+        assert_eq!(points.len(), 6);
+        for org in orgs {
+            let count = points
+                .iter()
+                .filter(|point| point.labels.contains(&("org_id", org.to_string())))
+                .count();
+            assert_eq!(count, 3);
+        }
+    }
+}
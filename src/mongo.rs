@@ -0,0 +1,70 @@
+// Persist each refresh's aggregated snapshot to a MongoDB collection, for cost history
+// beyond Prometheus retention
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson, Bson, DateTime, Document};
+use mongodb::{Client, Collection};
+use std::error::Error;
+
+use crate::sink::{Emit, Sink, Snapshot};
+use crate::state::Billing;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, Clone)]
+pub struct Mongo {
+    collection: Collection<Document>,
+    org: String,
+}
+
+impl Mongo {
+    pub async fn new(uri: &str, database: &str, collection: &str, org: &str) -> BoxResult<Self> {
+        let client = Client::with_uri_str(uri).await?;
+        Ok(Mongo {
+            collection: client.database(database).collection(collection),
+            org: org.to_string(),
+        })
+    }
+
+    pub async fn write_snapshot(&self, billing: &Billing) -> BoxResult<()> {
+        let mut keys: Vec<&String> = billing.totals.keys().collect();
+        keys.sort();
+
+        let totals = keys
+            .into_iter()
+            .map(|key| to_bson(&billing.totals[key]))
+            .collect::<Result<Vec<Bson>, _>>()?;
+        let total_price_cents: i64 = billing.totals.values().map(|v| v.total_price_cents).sum();
+
+        let snapshot = doc! {
+            "snapshot_time": DateTime::from_millis(Utc::now().timestamp_millis()),
+            "org_id": &self.org,
+            "billing_channel": billing.billing_channel,
+            "period_start": &billing.period_start,
+            "period_end": &billing.period_end,
+            "total_price_cents": total_price_cents,
+            "totals": totals,
+        };
+        self.collection.insert_one(snapshot, None).await?;
+
+        log::info!(
+            "{{\"fn\": \"mongo_write_snapshot\", \"collection\":\"{}\"}}",
+            self.collection.name()
+        );
+        Ok(())
+    }
+}
+
+impl Sink for Mongo {
+    fn name(&self) -> &'static str {
+        "MongoDB"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move {
+            if snapshot.is_new() {
+                self.write_snapshot(snapshot.billing).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,163 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::metrics::{help, Point};
+use crate::sink::{Emit, Sink, Snapshot};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Headers are given as comma separated key=value pairs, as with OTEL_EXPORTER_OTLP_HEADERS
+fn parse_headers(headers: Option<&str>) -> Vec<(String, String)> {
+    headers
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+// Export spans in batches to an OTLP/HTTP collector, so that Atlas api calls and scrapes
+// show up as traces
+pub fn tracer_provider(endpoint: &str, headers: Option<&str>) -> BoxResult<TracerProvider> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let url = match endpoint.ends_with("/v1/traces") {
+        true => endpoint.to_string(),
+        false => format!("{endpoint}/v1/traces"),
+    };
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(url)
+        .with_headers(parse_headers(headers).into_iter().collect())
+        .build()?;
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![
+            KeyValue::new("service.name", clap::crate_name!()),
+            KeyValue::new("service.version", clap::crate_version!()),
+        ]))
+        .build())
+}
+
+// Returns a tracer for the tracing layer
+pub fn tracer(provider: &TracerProvider) -> opentelemetry_sdk::trace::Tracer {
+    provider.tracer(clap::crate_name!())
+}
+
+// Pushes the billing gauges to an OTLP/HTTP collector, using the json encoding
+#[derive(Debug, Clone)]
+pub struct Otlp {
+    url: String,
+    headers: Vec<(String, String)>,
+    client: reqwest::Client,
+}
+
+impl Otlp {
+    pub fn new(endpoint: &str, headers: Option<&str>) -> Self {
+        let endpoint = endpoint.trim_end_matches('/');
+        let url = match endpoint.ends_with("/v1/metrics") {
+            true => endpoint.to_string(),
+            false => format!("{endpoint}/v1/metrics"),
+        };
+
+        Otlp {
+            url,
+            headers: parse_headers(headers),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn push(&self, points: &[Point]) -> BoxResult<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(encode(points).to_string());
+        for (k, v) in &self.headers {
+            request = request.header(k, v);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            log::error!("OTLP push failed with {}: {}", status, text);
+            return Err(format!("OTLP collector returned {status}").into());
+        }
+
+        log::info!(
+            "{{\"fn\": \"otlp_push\", \"url\":\"{}\", \"points\":{}}}",
+            self.url,
+            points.len()
+        );
+        Ok(())
+    }
+}
+
+impl Sink for Otlp {
+    fn name(&self) -> &'static str {
+        "OTLP collector"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move { self.push(snapshot.points).await })
+    }
+}
+
+fn attributes(point: &Point) -> Vec<Value> {
+    point
+        .labels
+        .iter()
+        .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+        .collect()
+}
+
+// Points come sorted by name, so each run of equal names becomes one gauge
+fn encode(points: &[Point]) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+
+    let mut metrics: Vec<Value> = Vec::new();
+    for chunk in points.chunk_by(|a, b| a.name == b.name) {
+        let data_points: Vec<Value> = chunk
+            .iter()
+            .map(|point| {
+                let time = point
+                    .timestamp
+                    .map(|ms| ms as u64 * 1_000_000)
+                    .unwrap_or(now);
+                json!({
+                    "attributes": attributes(point),
+                    "timeUnixNano": time.to_string(),
+                    "asDouble": point.value,
+                })
+            })
+            .collect();
+
+        metrics.push(json!({
+            "name": chunk[0].name,
+            "description": help(chunk[0].name),
+            "gauge": {"dataPoints": data_points},
+        }));
+    }
+
+    json!({"resourceMetrics": [{
+        "resource": {"attributes": [
+            {"key": "service.name", "value": {"stringValue": clap::crate_name!()}},
+            {"key": "service.version", "value": {"stringValue": clap::crate_version!()}},
+        ]},
+        "scopeMetrics": [{
+            "scope": {"name": clap::crate_name!(), "version": clap::crate_version!()},
+            "metrics": metrics,
+        }],
+    }]})
+}
@@ -0,0 +1,223 @@
+// Write each refresh's aggregated snapshot and the raw line items to Parquet files, in a
+// date partitioned directory layout that data lake ingestion can pick up
+use chrono::Utc;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::error::Error;
+use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::sink::{Emit, Sink, Snapshot};
+use crate::state::Billing;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+static SNAPSHOT_SCHEMA: &str = "
+message snapshot {
+    REQUIRED BYTE_ARRAY org_id (UTF8);
+    REQUIRED BYTE_ARRAY group_id (UTF8);
+    REQUIRED BYTE_ARRAY group_name (UTF8);
+    REQUIRED BYTE_ARRAY cluster_name (UTF8);
+    REQUIRED BYTE_ARRAY sku (UTF8);
+    REQUIRED BYTE_ARRAY unit (UTF8);
+    REQUIRED DOUBLE quantity;
+    REQUIRED INT64 total_price_cents;
+    REQUIRED DOUBLE unit_price_dollars;
+    REQUIRED BYTE_ARRAY start_date (UTF8);
+    REQUIRED BYTE_ARRAY end_date (UTF8);
+    REQUIRED INT64 snapshot_time (TIMESTAMP(MILLIS,true));
+}
+";
+
+static LINE_ITEM_SCHEMA: &str = "
+message line_item {
+    REQUIRED BYTE_ARRAY org_id (UTF8);
+    REQUIRED BYTE_ARRAY group_id (UTF8);
+    REQUIRED BYTE_ARRAY group_name (UTF8);
+    REQUIRED BYTE_ARRAY cluster_name (UTF8);
+    REQUIRED BYTE_ARRAY sku (UTF8);
+    REQUIRED BYTE_ARRAY unit (UTF8);
+    REQUIRED DOUBLE quantity;
+    REQUIRED INT64 total_price_cents;
+    REQUIRED DOUBLE unit_price_dollars;
+    REQUIRED BYTE_ARRAY start_date (UTF8);
+    REQUIRED BYTE_ARRAY end_date (UTF8);
+    REQUIRED BYTE_ARRAY created (UTF8);
+}
+";
+
+enum Column {
+    Text(Vec<ByteArray>),
+    Double(Vec<f64>),
+    Int(Vec<i64>),
+}
+
+fn text<'a, S: AsRef<str> + 'a>(values: impl Iterator<Item = &'a Option<S>>) -> Column {
+    Column::Text(
+        values
+            .map(|v| ByteArray::from(v.as_ref().map(S::as_ref).unwrap_or_default()))
+            .collect(),
+    )
+}
+
+fn write_file(path: &Path, schema: &str, columns: Vec<Column>) -> BoxResult<()> {
+    let schema = Arc::new(parse_message_type(schema)?);
+    let props = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, props)?;
+
+    let mut row_group = writer.next_row_group()?;
+    let mut columns = columns.into_iter();
+    while let Some(mut column) = row_group.next_column()? {
+        match columns.next() {
+            Some(Column::Text(values)) => {
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            Some(Column::Double(values)) => {
+                column
+                    .typed::<DoubleType>()
+                    .write_batch(&values, None, None)?;
+            }
+            Some(Column::Int(values)) => {
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&values, None, None)?;
+            }
+            None => return Err("schema has more columns than values".into()),
+        }
+        column.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+
+    Ok(())
+}
+
+fn write(dir: &str, billing: &Billing) -> BoxResult<Vec<PathBuf>> {
+    // Nanoseconds keep refreshes within the same second from overwriting each other's files
+    let now = Utc::now();
+    let stamp = now.format("%H%M%S%9f");
+    let partition = Path::new(dir).join(format!("date={}", now.format("%Y-%m-%d")));
+    create_dir_all(&partition)?;
+
+    let totals: Vec<_> = billing.totals.values().collect();
+    let snapshot = partition.join(format!("snapshot-{stamp}.parquet"));
+    write_file(
+        &snapshot,
+        SNAPSHOT_SCHEMA,
+        vec![
+            text(totals.iter().map(|v| &v.org_id)),
+            text(totals.iter().map(|v| &v.group_id)),
+            text(totals.iter().map(|v| &v.group_name)),
+            text(totals.iter().map(|v| &v.cluster_name)),
+            Column::Text(totals.iter().map(|v| ByteArray::from(&*v.sku)).collect()),
+            Column::Text(totals.iter().map(|v| ByteArray::from(&*v.unit)).collect()),
+            Column::Double(totals.iter().map(|v| v.quantity).collect()),
+            Column::Int(totals.iter().map(|v| v.total_price_cents).collect()),
+            Column::Double(totals.iter().map(|v| v.unit_price_dollars).collect()),
+            Column::Text(
+                totals
+                    .iter()
+                    .map(|v| ByteArray::from(v.start_date.as_str()))
+                    .collect(),
+            ),
+            Column::Text(
+                totals
+                    .iter()
+                    .map(|v| ByteArray::from(v.end_date.as_str()))
+                    .collect(),
+            ),
+            Column::Int(vec![now.timestamp_millis(); totals.len()]),
+        ],
+    )?;
+
+    let items = &billing.line_items;
+    let line_items = partition.join(format!("line_items-{stamp}.parquet"));
+    write_file(
+        &line_items,
+        LINE_ITEM_SCHEMA,
+        vec![
+            text(items.iter().map(|v| &v.org_id)),
+            text(items.iter().map(|v| &v.group_id)),
+            text(items.iter().map(|v| &v.group_name)),
+            text(items.iter().map(|v| &v.cluster_name)),
+            Column::Text(
+                items
+                    .iter()
+                    .map(|v| ByteArray::from(v.sku.as_str()))
+                    .collect(),
+            ),
+            Column::Text(
+                items
+                    .iter()
+                    .map(|v| ByteArray::from(v.unit.as_str()))
+                    .collect(),
+            ),
+            Column::Double(items.iter().map(|v| v.quantity).collect()),
+            Column::Int(items.iter().map(|v| v.total_price_cents).collect()),
+            Column::Double(items.iter().map(|v| v.unit_price_dollars).collect()),
+            Column::Text(
+                items
+                    .iter()
+                    .map(|v| ByteArray::from(v.start_date.as_str()))
+                    .collect(),
+            ),
+            Column::Text(
+                items
+                    .iter()
+                    .map(|v| ByteArray::from(v.end_date.as_str()))
+                    .collect(),
+            ),
+            Column::Text(
+                items
+                    .iter()
+                    .map(|v| ByteArray::from(v.created.as_str()))
+                    .collect(),
+            ),
+        ],
+    )?;
+
+    Ok(vec![snapshot, line_items])
+}
+
+// Writes the files of each new aggregation below a directory
+#[derive(Debug)]
+pub struct Parquet {
+    dir: String,
+}
+
+impl Parquet {
+    pub fn new(dir: &str) -> Self {
+        Parquet {
+            dir: dir.to_string(),
+        }
+    }
+
+    pub async fn write_snapshot(&self, billing: &Billing) -> BoxResult<()> {
+        let dir = self.dir.clone();
+        let billing = billing.clone();
+
+        let paths = tokio::task::spawn_blocking(move || write(&dir, &billing)).await??;
+        log::debug!("Wrote parquet snapshot to {:?}", paths);
+        Ok(())
+    }
+}
+
+impl Sink for Parquet {
+    fn name(&self) -> &'static str {
+        "Parquet"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move {
+            if snapshot.is_new() {
+                self.write_snapshot(snapshot.billing).await?;
+            }
+            Ok(())
+        })
+    }
+}
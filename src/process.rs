@@ -0,0 +1,108 @@
+// Standard process metrics, read from /proc on each scrape, so that the exporter can be
+// monitored without a node exporter alongside it
+use std::fs;
+
+// Clock ticks per second and page size are fixed on the platforms we build for
+const TICKS: f64 = 100.0;
+const PAGE_SIZE: f64 = 4096.0;
+
+struct Stat {
+    cpu_seconds: f64,
+    start_time: f64,
+    virtual_memory: f64,
+    resident_memory: f64,
+}
+
+fn stat() -> Option<Stat> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces, so fields are counted from its closing paren
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<f64>().ok();
+
+    let boot_time = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+
+    Some(Stat {
+        cpu_seconds: (field(14)? + field(15)?) / TICKS,
+        start_time: boot_time + field(22)? / TICKS,
+        virtual_memory: field(23)?,
+        resident_memory: field(24)? * PAGE_SIZE,
+    })
+}
+
+fn open_fds() -> Option<f64> {
+    Some(fs::read_dir("/proc/self/fd").ok()?.count() as f64)
+}
+
+fn max_fds() -> Option<f64> {
+    fs::read_to_string("/proc/self/limits")
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()
+}
+
+fn metric(output: &mut String, name: &str, kind: &str, help: &str, value: Option<f64>) {
+    if let Some(value) = value {
+        output.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ));
+    }
+}
+
+// Renders nothing where /proc is not available
+pub fn render() -> String {
+    let mut output = String::new();
+    let stat = stat();
+    metric(
+        &mut output,
+        "process_cpu_seconds_total",
+        "counter",
+        "Total user and system CPU time spent in seconds.",
+        stat.as_ref().map(|s| s.cpu_seconds),
+    );
+    metric(
+        &mut output,
+        "process_open_fds",
+        "gauge",
+        "Number of open file descriptors.",
+        open_fds(),
+    );
+    metric(
+        &mut output,
+        "process_max_fds",
+        "gauge",
+        "Maximum number of open file descriptors.",
+        max_fds(),
+    );
+    metric(
+        &mut output,
+        "process_virtual_memory_bytes",
+        "gauge",
+        "Virtual memory size in bytes.",
+        stat.as_ref().map(|s| s.virtual_memory),
+    );
+    metric(
+        &mut output,
+        "process_resident_memory_bytes",
+        "gauge",
+        "Resident memory size in bytes.",
+        stat.as_ref().map(|s| s.resident_memory),
+    );
+    metric(
+        &mut output,
+        "process_start_time_seconds",
+        "gauge",
+        "Start time of the process since unix epoch in seconds.",
+        stat.as_ref().map(|s| s.start_time),
+    );
+    output
+}
@@ -0,0 +1,37 @@
+// Minimal protobuf wire format encoding, enough for the handful of Prometheus messages we send
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+
+pub fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type);
+}
+
+pub fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(buf, field, WIRE_LEN);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn put_string(buf: &mut Vec<u8>, field: u64, value: &str) {
+    put_bytes(buf, field, value.as_bytes());
+}
+
+pub fn put_double(buf: &mut Vec<u8>, field: u64, value: f64) {
+    put_key(buf, field, WIRE_FIXED64);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_int64(buf: &mut Vec<u8>, field: u64, value: i64) {
+    put_key(buf, field, WIRE_VARINT);
+    put_varint(buf, value as u64);
+}
@@ -0,0 +1,240 @@
+// Prometheus protobuf exposition, built by parsing the text exposition into metric families,
+// so that both the recorder and the billing registry are covered by a single code path.
+use crate::proto::{put_bytes, put_double, put_int64, put_string, put_varint};
+
+pub static CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter = 0,
+    Gauge = 1,
+    Summary = 2,
+    Untyped = 3,
+    Histogram = 4,
+}
+
+#[derive(Debug, Default)]
+struct Metric {
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp: Option<i64>,
+    count: u64,
+    sum: f64,
+    buckets: Vec<(f64, u64)>,
+    quantiles: Vec<(f64, f64)>,
+}
+
+#[derive(Debug)]
+struct Family {
+    name: String,
+    help: String,
+    kind: Kind,
+    metrics: Vec<Metric>,
+}
+
+impl Family {
+    fn metric(&mut self, labels: Vec<(String, String)>) -> &mut Metric {
+        match self.metrics.iter().position(|m| m.labels == labels) {
+            Some(i) => &mut self.metrics[i],
+            None => {
+                self.metrics.push(Metric {
+                    labels,
+                    ..Default::default()
+                });
+                self.metrics.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_string(&mut buf, 1, &self.name);
+        if !self.help.is_empty() {
+            put_string(&mut buf, 2, &self.help);
+        }
+        put_int64(&mut buf, 3, self.kind as i64);
+
+        for metric in &self.metrics {
+            let mut m = Vec::new();
+            for (name, value) in &metric.labels {
+                let mut label = Vec::new();
+                put_string(&mut label, 1, name);
+                put_string(&mut label, 2, value);
+                put_bytes(&mut m, 1, &label);
+            }
+
+            let mut inner = Vec::new();
+            match self.kind {
+                Kind::Gauge | Kind::Counter | Kind::Untyped => {
+                    put_double(&mut inner, 1, metric.value);
+                }
+                Kind::Summary => {
+                    put_int64(&mut inner, 1, metric.count as i64);
+                    put_double(&mut inner, 2, metric.sum);
+                    for (quantile, value) in &metric.quantiles {
+                        let mut q = Vec::new();
+                        put_double(&mut q, 1, *quantile);
+                        put_double(&mut q, 2, *value);
+                        put_bytes(&mut inner, 3, &q);
+                    }
+                }
+                Kind::Histogram => {
+                    put_int64(&mut inner, 1, metric.count as i64);
+                    put_double(&mut inner, 2, metric.sum);
+                    for (bound, count) in &metric.buckets {
+                        let mut b = Vec::new();
+                        put_int64(&mut b, 1, *count as i64);
+                        put_double(&mut b, 2, *bound);
+                        put_bytes(&mut inner, 3, &b);
+                    }
+                }
+            }
+            let field = match self.kind {
+                Kind::Gauge => 2,
+                Kind::Counter => 3,
+                Kind::Summary => 4,
+                Kind::Untyped => 5,
+                Kind::Histogram => 7,
+            };
+            put_bytes(&mut m, field, &inner);
+
+            if let Some(timestamp) = metric.timestamp {
+                put_int64(&mut m, 6, timestamp);
+            }
+            put_bytes(&mut buf, 4, &m);
+        }
+
+        buf
+    }
+}
+
+// A sample line split into its name, labels, value and optional timestamp
+type Sample<'a> = (&'a str, Vec<(String, String)>, f64, Option<i64>);
+
+fn parse_sample(line: &str) -> Option<Sample<'_>> {
+    let (name, rest) = match line.find(['{', ' ']) {
+        Some(i) => line.split_at(i),
+        None => return None,
+    };
+
+    let mut labels = Vec::new();
+    let rest = match rest.strip_prefix('{') {
+        Some(mut rest) => loop {
+            rest = rest.trim_start_matches(',');
+            if let Some(r) = rest.strip_prefix('}') {
+                break r;
+            }
+            let (key, r) = rest.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = r.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (i, '"') => break i,
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((key.to_string(), value));
+            rest = &r[end + 1..];
+        },
+        None => rest,
+    };
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next()?.parse().ok()?;
+    let timestamp = fields.next().and_then(|t| t.parse().ok());
+    Some((name, labels, value, timestamp))
+}
+
+fn take_label(labels: &mut Vec<(String, String)>, name: &str) -> Option<f64> {
+    let i = labels.iter().position(|(k, _)| k == name)?;
+    labels.remove(i).1.parse().ok()
+}
+
+pub fn encode(text: &str) -> Vec<u8> {
+    let mut families: Vec<Family> = Vec::new();
+    let mut helps: Vec<(String, String)> = Vec::new();
+
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            if let Some((name, help)) = rest.split_once(' ') {
+                helps.push((name.to_string(), help.to_string()));
+            }
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            if let Some((name, kind)) = rest.split_once(' ') {
+                let kind = match kind {
+                    "counter" => Kind::Counter,
+                    "gauge" => Kind::Gauge,
+                    "summary" => Kind::Summary,
+                    "histogram" => Kind::Histogram,
+                    _ => Kind::Untyped,
+                };
+                let help = helps
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, h)| h.clone())
+                    .unwrap_or_default();
+                families.push(Family {
+                    name: name.to_string(),
+                    help,
+                    kind,
+                    metrics: Vec::new(),
+                });
+            }
+        } else if !line.starts_with('#') && !line.is_empty() {
+            let (name, mut labels, value, timestamp) = match parse_sample(line) {
+                Some(sample) => sample,
+                None => {
+                    log::debug!("Skipping unparsable sample {}", line);
+                    continue;
+                }
+            };
+
+            let family = match families.last_mut() {
+                Some(f) if name.starts_with(&f.name) => f,
+                _ => {
+                    families.push(Family {
+                        name: name.to_string(),
+                        help: String::new(),
+                        kind: Kind::Untyped,
+                        metrics: Vec::new(),
+                    });
+                    families.last_mut().unwrap()
+                }
+            };
+
+            let suffix = &name[family.name.len()..];
+            match (family.kind, suffix) {
+                (Kind::Histogram, "_bucket") => {
+                    let bound = take_label(&mut labels, "le").unwrap_or(f64::INFINITY);
+                    family.metric(labels).buckets.push((bound, value as u64));
+                }
+                (Kind::Summary, "") => {
+                    let quantile = take_label(&mut labels, "quantile").unwrap_or_default();
+                    family.metric(labels).quantiles.push((quantile, value));
+                }
+                (Kind::Histogram | Kind::Summary, "_sum") => family.metric(labels).sum = value,
+                (Kind::Histogram | Kind::Summary, "_count") => {
+                    family.metric(labels).count = value as u64
+                }
+                _ => {
+                    let metric = family.metric(labels);
+                    metric.value = value;
+                    metric.timestamp = timestamp;
+                }
+            }
+        }
+    }
+
+    let mut buf = Vec::new();
+    for family in families.iter().filter(|f| !f.metrics.is_empty()) {
+        let encoded = family.encode();
+        put_varint(&mut buf, encoded.len() as u64);
+        buf.extend_from_slice(&encoded);
+    }
+    buf
+}
@@ -0,0 +1,129 @@
+// Per client rate limiting of the billing data routes, with a token bucket per remote
+// address, so that one aggressive client cannot use up the Atlas api quota behind them
+use axum::{
+    extract::ConnectInfo,
+    http::{header::RETRY_AFTER, HeaderValue, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use clap::ArgMatches;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::error::Error as RestError;
+use crate::listener::Peer;
+
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    pub fn new(opts: &ArgMatches) -> Option<Arc<RateLimit>> {
+        let rate: f64 = opts
+            .value_of("rate_limit")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|_| {
+                eprintln!("Supplied rate_limit not in range, disabling rate limiting");
+                0.0
+            });
+        let burst: u32 = opts
+            .value_of("rate_limit_burst")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|_| {
+                eprintln!("Supplied rate_limit_burst not in range, defaulting to 10");
+                10
+            });
+        match rate > 0.0 {
+            true => Some(Arc::new(RateLimit {
+                rate,
+                burst: f64::from(burst.max(1)),
+                buckets: Mutex::new(HashMap::new()),
+            })),
+            false => None,
+        }
+    }
+
+    // Take a token for the client, or return the seconds until one is available
+    fn check(&self, ip: IpAddr) -> Result<(), f64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limit poisoned");
+
+        // Forget clients whose buckets have filled up again
+        if buckets.len() > 10000 {
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * self.rate < self.burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * self.rate)
+            .min(self.burst);
+        bucket.updated = now;
+
+        match bucket.tokens >= 1.0 {
+            true => {
+                bucket.tokens -= 1.0;
+                Ok(())
+            }
+            false => Err((1.0 - bucket.tokens) / self.rate),
+        }
+    }
+}
+
+async fn rate_limit<B>(req: Request<B>, next: Next<B>, limit: Arc<RateLimit>) -> Response {
+    let ip = match req.extensions().get::<ConnectInfo<Peer>>() {
+        Some(ConnectInfo(peer)) => peer.addr.ip(),
+        None => return next.run(req).await,
+    };
+    match limit.check(ip) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            log::warn!(
+                "{{\"fn\": \"rate_limit\", \"path\":\"{}\", \"remote\":\"{}\"}}",
+                req.uri().path(),
+                ip
+            );
+            metrics::increment_counter!("atlas_billing_http_requests_rate_limited_total");
+            let mut response = RestError::Http(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests".to_string(),
+            )
+            .into_response();
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(wait.ceil().max(1.0) as u64));
+            response
+        }
+    }
+}
+
+// Rate limit the routes of a router, when a rate is configured
+pub fn limit(router: Router, limit: &Option<Arc<RateLimit>>) -> Router {
+    match limit {
+        Some(limit) => {
+            let limit = limit.clone();
+            router.route_layer(middleware::from_fn(move |req, next| {
+                rate_limit(req, next, limit.clone())
+            }))
+        }
+        None => router,
+    }
+}
@@ -0,0 +1,214 @@
+// Record the responses from Atlas to a directory, and serve them back in place of Atlas, so
+// that the exact payloads behind a bug can be captured and replayed without credentials
+use chrono::{DateTime, Utc};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{Body, Method, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::error::Error as RestError;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Recording {
+    time: DateTime<Utc>,
+    method: String,
+    uri: String,
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+fn key(method: &str, uri: &str) -> String {
+    format!("{method} {uri}")
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    count: AtomicU64,
+}
+
+impl Recorder {
+    pub fn new(config: &Config) -> BoxResult<Option<Arc<Recorder>>> {
+        let dir = match &config.record_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => return Ok(None),
+        };
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("Could not create record_dir {}: {e}", dir.display()))?;
+        Ok(Some(Arc::new(Recorder {
+            dir,
+            count: AtomicU64::new(0),
+        })))
+    }
+
+    // Failing to record is logged, rather than failing the request that was recorded
+    pub async fn save(
+        &self,
+        method: &Method,
+        uri: &str,
+        status: u16,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) {
+        let time = Utc::now();
+        let recording = Recording {
+            time,
+            method: method.to_string(),
+            uri: uri.to_string(),
+            status,
+            // The body is saved whole, so its framing headers no longer apply
+            headers: headers
+                .iter()
+                .filter(|(name, _)| *name != TRANSFER_ENCODING && *name != CONTENT_LENGTH)
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: String::from_utf8_lossy(body).into_owned(),
+        };
+
+        // Names sort in the order the responses were received, across restarts too
+        let count = self.count.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!(
+            "{}-{count:06}.json",
+            time.format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        let result = match serde_json::to_vec_pretty(&recording) {
+            Ok(bytes) => tokio::fs::write(&path, bytes)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(()) => log::debug!("Recorded {} {} to {}", method, uri, path.display()),
+            Err(e) => log::error!("Failed to record response to {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Replay {
+    recordings: HashMap<String, Vec<Recording>>,
+    // Next recording to serve for each request, the last one repeats once all were served
+    cursors: Mutex<HashMap<String, usize>>,
+    started: DateTime<Utc>,
+}
+
+impl Replay {
+    pub fn new(config: &Config) -> BoxResult<Option<Arc<Replay>>> {
+        let dir = match &config.replay_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => return Ok(None),
+        };
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .map_err(|e| format!("Could not read replay_dir {}: {e}", dir.display()))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+
+        let mut recordings: HashMap<String, Vec<Recording>> = HashMap::new();
+        let mut started = None;
+        for path in paths {
+            let recording: Recording = serde_json::from_slice(&std::fs::read(&path)?)
+                .map_err(|e| format!("Could not parse recording {}: {e}", path.display()))?;
+            started.get_or_insert(recording.time);
+            recordings
+                .entry(key(&recording.method, &recording.uri))
+                .or_default()
+                .push(recording);
+        }
+        let started = started.ok_or(format!("No recordings found in {}", dir.display()))?;
+        log::info!(
+            "{{\"fn\": \"replay\", \"dir\":\"{}\", \"requests\":{}}}",
+            dir.display(),
+            recordings.len()
+        );
+
+        Ok(Some(Arc::new(Replay {
+            recordings,
+            cursors: Mutex::new(HashMap::new()),
+            started,
+        })))
+    }
+
+    // When the recording started, as the invoices asked for and the usage that is current
+    // depend on it
+    pub fn started(&self) -> DateTime<Utc> {
+        self.started
+    }
+
+    pub fn response(&self, method: &Method, uri: &str) -> Result<Response<Body>, RestError> {
+        let key = key(method.as_str(), uri);
+        let recordings = self.recordings.get(&key).ok_or_else(|| {
+            RestError::Http(
+                StatusCode::BAD_GATEWAY,
+                format!("No recording of {key} in replay_dir"),
+            )
+        })?;
+
+        let mut cursors = self.cursors.lock().expect("replay cursors poisoned");
+        let cursor = cursors.entry(key).or_insert(0);
+        let recording = &recordings[(*cursor).min(recordings.len() - 1)];
+        *cursor += 1;
+
+        let mut response = Response::new(Body::from(recording.body.clone()));
+        *response.status_mut() =
+            StatusCode::from_u16(recording.status).unwrap_or(StatusCode::BAD_GATEWAY);
+        for (name, value) in &recording.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, State};
+
+    // Recorded on the 3rd of the month, from an org billed through a cloud marketplace
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+    const ORG: &str = "5f0000000000000000000001";
+
+    #[tokio::test]
+    async fn replays_recorded_invoices() {
+        let config = Config {
+            replay_dir: Some(FIXTURES.to_string()),
+            ..Config::new("", "", &[ORG])
+        };
+        let state = State::new(config)
+            .await
+            .expect("state replaying the fixtures");
+        let billing = state.get_billing().await.expect("replayed billing");
+
+        let key = format!("{ORG}/000000000000000000000001/orders/ATLAS_AWS_INSTANCE_M30");
+        assert_eq!(billing.totals.len(), 2);
+        assert_eq!(billing.totals[&key].total_price_cents, 3240);
+        // Both regions of the most recent day
+        assert_eq!(billing.rates[&key].unit_price_dollars, 0.81);
+        assert_eq!(billing.rates[&key].end_date, "2024-05-03T00:00:00Z");
+
+        assert_eq!(billing.credits.len(), 1);
+        assert_eq!(
+            billing.credits[&format!("{ORG}///CREDIT")].total_price_cents,
+            -500
+        );
+
+        // Taken from the closed invoice, as nothing is billed on the pending one yet
+        assert_eq!(billing.channel(Some(ORG)), "marketplace");
+        assert_eq!(billing.period_start, "2024-05-01T00:00:00Z");
+    }
+}
@@ -0,0 +1,125 @@
+use chrono::Utc;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use std::error::Error;
+
+use crate::proto::{put_bytes, put_double, put_int64, put_string};
+use crate::sink::{Emit, Sink, Snapshot};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Maximum number of series to send in a single remote write request
+const BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Default)]
+pub struct TimeSeries {
+    pub labels: Vec<(String, String)>,
+    pub samples: Vec<(f64, i64)>,
+}
+
+impl TimeSeries {
+    // Remote write requires labels sorted by name, with the metric name in __name__
+    pub fn new(name: &str, labels: &[(&str, String)]) -> TimeSeries {
+        let mut labels: Vec<(String, String)> = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        labels.push(("__name__".to_string(), name.to_string()));
+        labels.sort();
+
+        TimeSeries {
+            labels,
+            samples: Vec::new(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (name, value) in &self.labels {
+            let mut label = Vec::new();
+            put_string(&mut label, 1, name);
+            put_string(&mut label, 2, value);
+            put_bytes(&mut buf, 1, &label);
+        }
+        for (value, timestamp) in &self.samples {
+            let mut sample = Vec::new();
+            put_double(&mut sample, 1, *value);
+            put_int64(&mut sample, 2, *timestamp);
+            put_bytes(&mut buf, 2, &sample);
+        }
+        buf
+    }
+}
+
+pub fn encode_write_request(series: &[TimeSeries]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for ts in series {
+        put_bytes(&mut buf, 1, &ts.encode());
+    }
+    buf
+}
+
+pub async fn push(url: &str, series: &[TimeSeries]) -> BoxResult<()> {
+    let client = reqwest::Client::new();
+
+    for batch in series.chunks(BATCH_SIZE) {
+        let body = snap::raw::Encoder::new().compress_vec(&encode_write_request(batch))?;
+
+        let response = client
+            .post(url)
+            .header(CONTENT_ENCODING, "snappy")
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            log::error!("Remote write failed with {}: {}", status, text);
+            return Err(format!("remote write returned {status}").into());
+        }
+
+        log::debug!("Pushed {} series to {}", batch.len(), url);
+    }
+
+    Ok(())
+}
+
+// Pushes the series of every refresh, at the refresh time unless they carry a timestamp
+#[derive(Debug, Clone)]
+pub struct RemoteWrite {
+    url: String,
+}
+
+impl RemoteWrite {
+    pub fn new(url: &str) -> Self {
+        RemoteWrite {
+            url: url.to_string(),
+        }
+    }
+}
+
+impl Sink for RemoteWrite {
+    fn name(&self) -> &'static str {
+        "remote write"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move {
+            let now = Utc::now().timestamp_millis();
+            let series: Vec<TimeSeries> = snapshot
+                .points
+                .iter()
+                .map(|point| {
+                    let mut series = TimeSeries::new(point.name, &point.labels);
+                    series
+                        .samples
+                        .push((point.value, point.timestamp.unwrap_or(now)));
+                    series
+                })
+                .collect();
+            push(&self.url, &series).await
+        })
+    }
+}
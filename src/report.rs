@@ -0,0 +1,230 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::state::{Billing, DEFAULT_CURRENCY};
+
+// Number of rows shown in each of the top tables
+const TOP: usize = 10;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Amounts are in hundredths of the currency, with a symbol for the one Atlas defaults to
+pub fn amount(cents: i64, currency: &str) -> String {
+    match currency == DEFAULT_CURRENCY {
+        true => format!("${:.2}", cents as f64 / 100.0),
+        false => format!("{:.2} {currency}", cents as f64 / 100.0),
+    }
+}
+
+// Amounts per currency, as orgs fetched together may be billed in different currencies
+fn amounts(totals: &BTreeMap<String, i64>) -> String {
+    match totals.is_empty() {
+        true => amount(0, DEFAULT_CURRENCY),
+        false => totals
+            .iter()
+            .map(|(currency, cents)| amount(*cents, currency))
+            .collect::<Vec<String>>()
+            .join(", "),
+    }
+}
+
+// Name, currency and amount
+type Row = (String, String, i64);
+
+fn top(map: HashMap<(String, String), i64>) -> Vec<Row> {
+    let mut values: Vec<Row> = map
+        .into_iter()
+        .map(|((name, currency), cents)| (name, currency, cents))
+        .collect();
+    values.sort_by_key(|(name, currency, cents)| (Reverse(*cents), name.clone(), currency.clone()));
+    values.truncate(TOP);
+    values
+}
+
+fn table(title: &str, column: &str, rows: &[Row]) -> String {
+    let rows: String = rows
+        .iter()
+        .map(|(name, currency, cents)| {
+            format!(
+                "<tr><td>{}</td><td class=\"cost\">{}</td></tr>",
+                escape(name),
+                amount(*cents, currency)
+            )
+        })
+        .collect();
+    format!("<h2>{title}</h2><table><tr><th>{column}</th><th>Cost</th></tr>{rows}</table>")
+}
+
+// Extrapolate the spend so far over the full billing period
+fn projection(billing: &Billing, total: i64, now: DateTime<Utc>) -> Option<i64> {
+    let start = DateTime::parse_from_rfc3339(&billing.period_start).ok()?;
+    let end = match DateTime::parse_from_rfc3339(&billing.period_end) {
+        Ok(end) => end,
+        // Pending invoices may not have an end date yet, so assume the end of the month
+        Err(_) => {
+            let (year, month) = match start.month() {
+                12 => (start.year() + 1, 1),
+                m => (start.year(), m + 1),
+            };
+            NaiveDate::from_ymd_opt(year, month, 1)?
+                .and_hms_opt(0, 0, 0)?
+                .and_utc()
+                .into()
+        }
+    };
+
+    let elapsed = (now - start.with_timezone(&Utc)).num_seconds() as f64;
+    let length = (end - start).num_seconds() as f64;
+    if elapsed <= 0.0 || length <= 0.0 {
+        return None;
+    }
+
+    Some((total as f64 * (length / elapsed).max(1.0)) as i64)
+}
+
+struct Summary {
+    total: String,
+    projected: String,
+    // Taken off the spend above, per currency
+    credits: String,
+    clusters: Vec<Row>,
+    skus: Vec<Row>,
+}
+
+fn summarize(billing: &Billing) -> Summary {
+    let mut totals: BTreeMap<String, i64> = BTreeMap::new();
+    let mut clusters: HashMap<(String, String), i64> = HashMap::new();
+    let mut skus: HashMap<(String, String), i64> = HashMap::new();
+    for value in billing.totals.values() {
+        *totals.entry(value.currency.to_string()).or_insert(0) += value.total_price_cents;
+        if let Some(cluster) = &value.cluster_name {
+            let name = format!(
+                "{} / {}",
+                value.group_name.as_deref().unwrap_or_default(),
+                cluster
+            );
+            *clusters
+                .entry((name, value.currency.to_string()))
+                .or_insert(0) += value.total_price_cents;
+        }
+        *skus
+            .entry((value.sku.to_string(), value.currency.to_string()))
+            .or_insert(0) += value.total_price_cents;
+    }
+
+    let projected: Option<BTreeMap<String, i64>> = totals
+        .iter()
+        .map(|(currency, total)| {
+            projection(billing, *total, Utc::now()).map(|cents| (currency.clone(), cents))
+        })
+        .collect();
+    let projected = match projected {
+        Some(projected) => amounts(&projected),
+        None => "n/a".to_string(),
+    };
+
+    // Credits are negative on the invoice, and reported as the amount taken off
+    let mut credits: BTreeMap<String, i64> = BTreeMap::new();
+    for value in billing.credits.values() {
+        *credits.entry(value.currency.to_string()).or_insert(0) -= value.total_price_cents;
+    }
+
+    Summary {
+        total: amounts(&totals),
+        projected,
+        credits: amounts(&credits),
+        clusters: top(clusters),
+        skus: top(skus),
+    }
+}
+
+pub fn render(billing: &Billing) -> String {
+    let summary = summarize(billing);
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Atlas Billing Report</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.8em; text-align: left; }}
+td.cost {{ text-align: right; }}
+</style>
+</head>
+<body>
+<h1>Atlas Billing Report</h1>
+<p>Billing period: {} to {}</p>
+<p>Month to date spend: <b>{}</b></p>
+<p>Projected spend: <b>{}</b></p>
+<p>Credits and discounts: <b>{}</b></p>
+{}
+{}
+</body>
+</html>
+",
+        escape(&billing.period_start),
+        escape(&billing.period_end),
+        summary.total,
+        summary.projected,
+        summary.credits,
+        table("Top Clusters", "Project / Cluster", &summary.clusters),
+        table("Top SKUs", "SKU", &summary.skus),
+    )
+}
+
+fn text_table(title: &str, rows: &[Row]) -> String {
+    let width = rows
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let rows: String = rows
+        .iter()
+        .map(|(name, currency, cents)| {
+            format!("  {:<width$}  {:>12}\n", name, amount(*cents, currency))
+        })
+        .collect();
+    format!("\n{title}\n{rows}")
+}
+
+// Plain text version of the report, for the terminal
+pub fn text(billing: &Billing) -> String {
+    let summary = summarize(billing);
+    format!(
+        "Billing period: {} to {}\nMonth to date spend: {}\nProjected spend: {}\nCredits and discounts: {}\n{}{}",
+        billing.period_start,
+        billing.period_end,
+        summary.total,
+        summary.projected,
+        summary.credits,
+        text_table("Top Clusters", &summary.clusters),
+        text_table("Top SKUs", &summary.skus),
+    )
+}
+
+// Daily cost per cluster, keyed by project and cluster name, then by usage date
+pub fn daily(billing: &Billing) -> BTreeMap<String, BTreeMap<String, u64>> {
+    let mut clusters: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+    for item in billing.line_items.iter().filter(|item| !item.is_credit()) {
+        if let Some(cluster) = &item.cluster_name {
+            let name = format!(
+                "{} / {}",
+                item.group_name.as_deref().unwrap_or_default(),
+                cluster
+            );
+            let date = item.start_date.chars().take(10).collect();
+            *clusters.entry(name).or_default().entry(date).or_insert(0) +=
+                item.total_price_cents.unsigned_abs();
+        }
+    }
+    clusters
+}
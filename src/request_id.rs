@@ -0,0 +1,42 @@
+// Tag each request with an id, taken from the X-Request-Id header when a proxy in front has
+// set one, so that error responses and log lines can be matched up across the proxy chain
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::IntoResponse,
+};
+
+pub static X_REQUEST_ID: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Only ids of reasonable length and printable characters are honored
+fn incoming(value: &HeaderValue) -> Option<String> {
+    let id = value.to_str().ok()?;
+    match !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()) {
+        true => Some(id.to_string()),
+        false => None,
+    }
+}
+
+pub async fn request_id<B>(mut req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(incoming)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let value = HeaderValue::from_str(&id).expect("request id is a valid header value");
+
+    // Passed on in the request, so that the request span can pick it up
+    req.headers_mut().insert(X_REQUEST_ID, value.clone());
+    let mut response = REQUEST_ID.scope(id, next.run(req)).await.into_response();
+    response.headers_mut().insert(X_REQUEST_ID, value);
+    response
+}
+
+// The id of the request being handled, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
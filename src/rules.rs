@@ -0,0 +1,150 @@
+use clap::ArgMatches;
+
+// Days left in the current month, as a scalar so it can be applied to any label set
+static REMAINING_DAYS: &str = "scalar(days_in_month() - day_of_month())";
+
+// The cost of the last day, from how much the totals grew over it. The rates are the price
+// of a single unit, such as one server, so they can not be projected on their own.
+static DAILY: &str = "clamp_min(delta(atlas_billing_item_cents_total[1d]), 0)";
+
+fn rule(record: &str, expr: &str) -> String {
+    format!("  - record: {record}\n    expr: {expr}\n")
+}
+
+// Recording rules rolling up the billing metrics, so that dashboards do not have to
+// aggregate every series at query time
+pub fn recording_rules(opts: &ArgMatches) -> String {
+    let interval = opts.value_of("interval").unwrap();
+    let labels = opts
+        .value_of("cluster_labels")
+        .unwrap()
+        .split(',')
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && *l != "currency")
+        // Amounts in different currencies are never summed together
+        .chain(["currency"])
+        .collect::<Vec<&str>>()
+        .join(", ");
+
+    let mut output = format!("groups:\n- name: atlas_billing\n  interval: {interval}\n  rules:\n");
+
+    // Per cluster totals, unit prices per hour and cost of the last day
+    output.push_str(&rule(
+        "cluster:atlas_billing_item_cents_total:sum",
+        &format!("sum by ({labels}) (atlas_billing_item_cents_total)"),
+    ));
+    output.push_str(&rule(
+        "cluster:atlas_billing_item_cents_rate:sum",
+        &format!("sum by ({labels}) (atlas_billing_item_cents_rate)"),
+    ));
+    output.push_str(&rule(
+        "cluster:atlas_billing_daily_cents:sum",
+        &format!("sum by ({labels}) ({DAILY})"),
+    ));
+    output.push_str(&rule(
+        "cluster:atlas_billing_projected_cents:sum",
+        &format!("cluster:atlas_billing_item_cents_total:sum + cluster:atlas_billing_daily_cents:sum * {REMAINING_DAYS}"),
+    ));
+
+    // Category rollups, such as backup or data federation
+    output.push_str(&rule(
+        "category:atlas_billing_item_cents_total:sum",
+        "sum by (org_id, category, currency) (atlas_billing_item_cents_total)",
+    ));
+    output.push_str(&rule(
+        "category:atlas_billing_item_cents_rate:sum",
+        "sum by (org_id, category, currency) (atlas_billing_item_cents_rate)",
+    ));
+
+    // Org wide totals and projection for the month
+    output.push_str(&rule(
+        "org:atlas_billing_item_cents_total:sum",
+        "sum by (org_id, currency) (atlas_billing_item_cents_total)",
+    ));
+    output.push_str(&rule(
+        "org:atlas_billing_item_cents_rate:sum",
+        "sum by (org_id, currency) (atlas_billing_item_cents_rate)",
+    ));
+    output.push_str(&rule(
+        "org:atlas_billing_daily_cents:sum",
+        &format!("sum by (org_id, currency) ({DAILY})"),
+    ));
+    output.push_str(&rule(
+        "org:atlas_billing_projected_cents:sum",
+        &format!("org:atlas_billing_item_cents_total:sum + org:atlas_billing_daily_cents:sum * {REMAINING_DAYS}"),
+    ));
+
+    output
+}
+
+fn alert(name: &str, expr: &str, duration: &str, severity: &str, summary: &str) -> String {
+    format!(
+        "  - alert: {name}\n    expr: {expr}\n    for: {duration}\n    labels:\n      severity: {severity}\n    annotations:\n      summary: \"{}\"\n",
+        summary.replace('"', "\\\"")
+    )
+}
+
+// A starter set of alerts on the billing metrics, and on the exporter itself
+pub fn alert_rules(opts: &ArgMatches) -> String {
+    let job = opts.value_of("job").unwrap();
+    let stale = opts.value_of("stale_after").unwrap();
+    let budget: f64 = opts
+        .value_of("budget")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("Supplied budget not in range, defaulting to 0");
+            0.0
+        });
+
+    let mut output = String::from("groups:\n- name: atlas_billing_alerts\n  rules:\n");
+
+    // Budget alerts are only generated when a monthly budget is set, in the currency of the org
+    if budget > 0.0 {
+        output.push_str(&alert(
+            "AtlasBillingBudgetExceeded",
+            &format!("sum by (org_id, currency) (atlas_billing_item_cents_total) / 100 > {budget}"),
+            "15m",
+            "critical",
+            &format!("Atlas org {{{{ $labels.org_id }}}} has spent {{{{ $value | printf \"%.2f\" }}}} {{{{ $labels.currency }}}}, over the budget of {budget}"),
+        ));
+        output.push_str(&alert(
+            "AtlasBillingProjectedOverBudget",
+            &format!("(sum by (org_id, currency) (atlas_billing_item_cents_total) + sum by (org_id, currency) ({DAILY}) * {REMAINING_DAYS}) / 100 > {budget}"),
+            "1h",
+            "warning",
+            &format!("Atlas org {{{{ $labels.org_id }}}} is projected to spend {{{{ $value | printf \"%.2f\" }}}} {{{{ $labels.currency }}}} this month, over the budget of {budget}"),
+        ));
+    }
+
+    output.push_str(&alert(
+        "AtlasBillingExporterDown",
+        &format!("up{{job=\"{job}\"}} == 0"),
+        "10m",
+        "warning",
+        "Atlas billing exporter {{ $labels.instance }} can not be scraped",
+    ));
+    output.push_str(&alert(
+        "AtlasBillingScrapeErrors",
+        &format!("min by (instance) (atlas_billing_scrape_success{{job=\"{job}\"}}) == 0"),
+        "15m",
+        "warning",
+        "Atlas billing exporter {{ $labels.instance }} fails to fetch invoices from Atlas",
+    ));
+    output.push_str(&alert(
+        "AtlasBillingDataMissing",
+        &format!("absent_over_time(atlas_billing_item_cents_total{{job=\"{job}\"}}[{stale}])"),
+        "0m",
+        "warning",
+        &format!("No Atlas billing data has been scraped for {stale}"),
+    ));
+    output.push_str(&alert(
+        "AtlasBillingDataStale",
+        &format!("sum by (instance) (changes(atlas_billing_item_cents_total{{job=\"{job}\"}}[{stale}])) == 0"),
+        "0m",
+        "warning",
+        &format!("Atlas billing totals on {{{{ $labels.instance }}}} have not changed for {stale}"),
+    ));
+
+    output
+}
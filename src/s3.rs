@@ -0,0 +1,191 @@
+// Archive the raw invoice json to S3, or any S3 compatible store, signing requests with
+// AWS Signature Version 4
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, Clone)]
+pub struct S3 {
+    bucket: String,
+    prefix: String,
+    region: String,
+    endpoint: Option<String>,
+    client: reqwest::Client,
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    // Credentials are read on each upload, so that rotated session tokens are picked up
+    fn from_env() -> BoxResult<Self> {
+        Ok(Credentials {
+            access_key: std::env::var("AWS_ACCESS_KEY_ID")
+                .map_err(|_| "AWS_ACCESS_KEY_ID is not set")?,
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY")
+                .map_err(|_| "AWS_SECRET_ACCESS_KEY is not set")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Percent encode each path segment as required for the canonical uri
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            segment
+                .bytes()
+                .map(|b| match b {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                        (b as char).to_string()
+                    }
+                    _ => format!("%{b:02X}"),
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+impl S3 {
+    // Without an endpoint, virtual hosted AWS urls are used. With an endpoint, such as
+    // for MinIO, the bucket is put in the path instead.
+    pub fn new(bucket: &str, prefix: Option<&str>, region: &str, endpoint: Option<&str>) -> Self {
+        S3 {
+            bucket: bucket.to_string(),
+            prefix: prefix.unwrap_or_default().trim_matches('/').to_string(),
+            region: region.to_string(),
+            endpoint: endpoint.map(|e| e.trim_end_matches('/').to_string()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // Objects are keyed by org and day, so the last fetch of each day is kept
+    pub async fn archive(&self, org: &str, body: &[u8]) -> BoxResult<()> {
+        let now = Utc::now();
+        let mut key = now.format(&format!("{org}/%Y/%m/%d.json")).to_string();
+        if !self.prefix.is_empty() {
+            key = format!("{}/{}", self.prefix, key);
+        }
+        self.put(&key, body, now).await
+    }
+
+    // Build the authorization header for a request with the given canonical path and headers
+    fn sign(
+        &self,
+        credentials: &Credentials,
+        path: &str,
+        headers: &[(&str, String)],
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(k, v)| format!("{k}:{}\n", v.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<&str>>()
+            .join(";");
+        let canonical_request =
+            format!("PUT\n{path}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            sha256_hex(canonical_request.as_bytes())
+        );
+
+        let key_date = hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), &date);
+        let key_region = hmac(&key_date, &self.region);
+        let key_service = hmac(&key_region, "s3");
+        let key_signing = hmac(&key_service, "aws4_request");
+        let signature = hex::encode(hmac(&key_signing, &string_to_sign));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            credentials.access_key
+        )
+    }
+
+    async fn put(&self, key: &str, body: &[u8], now: DateTime<Utc>) -> BoxResult<()> {
+        let credentials = Credentials::from_env()?;
+
+        let (url, host, path) = match &self.endpoint {
+            Some(endpoint) => {
+                let host = endpoint
+                    .split_once("://")
+                    .map(|(_, h)| h)
+                    .unwrap_or(endpoint)
+                    .to_string();
+                let path = encode_path(&format!("/{}/{}", self.bucket, key));
+                (format!("{endpoint}{path}"), host, path)
+            }
+            None => {
+                let host = format!("{}.s3.{}.amazonaws.com", self.bucket, self.region);
+                let path = encode_path(&format!("/{key}"));
+                (format!("https://{host}{path}"), host, path)
+            }
+        };
+
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = sha256_hex(body);
+
+        // Headers have to be signed in sorted order
+        let mut headers = vec![
+            ("content-type", "application/json".to_string()),
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let authorization = self.sign(&credentials, &path, &headers, &payload_hash, now);
+
+        let mut request = self
+            .client
+            .put(&url)
+            .header("authorization", authorization)
+            .body(body.to_vec());
+        for (k, v) in headers.into_iter().filter(|(k, _)| *k != "host") {
+            request = request.header(k, v);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            log::error!("S3 upload failed with {}: {}", status, text);
+            return Err(format!("S3 returned {status}").into());
+        }
+
+        log::info!(
+            "{{\"fn\": \"s3_archive\", \"bucket\":\"{}\", \"key\":\"{}\"}}",
+            self.bucket,
+            key
+        );
+        Ok(())
+    }
+}
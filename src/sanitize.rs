@@ -0,0 +1,96 @@
+use crate::config::Config;
+
+// Cluster and project names are free form, so optionally normalize them before they are
+// used as label values
+#[derive(Debug, Clone, Default)]
+pub struct Sanitizer {
+    pub lowercase: bool,
+    pub replace_invalid: bool,
+    pub max_length: usize,
+}
+
+impl Sanitizer {
+    pub fn new(config: &Config) -> Self {
+        Sanitizer {
+            lowercase: config.label_lowercase,
+            replace_invalid: config.label_replace_invalid,
+            max_length: config.label_max_length,
+        }
+    }
+
+    pub fn apply(&self, value: &str) -> String {
+        let mut value = match self.lowercase {
+            true => value.to_lowercase(),
+            false => value.to_string(),
+        };
+
+        if self.replace_invalid {
+            value = value
+                .chars()
+                .map(
+                    |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                        true => c,
+                        false => '_',
+                    },
+                )
+                .collect();
+        }
+
+        if self.max_length > 0 {
+            value = value.chars().take(self.max_length).collect();
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_by_default() {
+        let sanitizer = Sanitizer::default();
+        assert_eq!(sanitizer.apply("Orders Prod/EU ü"), "Orders Prod/EU ü");
+    }
+
+    #[test]
+    fn lowercases() {
+        let sanitizer = Sanitizer {
+            lowercase: true,
+            ..Sanitizer::default()
+        };
+        assert_eq!(sanitizer.apply("Orders-PROD"), "orders-prod");
+    }
+
+    #[test]
+    fn replaces_invalid() {
+        let sanitizer = Sanitizer {
+            replace_invalid: true,
+            ..Sanitizer::default()
+        };
+        // Each character is replaced once, multibyte ones included
+        assert_eq!(sanitizer.apply("orders prod/eu.ü_1"), "orders_prod_eu.__1");
+    }
+
+    #[test]
+    fn cuts_on_characters() {
+        let sanitizer = Sanitizer {
+            max_length: 3,
+            ..Sanitizer::default()
+        };
+        // A byte cut would fall inside the multibyte characters
+        assert_eq!(sanitizer.apply("äöüß"), "äöü");
+        assert_eq!(sanitizer.apply("ab"), "ab");
+    }
+
+    #[test]
+    fn lowercases_before_replacing() {
+        let sanitizer = Sanitizer {
+            lowercase: true,
+            replace_invalid: true,
+            max_length: 6,
+        };
+        assert_eq!(sanitizer.apply("ÄB Orders"), "_b_ord");
+    }
+}
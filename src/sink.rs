@@ -0,0 +1,235 @@
+// Destinations the billing series are emitted to after each refresh: the Prometheus registry
+// served on /metrics, the snapshot stores and the push based sinks. All of them are handed the
+// same series, computed once per refresh, along with the aggregation they were computed from.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::aggregate::hourly_rate;
+use crate::config::Config;
+use crate::metrics::{BillingRegistry, Labels, Point};
+use crate::sanitize::Sanitizer;
+use crate::sku::backup_type;
+use crate::state::{parse_timestamp, Billing};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub type Emit<'a> = Pin<Box<dyn Future<Output = BoxResult<()>> + Send + 'a>>;
+
+// The outcome of a refresh, as handed to every sink
+pub struct Snapshot<'a> {
+    pub billing: &'a Billing,
+    // Every series of the refresh, sorted by name and labels
+    pub points: &'a [Point],
+    // Set when the billing is the same aggregation as the previous refresh
+    pub unchanged: bool,
+    // Set when the aggregation is served from the cache, as fetching it failed
+    pub stale: bool,
+}
+
+impl Snapshot<'_> {
+    // Snapshot stores only write fresh data, so that history is not duplicated
+    pub fn is_new(&self) -> bool {
+        !self.unchanged && !self.stale
+    }
+}
+
+pub trait Sink: Debug + Send + Sync {
+    // Used when logging a failed emit
+    fn name(&self) -> &'static str;
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a>;
+}
+
+// Computes the billing series of an aggregation
+#[derive(Debug)]
+pub struct Series {
+    timestamps: bool,
+    dollars: bool,
+    sanitizer: Sanitizer,
+}
+
+// Add a cost total, along with its variant in whole units of the currency when enabled
+pub fn push_total(
+    points: &mut Vec<Point>,
+    names: (&'static str, &'static str),
+    dollars: bool,
+    labels: Labels,
+    cents: f64,
+    timestamp: Option<i64>,
+) {
+    if dollars {
+        points.push(Point::new(
+            names.1,
+            labels.clone(),
+            cents / 100.0,
+            timestamp,
+        ));
+    }
+    points.push(Point::new(names.0, labels, cents, timestamp));
+}
+
+impl Series {
+    pub fn new(config: &Config) -> Self {
+        Series {
+            timestamps: config.timestamps,
+            dollars: config.dollar_metrics,
+            sanitizer: Sanitizer::new(config),
+        }
+    }
+
+    pub fn points(&self, billing: &Billing) -> Vec<Point> {
+        let mut points = Vec::new();
+
+        // Only attach timestamps to rates when requested, using the end date of the usage. The
+        // totals keep growing through the day, and Prometheus rejects a sample with another
+        // value at a timestamp it has already seen, so they are left at the scrape time.
+        let timestamp = |end_date: &str| match self.timestamps {
+            true => parse_timestamp(end_date),
+            false => None,
+        };
+
+        // Sum all backup sku's per cluster, as retention changes are easy to miss across sku's
+        let mut map_backup: HashMap<Labels, i64> = HashMap::new();
+
+        for value in billing.totals.values() {
+            if backup_type(&value.sku).is_some() {
+                let cluster = value
+                    .labels(billing.channel(value.org_id.as_deref()), &self.sanitizer)
+                    .into_iter()
+                    .filter(|(name, _)| {
+                        !matches!(*name, "category" | "backup_type" | "sku" | "unit")
+                    })
+                    .collect();
+                *map_backup.entry(cluster).or_insert(0) += value.total_price_cents;
+            }
+
+            push_total(
+                &mut points,
+                (
+                    "atlas_billing_item_cents_total",
+                    "atlas_billing_item_dollars_total",
+                ),
+                self.dollars,
+                value.labels(billing.channel(value.org_id.as_deref()), &self.sanitizer),
+                value.total_price_cents as f64,
+                None,
+            );
+        }
+
+        for (labels, cents) in map_backup {
+            push_total(
+                &mut points,
+                (
+                    "atlas_billing_cluster_backup_cents_total",
+                    "atlas_billing_cluster_backup_dollars_total",
+                ),
+                self.dollars,
+                labels,
+                cents as f64,
+                None,
+            );
+        }
+
+        for value in billing.credits.values() {
+            push_total(
+                &mut points,
+                (
+                    "atlas_billing_credits_cents",
+                    "atlas_billing_credits_dollars",
+                ),
+                self.dollars,
+                value.credit_labels(billing.channel(value.org_id.as_deref()), &self.sanitizer),
+                // Credits are negative on the invoice, and exported as the amount taken off
+                -value.total_price_cents as f64,
+                None,
+            );
+        }
+
+        for value in billing.rates.values() {
+            let labels = value.labels(billing.channel(value.org_id.as_deref()), &self.sanitizer);
+
+            match hourly_rate(value) {
+                Some(rate) => points.push(Point::new(
+                    "atlas_billing_item_cents_rate",
+                    labels,
+                    rate,
+                    timestamp(&value.end_date),
+                )),
+                // Data scanned or returned is billed per use, so there is no hourly rate to report
+                None => log::debug!("Skipping rate for usage based sku {}", &value.sku),
+            }
+        }
+
+        points
+    }
+}
+
+// Sets the series in the registry served on /metrics
+#[derive(Debug)]
+pub struct Prometheus {
+    registry: BillingRegistry,
+}
+
+impl Sink for Prometheus {
+    fn name(&self) -> &'static str {
+        "Prometheus"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move {
+            self.registry.set_points(snapshot.points);
+            Ok(())
+        })
+    }
+}
+
+// The registry served on /metrics, followed by the configured snapshot stores
+#[cfg_attr(not(feature = "mongodb"), allow(unused_variables))]
+pub async fn sinks(
+    config: &Config,
+    org: &str,
+    registry: &BillingRegistry,
+) -> BoxResult<Vec<Arc<dyn Sink>>> {
+    let mut sinks: Vec<Arc<dyn Sink>> = vec![Arc::new(Prometheus {
+        registry: registry.clone(),
+    })];
+
+    #[cfg(feature = "parquet")]
+    if let Some(dir) = &config.parquet_dir {
+        sinks.push(Arc::new(crate::parquet::Parquet::new(dir)));
+    }
+    #[cfg(not(feature = "parquet"))]
+    if config.parquet_dir.is_some() {
+        return Err("Supplied parquet_dir needs a build with the parquet feature".into());
+    }
+
+    #[cfg(feature = "mongodb")]
+    if let Some(uri) = &config.mongodb_uri {
+        sinks.push(Arc::new(
+            crate::mongo::Mongo::new(
+                uri,
+                &config.mongodb_database,
+                &config.mongodb_collection,
+                org,
+            )
+            .await?,
+        ));
+    }
+    #[cfg(not(feature = "mongodb"))]
+    if config.mongodb_uri.is_some() {
+        return Err("Supplied mongodb_uri needs a build with the mongodb feature".into());
+    }
+
+    if let Some(table) = &config.bigquery_table {
+        sinks.push(Arc::new(crate::bigquery::BigQuery::new(
+            table,
+            config.bigquery_token_file.as_deref(),
+        )?));
+    }
+
+    Ok(sinks)
+}
@@ -0,0 +1,78 @@
+// Atlas bills in a handful of unit styles. Cluster compute is priced per hour,
+// storage per day, Online Archive per month, and Data Federation per amount of
+// data scanned or returned, which has no meaningful hourly rate at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitKind {
+    Hourly,
+    Daily,
+    Monthly,
+    Usage,
+}
+
+impl UnitKind {
+    pub fn from_unit(unit: &str) -> UnitKind {
+        let unit = unit.to_lowercase();
+        if unit.contains("hour") {
+            UnitKind::Hourly
+        } else if unit.contains("day") {
+            UnitKind::Daily
+        } else if unit.contains("month") {
+            UnitKind::Monthly
+        } else {
+            UnitKind::Usage
+        }
+    }
+
+    // Number of hours covered by a single unit, if the unit is time based
+    pub fn hours(&self) -> Option<f64> {
+        match self {
+            UnitKind::Hourly => Some(1.0),
+            UnitKind::Daily => Some(24.0),
+            // Atlas uses an average month of 730 hours
+            UnitKind::Monthly => Some(730.0),
+            UnitKind::Usage => None,
+        }
+    }
+}
+
+// Group sku's into broader categories, so that otherwise hidden costs can be selected on
+pub fn category(sku: &str) -> &'static str {
+    if backup_type(sku).is_some() {
+        "backup"
+    } else if sku.contains("OBJECT_STORAGE") || sku.contains("ONLINE_ARCHIVE") {
+        "online_archive"
+    } else if sku.contains("DATA_LAKE") || sku.contains("DATA_FEDERATION") {
+        "data_federation"
+    } else {
+        "other"
+    }
+}
+
+// Backup costs are spread over several sku's per cloud provider
+pub fn backup_type(sku: &str) -> Option<&'static str> {
+    if sku.contains("PIT_RESTORE") {
+        Some("continuous")
+    } else if sku.contains("SNAPSHOT_EXPORT") || sku.contains("BACKUP_DOWNLOAD") {
+        Some("snapshot_export")
+    } else if sku.contains("BACKUP_SNAPSHOT") || sku.contains("BACKUP_STORAGE") {
+        Some("snapshot_storage")
+    } else {
+        None
+    }
+}
+
+// Line items that take off the bill rather than add to it. Atlas lists these with a
+// negative price, which is also how any other adjustment shows up.
+pub fn credit_type(sku: &str, cents: i64) -> Option<&'static str> {
+    if sku.contains("DISCOUNT") {
+        Some("discount")
+    } else if sku.contains("PREPAID") || sku.contains("COMMITMENT") {
+        Some("prepaid")
+    } else if sku.contains("CREDIT") {
+        Some("credit")
+    } else if cents < 0 {
+        Some("adjustment")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,143 @@
+// Record the totals of every refresh in a local SQLite database, so that cost history
+// survives restarts without an external database
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use crate::sink::{Emit, Sink, Snapshot};
+use crate::state::Billing;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+static SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS totals (
+    refresh_time INTEGER NOT NULL,
+    org_id TEXT NOT NULL,
+    group_id TEXT NOT NULL,
+    group_name TEXT NOT NULL,
+    cluster_name TEXT NOT NULL,
+    sku TEXT NOT NULL,
+    quantity REAL NOT NULL,
+    total_price_cents INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS totals_refresh_time ON totals (refresh_time);
+";
+
+// Org wide total of a single refresh
+#[derive(Serialize, Debug, Clone)]
+pub struct Refresh {
+    pub refresh_time: i64,
+    pub org_id: String,
+    pub total_price_cents: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+    retention_days: i64,
+}
+
+impl Sqlite {
+    pub fn open(path: &str, retention_days: i64) -> BoxResult<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Sqlite {
+            connection: Arc::new(Mutex::new(connection)),
+            retention_days,
+        })
+    }
+
+    pub async fn record(&self, billing: &Billing) -> BoxResult<()> {
+        let refresh_time = Utc::now().timestamp_millis();
+        let rows: Vec<_> = billing
+            .totals
+            .values()
+            .map(|v| {
+                (
+                    v.org_id.as_deref().unwrap_or_default().to_string(),
+                    v.group_id.as_deref().unwrap_or_default().to_string(),
+                    v.group_name.as_deref().unwrap_or_default().to_string(),
+                    v.cluster_name.as_deref().unwrap_or_default().to_string(),
+                    v.sku.to_string(),
+                    v.quantity,
+                    v.total_price_cents,
+                )
+            })
+            .collect();
+
+        let expire = refresh_time - self.retention_days * 24 * 60 * 60 * 1000;
+        let retention_days = self.retention_days;
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || -> BoxResult<()> {
+            let mut connection = connection.lock().expect("sqlite connection poisoned");
+            let tx = connection.transaction()?;
+            {
+                let mut insert = tx.prepare_cached(
+                    "INSERT INTO totals (refresh_time, org_id, group_id, group_name, cluster_name, sku, quantity, total_price_cents) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                )?;
+                for (org_id, group_id, group_name, cluster_name, sku, quantity, cents) in &rows {
+                    insert.execute(params![
+                        refresh_time,
+                        org_id,
+                        group_id,
+                        group_name,
+                        cluster_name,
+                        sku,
+                        quantity,
+                        cents
+                    ])?;
+                }
+            }
+            // Zero retention keeps the history forever
+            if retention_days > 0 {
+                tx.execute("DELETE FROM totals WHERE refresh_time < ?1", params![expire])?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await??;
+
+        log::info!(
+            "{{\"fn\": \"sqlite_record\", \"refresh_time\":{}}}",
+            refresh_time
+        );
+        Ok(())
+    }
+
+    // Org wide totals of each refresh since the given time, oldest first
+    pub async fn history(&self, since: i64) -> BoxResult<Vec<Refresh>> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || -> BoxResult<Vec<Refresh>> {
+            let connection = connection.lock().expect("sqlite connection poisoned");
+            let mut query = connection.prepare_cached(
+                "SELECT refresh_time, org_id, SUM(total_price_cents) FROM totals WHERE refresh_time >= ?1 GROUP BY refresh_time, org_id ORDER BY refresh_time",
+            )?;
+            let rows = query.query_map(params![since], |row| {
+                Ok(Refresh {
+                    refresh_time: row.get(0)?,
+                    org_id: row.get(1)?,
+                    total_price_cents: row.get(2)?,
+                })
+            })?;
+            Ok(rows.collect::<Result<Vec<Refresh>, _>>()?)
+        })
+        .await?
+    }
+}
+
+impl Sink for Sqlite {
+    fn name(&self) -> &'static str {
+        "SQLite"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move {
+            if snapshot.is_new() {
+                self.record(snapshot.billing).await?;
+            }
+            Ok(())
+        })
+    }
+}
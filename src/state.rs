@@ -1,23 +1,90 @@
-use crate::https::HttpsClient;
-use chrono::Datelike;
+use crate::https::{create_https_client, decompress, HttpsClient};
 use chrono::Utc;
-use clap::ArgMatches;
-use hyper::header::{HeaderValue, AUTHORIZATION};
-use hyper::{Body, Request, Response};
+use chrono::{DateTime, Datelike};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE, ETAG,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, USER_AGENT,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
 use std::error::Error;
 //use serde_json::{Value};
-use digest_auth::AuthContext;
+use digest_auth::{AuthContext, HttpMethod};
 //use url::Url;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::Instrument;
 
-use crate::create_https_client;
+use crate::aggregate::{Aggregator, Options};
+use crate::circuit::Breaker;
+use crate::config::Config;
+use crate::cost_explorer::Usage;
 use crate::error::Error as RestError;
+use crate::exchange::Exchange;
+use crate::metrics::{agnostic, BillingRegistry, Family, Format, Labels, Point};
+use crate::recording::{Recorder, Replay};
+use crate::s3::S3;
+use crate::sanitize::Sanitizer;
+use crate::sink::{sinks, Series, Sink, Snapshot};
+use crate::sku::{backup_type, category, credit_type};
+#[cfg(not(feature = "simd-json"))]
+use crate::stream;
+use crate::throttle::Throttle;
+use crate::trace_context;
 
 type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+type Rendered = (Arc<Billing>, Arc<Vec<Point>>);
 
-static URL: &str = "https://cloud.mongodb.com/api/atlas/v1.0";
+// Convert an Atlas date into a timestamp in milliseconds
+pub fn parse_timestamp(date: &str) -> Option<i64> {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(t) => Some(t.timestamp_millis()),
+        Err(e) => {
+            log::error!("Could not parse date {}: {}", date, e);
+            None
+        }
+    }
+}
+
+// Replace the org and invoice ids in an api path, and drop the query, so that the
+// endpoint can be used as a metric label
+fn endpoint(path: &str) -> String {
+    let path = path.split('?').next().unwrap_or_default();
+    path.split('/')
+        .map(|segment| {
+            match segment.len() == 24 && segment.bytes().all(|b| b.is_ascii_hexdigit()) {
+                true => "{id}",
+                false => segment,
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+const WARM_UP_RETRY: Duration = Duration::from_secs(5);
+const CREDENTIALS_TTL: Duration = Duration::from_secs(60);
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+// When the credentials were last checked, and the outcome
+type CredentialCheck = Option<(Instant, Result<(), String>)>;
+
+static URL: &str = "/api/atlas/v1.0";
+
+// Atlas bills in US dollars unless the invoice says otherwise
+pub static DEFAULT_CURRENCY: &str = "USD";
+
+fn default_currency<T: From<&'static str>>() -> T {
+    T::from(DEFAULT_CURRENCY)
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -26,97 +93,596 @@ pub struct Data {
     amount_paid_cents: u64,
     created: String,
     credits_cents: u64,
+    #[serde(default)]
+    start_date: String,
     end_date: String,
     id: String,
     line_items: Vec<LineItem>,
+    #[serde(default)]
+    subtotal_cents: u64,
+    #[serde(default)]
+    org_id: Option<String>,
+    #[serde(default)]
+    linked_invoices: Vec<Data>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    status_name: Option<String>,
+}
+
+// Feeds serialized json straight into a hasher, without buffering it
+struct HashWriter<'a>(&'a mut DefaultHasher);
+
+impl std::io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Data {
+    // Hash of the whole invoice, to tell whether it changed since the last fetch
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_writer(HashWriter(&mut hasher), self).expect("invoice serializes");
+        hasher.finish()
+    }
+
+    // Start and end date of the billing period
+    pub fn period(&self) -> (String, String) {
+        (self.start_date.clone(), self.end_date.clone())
+    }
+
+    // Orgs billed through a cloud marketplace receive invoices with nothing billed by Atlas
+    // directly, as the charges are passed on to the cloud provider instead. Nothing is billed
+    // on a pending invoice either until it closes, so those tell nothing about the channel.
+    pub fn billing_channel(&self) -> Option<&'static str> {
+        match self.status_name.as_deref() {
+            None | Some("PENDING") => return None,
+            Some(_) => (),
+        }
+
+        let usage_cents = self
+            .line_items
+            .iter()
+            .filter(|item| !item.is_credit())
+            .map(|item| item.total_price_cents.unsigned_abs())
+            .sum::<u64>()
+            .max(self.subtotal_cents);
+
+        match self.amount_billed_cents == 0 && usage_cents > 0 {
+            true => Some("marketplace"),
+            false => Some("direct"),
+        }
+    }
+
+    // With cross-organization billing the paying org's invoice links the invoices of its
+    // child orgs, so pull their line items up and tag every item with the org it belongs to
+    pub fn flatten(self, org: &str) -> Vec<LineItem> {
+        let org_id = self.org_id.unwrap_or(org.to_string());
+        let currency = self.currency.unwrap_or_else(default_currency);
+        let mut items: Vec<LineItem> = self
+            .line_items
+            .into_iter()
+            .map(|mut item| {
+                item.org_id.get_or_insert(org_id.clone());
+                item.currency.get_or_insert(currency.clone());
+                item
+            })
+            .collect();
+
+        // Linked invoices are in the currency of the paying org unless they say otherwise
+        for mut linked in self.linked_invoices {
+            log::debug!("Flattening linked invoice {}", linked.id);
+            linked.currency.get_or_insert(currency.clone());
+            items.extend(linked.flatten(&org_id));
+        }
+
+        items
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct LineItem {
-    cluster_name: Option<String>,
-    created: String,
-    end_date: String,
-    quantity: f64,
-    group_name: Option<String>,
-    sku: String,
-    start_date: String,
-    total_price_cents: u64,
-    unit: String,
-    unit_price_dollars: f64,
+    pub cluster_name: Option<String>,
+    pub created: String,
+    pub end_date: String,
+    pub quantity: f64,
+    pub group_name: Option<String>,
+    #[serde(default)]
+    pub group_id: Option<String>,
+    pub sku: String,
+    pub start_date: String,
+    // Negative for credits, discounts and other adjustments
+    pub total_price_cents: i64,
+    pub unit: String,
+    pub unit_price_dollars: f64,
+    #[serde(default)]
+    pub org_id: Option<String>,
+    // Amounts are in hundredths of this currency, despite the cents and dollars in the names
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+impl LineItem {
+    // Aggregate on ids rather than display names, as names are neither unique across
+    // projects nor stable across renames. Clusters have no id in the invoice.
+    pub fn key(&self) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            self.org_id.as_deref().unwrap_or_default(),
+            self.group_id.as_deref().unwrap_or_default(),
+            self.cluster_name.as_deref().unwrap_or_default(),
+            self.sku
+        )
+    }
+
+    pub fn credit_type(&self) -> Option<&'static str> {
+        credit_type(&self.sku, self.total_price_cents)
+    }
+
+    pub fn is_credit(&self) -> bool {
+        self.credit_type().is_some()
+    }
+
+    pub fn currency(&self) -> String {
+        self.currency.clone().unwrap_or_else(default_currency)
+    }
 }
 
+// The names repeated across series are shared rather than copied for each of them
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Compressed {
-    cluster_name: Option<String>,
-    quantity: f64,
-    group_name: Option<String>,
-    sku: String,
-    total_price_cents: u64,
-    unit: String,
-    unit_price_dollars: f64,
-    end_date: String,
-    start_date: String,
+    pub org_id: Option<Arc<str>>,
+    pub cluster_name: Option<Arc<str>>,
+    pub quantity: f64,
+    pub group_name: Option<Arc<str>>,
+    pub group_id: Option<Arc<str>>,
+    pub sku: Arc<str>,
+    // Negative for credits, discounts and other adjustments, as on the invoice
+    pub total_price_cents: i64,
+    pub unit: Arc<str>,
+    pub unit_price_dollars: f64,
+    pub end_date: String,
+    pub start_date: String,
+    #[serde(default = "default_currency")]
+    pub currency: Arc<str>,
+    // Set for credits, discounts and other adjustments, from the first line item summed in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credit_type: Option<Arc<str>>,
+}
+
+impl From<&LineItem> for Compressed {
+    fn from(item: &LineItem) -> Self {
+        Compressed {
+            org_id: item.org_id.as_deref().map(Arc::from),
+            cluster_name: item.cluster_name.as_deref().map(Arc::from),
+            quantity: item.quantity,
+            sku: Arc::from(item.sku.as_str()),
+            group_name: item.group_name.as_deref().map(Arc::from),
+            group_id: item.group_id.as_deref().map(Arc::from),
+            total_price_cents: item.total_price_cents,
+            unit: Arc::from(item.unit.as_str()),
+            unit_price_dollars: item.unit_price_dollars,
+            start_date: item.start_date.clone(),
+            end_date: item.end_date.clone(),
+            currency: Arc::from(item.currency()),
+            credit_type: item.credit_type().map(Arc::from),
+        }
+    }
+}
+
+impl Compressed {
+    // Display names are only sanitized here, as the aggregation is keyed on the names Atlas
+    // returned, and the ids are carried along so that equally named clusters stay apart
+    pub fn labels(&self, billing_channel: &str, sanitizer: &Sanitizer) -> Labels {
+        vec![
+            (
+                "org_id",
+                self.org_id.as_deref().unwrap_or_default().to_string(),
+            ),
+            (
+                "group_id",
+                self.group_id.as_deref().unwrap_or_default().to_string(),
+            ),
+            (
+                "cluster_name",
+                sanitizer.apply(self.cluster_name.as_deref().unwrap_or_default()),
+            ),
+            (
+                "group_name",
+                sanitizer.apply(self.group_name.as_deref().unwrap_or_default()),
+            ),
+            ("category", category(&self.sku).to_string()),
+            (
+                "backup_type",
+                backup_type(&self.sku).unwrap_or("").to_string(),
+            ),
+            ("sku", self.sku.to_string()),
+            ("unit", self.unit.to_string()),
+            ("billing_channel", billing_channel.to_string()),
+            ("currency", self.currency.to_string()),
+        ]
+    }
+
+    pub fn credit_labels(&self, billing_channel: &str, sanitizer: &Sanitizer) -> Labels {
+        vec![
+            (
+                "org_id",
+                self.org_id.as_deref().unwrap_or_default().to_string(),
+            ),
+            (
+                "group_id",
+                self.group_id.as_deref().unwrap_or_default().to_string(),
+            ),
+            (
+                "group_name",
+                sanitizer.apply(self.group_name.as_deref().unwrap_or_default()),
+            ),
+            (
+                "credit_type",
+                self.credit_type.as_deref().unwrap_or_default().to_string(),
+            ),
+            ("sku", self.sku.to_string()),
+            ("billing_channel", billing_channel.to_string()),
+            ("currency", self.currency.to_string()),
+        ]
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Billing {
+    pub billing_channel: &'static str,
+    pub period_start: String,
+    pub period_end: String,
+    pub totals: HashMap<String, Compressed>,
+    pub rates: HashMap<String, Compressed>,
+    // Credits, discounts and other adjustments, kept apart from the usage totals
+    pub credits: HashMap<String, Compressed>,
+    #[serde(skip)]
+    pub line_items: Vec<LineItem>,
+    // Billing channel of each org, as orgs fetched together may be billed differently
+    #[serde(skip)]
+    pub channels: HashMap<String, &'static str>,
+    // The configured org whose invoice each org's line items came from, and the validators
+    // of those invoices, so that unchanged invoices can be carried over to the next fetch
+    #[serde(skip)]
+    pub sources: HashMap<String, String>,
+    #[serde(skip)]
+    pub validators: HashMap<String, Validators>,
+    #[serde(skip)]
+    pub fingerprints: HashMap<String, u64>,
+}
+
+// Validators Atlas returned with an invoice, sent along with the next fetch of the invoice
+// so that Atlas can answer with a 304 when it has not changed
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl Validators {
+    fn from_response(response: &Response<Body>) -> Option<Validators> {
+        let validators = Validators {
+            etag: response.headers().get(ETAG).cloned(),
+            last_modified: response.headers().get(LAST_MODIFIED).cloned(),
+        };
+        match validators.etag.is_some() || validators.last_modified.is_some() {
+            true => Some(validators),
+            false => None,
+        }
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = &self.etag {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        headers
+    }
+}
+
+// An invoice as fetched from Atlas, or None when it has not changed since the last fetch
+pub type Invoice = Option<(Data, Option<Validators>)>;
+
+// The invoice of an org with its validators, and its content and fingerprint unless it is
+// carried over from the previous fetch
+type Fetched = (String, Option<Validators>, Option<(Data, u64)>);
+
+impl Billing {
+    pub fn channel(&self, org_id: Option<&str>) -> &'static str {
+        org_id
+            .and_then(|org_id| self.channels.get(org_id))
+            .copied()
+            .unwrap_or(self.billing_channel)
+    }
+
+    // End of the newest usage line item, falling back to the start of the billing period
+    // while the invoice has no usage yet, in milliseconds. Credits are left out, as they
+    // usually run to the end of the billing period.
+    pub fn newest_end_date(&self) -> Option<i64> {
+        match self
+            .line_items
+            .iter()
+            .filter(|item| !item.is_credit())
+            .map(|item| &item.end_date)
+            .max()
+        {
+            Some(end_date) => parse_timestamp(end_date),
+            None => parse_timestamp(&self.period_start),
+        }
+    }
+}
+
+// Outcome of the fetches from Atlas, for the deep health check
+#[derive(Debug, Default)]
+pub struct FetchHealth {
+    pub error_streak: AtomicU64,
+    pub last_error: RwLock<Option<String>>,
+}
+
+impl FetchHealth {
+    fn success(&self) {
+        self.error_streak.store(0, Ordering::SeqCst);
+        *self.last_error.write().expect("fetch health poisoned") = None;
+    }
+
+    fn failure(&self, e: &RestError) {
+        self.error_streak.fetch_add(1, Ordering::SeqCst);
+        *self.last_error.write().expect("fetch health poisoned") = Some(e.to_string());
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct State {
-    pub client: HttpsClient,
-    pub public_key: String,
-    pub private_key: String,
-    pub org: String,
+    pub(crate) client: HttpsClient,
+    pub(crate) atlas_url: String,
+    pub(crate) user_agent: HeaderValue,
+    pub(crate) max_response_bytes: u64,
+    // Atlas requests only have a connect timeout, this bounds those that may hang
+    pub(crate) timeout: Duration,
+    pub(crate) breaker: Option<Arc<Breaker>>,
+    pub(crate) throttle: Option<Arc<Throttle>>,
+    pub(crate) recorder: Option<Arc<Recorder>>,
+    pub(crate) replay: Option<Arc<Replay>>,
+    pub(crate) public_key: String,
+    pub(crate) private_key: String,
+    // The first org, used for everything but fetching invoices
+    pub(crate) org: String,
+    pub(crate) orgs: Vec<String>,
+    pub(crate) org_concurrency: usize,
+    pub(crate) invoice_file: Option<String>,
+    pub(crate) cost_explorer_days: Option<i64>,
+    // The last Cost Explorer usage, queried in the background
+    pub(crate) usage: Arc<RwLock<Option<Usage>>>,
+    pub(crate) max_series: usize,
+    // Used by backfill and the Cost Explorer usage, the billing series are sanitized by series
+    pub(crate) sanitizer: Sanitizer,
+    // Also a sink, kept here to answer /api/v1/history
+    #[cfg(feature = "sqlite")]
+    pub(crate) sqlite: Option<crate::sqlite::Sqlite>,
+    pub(crate) series: Arc<Series>,
+    pub(crate) currency_agnostic_names: bool,
+    // Used by backfill, the billing series have their own in series
+    #[cfg(feature = "cli")]
+    pub(crate) dollar_metrics: bool,
+    pub(crate) exchange: Option<Arc<Exchange>>,
+    pub(crate) registry: BillingRegistry,
+    // Emitted to in order after each refresh, starting with the registry above
+    pub(crate) sinks: Vec<Arc<dyn Sink>>,
+    pub(crate) s3: Option<S3>,
+    pub(crate) cache: Arc<RwLock<Option<Arc<Billing>>>>,
+    // The aggregation the billing series were last computed from, along with those series
+    rendered: Arc<Mutex<Option<Rendered>>>,
+    pub(crate) fetched: Arc<AtomicI64>,
+    pub(crate) serve_stale: bool,
+    pub(crate) cache_file: Option<String>,
+    // Set while the cache loaded from disk is served, until the first fetch has completed
+    pub(crate) from_disk: Arc<AtomicBool>,
+    // Set once the warm up fetch has completed, or timed out
+    pub(crate) ready: Arc<AtomicBool>,
+    credentials: Arc<RwLock<CredentialCheck>>,
+    // Billing channel of each org, taken from its last closed invoice
+    closed_channels: Arc<RwLock<HashMap<String, &'static str>>>,
+    pub(crate) fetch_health: Arc<FetchHealth>,
+    pub(crate) max_data_age: i64,
+    heartbeat: Arc<AtomicI64>,
+}
+
+// Turn the status of the answer from Atlas into an error, unless the request succeeded
+fn check_status(response: Response<Body>) -> Result<Response<Body>, RestError> {
+    match response.status().as_u16() {
+        404 => Err(RestError::NotFound),
+        403 => Err(RestError::Forbidden),
+        401 => Err(RestError::Unauthorized),
+        102 | 200..=299 | 304 => Ok(decompress(response)),
+        code => {
+            log::error!("Got bad status code getting config: {}", code);
+            Err(RestError::UnknownCode(code))
+        }
+    }
 }
 
 impl State {
-    pub async fn new(opts: ArgMatches<'_>) -> BoxResult<Self> {
-        // Set timeout
-        let timeout: u64 = opts
-            .value_of("timeout")
-            .unwrap()
-            .parse()
-            .unwrap_or_else(|_| {
-                eprintln!("Supplied timeout not in range, defaulting to 60");
-                60
-            });
-
-        let client = create_https_client(timeout)?;
-        let public_key = opts
-            .value_of("public_key")
-            .unwrap()
-            .parse()
-            .expect("Could not parse public_key");
-        let private_key = opts
-            .value_of("private_key")
-            .unwrap()
-            .parse()
-            .expect("Could not parse private_key");
-        let org = opts
-            .value_of("org")
-            .unwrap()
-            .parse()
-            .expect("Could not get org id");
+    pub async fn new(config: Config) -> BoxResult<Self> {
+        let client = create_https_client(&config)?;
+        // An invoice file stands in for Atlas, so credentials and the org are optional
+        let invoice_file = config.invoice_file.clone();
+        let orgs: Vec<String> = config
+            .orgs
+            .iter()
+            .map(|org| org.trim().to_string())
+            .filter(|org| !org.is_empty())
+            .collect();
+        let orgs = match orgs.is_empty() && invoice_file.is_some() {
+            true => vec!["offline".to_string()],
+            false => orgs,
+        };
+        let org = orgs.first().cloned().ok_or("Supplied org is required")?;
+
+        // The file holds the invoice of one org, which would be counted once for every org
+        if invoice_file.is_some() && orgs.len() > 1 {
+            return Err("Supplied invoice_file can only be used with a single org".into());
+        }
+
+        let exchange = Exchange::new(&config)?.map(Arc::new);
+        let registry = BillingRegistry::new(config.series_ttl);
+        #[allow(unused_mut)]
+        let mut sinks = sinks(&config, &org, &registry).await?;
+
+        #[cfg(feature = "sqlite")]
+        let sqlite = match &config.sqlite_path {
+            Some(path) => Some(crate::sqlite::Sqlite::open(
+                path,
+                config.sqlite_retention_days,
+            )?),
+            None => None,
+        };
+        #[cfg(feature = "sqlite")]
+        if let Some(sqlite) = &sqlite {
+            sinks.push(Arc::new(sqlite.clone()));
+        }
+        #[cfg(not(feature = "sqlite"))]
+        if config.sqlite_path.is_some() {
+            return Err("Supplied sqlite_path needs a build with the sqlite feature".into());
+        }
+
+        let s3 = config.s3_bucket.as_deref().map(|bucket| {
+            S3::new(
+                bucket,
+                config.s3_prefix.as_deref(),
+                &config.s3_region,
+                config.s3_endpoint.as_deref(),
+            )
+        });
+
+        // Start from the aggregation cached on disk by a previous run, if there is one
+        let cache_file = config.cache_file.clone();
+        let (cached, fetched) = match &cache_file {
+            Some(path) => match crate::cache::load(path).await {
+                Ok((billing, fetched)) => {
+                    log::info!(
+                        "{{\"fn\": \"new\", \"msg\":\"loaded cache from {}\"}}",
+                        path
+                    );
+                    (Some(billing), fetched)
+                }
+                Err(e) => {
+                    log::warn!("Could not load cache file {}: {}", path, e);
+                    (None, 0)
+                }
+            },
+            None => (None, 0),
+        };
+        let from_disk = cached.is_some();
+
+        let user_agent = HeaderValue::from_str(&config.user_agent)
+            .map_err(|_| "Supplied user_agent is not a valid header value")?;
 
         Ok(State {
             client,
-            public_key,
-            private_key,
+            atlas_url: config.atlas_url.trim_end_matches('/').to_string(),
+            user_agent,
+            max_response_bytes: config.max_response_bytes,
+            timeout: Duration::from_secs(config.timeout),
+            breaker: Breaker::new(&config),
+            throttle: Throttle::new(&config),
+            recorder: Recorder::new(&config)?,
+            replay: Replay::new(&config)?,
+            public_key: config.public_key.clone(),
+            private_key: config.private_key.clone(),
             org,
+            orgs,
+            org_concurrency: config.org_concurrency.max(1),
+            invoice_file,
+            cost_explorer_days: config.cost_explorer_days,
+            usage: Arc::new(RwLock::new(None)),
+            max_series: config.max_series,
+            sanitizer: Sanitizer::new(&config),
+            #[cfg(feature = "sqlite")]
+            sqlite,
+            series: Arc::new(Series::new(&config)),
+            currency_agnostic_names: config.currency_agnostic_names,
+            #[cfg(feature = "cli")]
+            dollar_metrics: config.dollar_metrics,
+            exchange,
+            registry,
+            sinks,
+            s3,
+            cache: Arc::new(RwLock::new(cached.map(Arc::new))),
+            rendered: Arc::new(Mutex::new(None)),
+            fetched: Arc::new(AtomicI64::new(fetched)),
+            serve_stale: config.serve_stale,
+            cache_file,
+            from_disk: Arc::new(AtomicBool::new(from_disk)),
+            ready: Arc::new(AtomicBool::new(false)),
+            credentials: Arc::new(RwLock::new(None)),
+            closed_channels: Arc::new(RwLock::new(HashMap::new())),
+            fetch_health: Arc::new(FetchHealth::default()),
+            max_data_age: config.max_data_age,
+            heartbeat: Arc::new(AtomicI64::new(Utc::now().timestamp_millis())),
         })
     }
 
-    pub async fn get_pending(&self) -> Result<Data, RestError> {
-        let path = format!("orgs/{}/invoices/pending", self.org);
-        let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-        let value: Data = serde_json::from_slice(&bytes)?;
-        Ok(value)
+    // Push based sinks, emitted to after the registry and the snapshot stores
+    #[cfg(feature = "cli")]
+    pub(crate) fn with_sinks(mut self, sinks: Vec<Arc<dyn Sink>>) -> Self {
+        self.sinks.extend(sinks);
+        self
     }
 
-    pub async fn get_last_invoice_id(&self) -> Result<String, RestError> {
-        let path = format!("orgs/{}/invoices?itemsPerPage=2", self.org);
+    pub async fn get_pending(
+        &self,
+        org: &str,
+        validators: Option<&Validators>,
+    ) -> Result<Invoice, RestError> {
+        let path = format!("orgs/{}/invoices/pending", org);
+        let headers = validators.map(Validators::headers).unwrap_or_default();
+        let body = self.get_with(&path, &headers).await?;
+        if body.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let validators = Validators::from_response(&body);
+
+        // Archive the invoice untouched, before anything is parsed out of it
+        if let Some(s3) = &self.s3 {
+            let bytes = self.read(body).await?;
+            if let Err(e) = s3.archive(org, &bytes).await {
+                log::error!("Failed to archive invoice to S3: {}", e);
+            }
+            let value: Data = serde_json::from_slice(&bytes)?;
+            return Ok(Some((value, validators)));
+        }
+
+        Ok(Some((self.parse(body).await?, validators)))
+    }
+
+    // Read an invoice saved from Atlas, such as for dashboards, tests or reproducing a bug.
+    // The file is read on every refresh, so edits show up without a restart.
+    async fn read_invoice_file(&self, path: &str) -> Result<Data, RestError> {
+        let bytes = tokio::fs::read(path).await.map_err(|e| {
+            RestError::Http(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not read invoice_file {path}: {e}"),
+            )
+        })?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    pub async fn get_last_invoice_id(&self, org: &str) -> Result<String, RestError> {
+        let path = format!("orgs/{}/invoices?itemsPerPage=2", org);
         let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
+        let bytes = self.read(body).await?;
         let value: Value = serde_json::from_slice(&bytes)?;
 
         // Extract results array from json
@@ -132,30 +698,301 @@ impl State {
         Ok(id.as_str().expect("Cannot unwrap id as string!").to_owned())
     }
 
-    pub async fn get_last_invoice(&self) -> Result<Data, RestError> {
-        let id = self.get_last_invoice_id().await?;
+    // Get the most recent invoices of an org as listed by Atlas, newest first
+    async fn list_invoices(&self, org: &str, count: usize) -> Result<Vec<Value>, RestError> {
+        let path = format!("orgs/{}/invoices?itemsPerPage={}", org, count);
+        let body = self.get(&path).await?;
+        let bytes = self.read(body).await?;
+        let mut value: Value = serde_json::from_slice(&bytes)?;
+
+        match value["results"].take() {
+            Value::Array(results) => Ok(results),
+            _ => Err(RestError::NotFound),
+        }
+    }
+
+    // Get the ids of the most recent invoices, newest first
+    pub async fn get_invoice_ids(&self, org: &str, count: usize) -> Result<Vec<String>, RestError> {
+        Ok(self
+            .list_invoices(org, count)
+            .await?
+            .iter()
+            .filter_map(|invoice| invoice.get("id").and_then(|id| id.as_str()))
+            .map(|id| id.to_owned())
+            .collect())
+    }
+
+    // Get the ids of the most recent closed invoices, newest first, leaving out the pending
+    // invoice of the current month
+    pub async fn get_closed_invoice_ids(
+        &self,
+        org: &str,
+        count: usize,
+    ) -> Result<Vec<String>, RestError> {
+        Ok(self
+            .list_invoices(org, count + 1)
+            .await?
+            .iter()
+            .filter(|invoice| invoice["statusName"].as_str() != Some("PENDING"))
+            .filter_map(|invoice| invoice.get("id").and_then(|id| id.as_str()))
+            .map(|id| id.to_owned())
+            .take(count)
+            .collect())
+    }
+
+    pub async fn get_last_invoice(&self, org: &str) -> Result<Data, RestError> {
+        let id = self.get_last_invoice_id(org).await?;
+        self.get_invoice(org, &id).await
+    }
 
-        let path = format!("orgs/{}/invoices/{}", self.org, id);
+    pub async fn get_invoice(&self, org: &str, id: &str) -> Result<Data, RestError> {
+        let path = format!("orgs/{}/invoices/{}", org, id);
         let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-        let value: Data = serde_json::from_slice(&bytes)?;
-        Ok(value)
+        self.parse(body).await
+    }
+
+    // The billing channel of an org, from its last closed invoice as the pending one cannot
+    // tell. Looked up once per org, and taken as direct when there is no closed invoice yet.
+    async fn closed_channel(&self, org: &str) -> &'static str {
+        if let Some(channel) = self
+            .closed_channels
+            .read()
+            .expect("billing channels poisoned")
+            .get(org)
+        {
+            return channel;
+        }
+        if self.invoice_file.is_some() {
+            return "direct";
+        }
+
+        let channel = match self.get_last_invoice(org).await {
+            Ok(data) => data.billing_channel().unwrap_or("direct"),
+            Err(RestError::NotFound) => "direct",
+            Err(e) => {
+                log::error!("Failed to get the last invoice of org {}: {}", org, e);
+                return "direct";
+            }
+        };
+        self.closed_channels
+            .write()
+            .expect("billing channels poisoned")
+            .insert(org.to_string(), channel);
+        channel
+    }
+
+    fn too_large(&self) -> RestError {
+        log::error!(
+            "{{\"fn\": \"read\", \"error\": \"response exceeds {} bytes\"}}",
+            self.max_response_bytes
+        );
+        metrics::increment_counter!("atlas_api_errors_total", "kind" => "too_large");
+        RestError::TooLarge(self.max_response_bytes)
+    }
+
+    // Read a response body into memory, up to max_response_bytes
+    pub async fn read(&self, response: Response<Body>) -> Result<Bytes, RestError> {
+        let max = self.max_response_bytes;
+
+        // Refuse early when Atlas announces the size up front
+        if response.body().size_hint().lower() > max {
+            return Err(self.too_large());
+        }
+
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if (bytes.len() + chunk.len()) as u64 > max {
+                return Err(self.too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(Bytes::from(bytes))
+    }
+
+    // Parse a response body as it is received, up to max_response_bytes
+    #[cfg(not(feature = "simd-json"))]
+    pub async fn parse<T>(&self, response: Response<Body>) -> Result<T, RestError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        if response.body().size_hint().lower() > self.max_response_bytes {
+            return Err(self.too_large());
+        }
+        match stream::parse(response.into_body(), self.max_response_bytes).await {
+            Err(RestError::TooLarge(_)) => Err(self.too_large()),
+            result => result,
+        }
+    }
+
+    // simd-json needs the whole document in a mutable buffer, but parses it several times
+    // faster, which matters more than memory for large invoices
+    #[cfg(feature = "simd-json")]
+    pub async fn parse<T>(&self, response: Response<Body>) -> Result<T, RestError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let mut bytes = Vec::from(self.read(response).await?);
+        tokio::task::spawn_blocking(move || {
+            simd_json::serde::from_slice(&mut bytes)
+                .map_err(|e| RestError::SerdeJson(serde::de::Error::custom(e)))
+        })
+        .await
+        .expect("json parser panicked")
     }
 
     pub async fn get(&self, path: &str) -> Result<Response<Body>, RestError> {
-        let uri = format!("{URL}/{path}");
+        self.get_with(path, &HeaderMap::new()).await
+    }
+
+    #[tracing::instrument(name = "atlas", skip(self, headers))]
+    pub async fn get_with(
+        &self,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, RestError> {
+        let uri = format!("{}{URL}/{path}", self.atlas_url);
+        let start = Instant::now();
+        let result = self
+            .request(Method::GET, &uri, path, None, None, headers)
+            .await;
+        let endpoint = endpoint(path);
+        metrics::histogram!(
+            "atlas_api_request_duration_seconds",
+            start.elapsed().as_secs_f64(),
+            "endpoint" => endpoint.clone()
+        );
+
+        let status = match &result {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(e) => e
+                .upstream_status()
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "none".to_string()),
+        };
+        metrics::increment_counter!(
+            "atlas_api_requests_total",
+            "endpoint" => endpoint,
+            "status" => status
+        );
+        if let Err(e) = &result {
+            metrics::increment_counter!("atlas_api_errors_total", "kind" => e.kind());
+        }
+
+        result
+    }
+
+    // Send a request to Atlas, unless the circuit breaker has paused calls
+    pub async fn request(
+        &self,
+        method: Method,
+        uri: &str,
+        path: &str,
+        body: Option<String>,
+        accept: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, RestError> {
+        if let Some(replay) = &self.replay {
+            return check_status(replay.response(&method, uri)?);
+        }
+
+        let result = match &self.breaker {
+            Some(breaker) => {
+                breaker.allow()?;
+                let result = self
+                    .send(method.clone(), uri, path, body, accept, headers)
+                    .await;
+                breaker.record(result.as_ref().err());
+                result
+            }
+            None => {
+                self.send(method.clone(), uri, path, body, accept, headers)
+                    .await
+            }
+        };
+        match &self.recorder {
+            Some(recorder) => self.record(recorder, &method, uri, result).await,
+            None => result,
+        }
+    }
+
+    // Save a copy of the response to the record_dir, and hand on an identical one
+    async fn record(
+        &self,
+        recorder: &Recorder,
+        method: &Method,
+        uri: &str,
+        result: Result<Response<Body>, RestError>,
+    ) -> Result<Response<Body>, RestError> {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                if let Some(status) = e.upstream_status() {
+                    recorder
+                        .save(method, uri, status, &HeaderMap::new(), &[])
+                        .await;
+                }
+                return Err(e);
+            }
+        };
+        let (parts, body) = response.into_parts();
+        let bytes = self.read(Response::new(body)).await?;
+        recorder
+            .save(method, uri, parts.status.as_u16(), &parts.headers, &bytes)
+            .await;
+        Ok(Response::from_parts(parts, Body::from(bytes)))
+    }
+
+    // Send a digest authenticated request. The initial request is expected to be rejected
+    // with a www-authenticate challenge, which is then answered in a second request.
+    async fn send(
+        &self,
+        method: Method,
+        uri: &str,
+        path: &str,
+        body: Option<String>,
+        accept: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, RestError> {
+        let build = |auth: Option<HeaderValue>| {
+            let mut builder = Request::builder()
+                .method(method.clone())
+                .uri(uri)
+                .header(USER_AGENT, self.user_agent.clone())
+                .header(ACCEPT_ENCODING, "gzip");
+            if let Some(accept) = accept {
+                builder = builder.header(ACCEPT, accept);
+                if body.is_some() {
+                    builder = builder.header(CONTENT_TYPE, accept);
+                }
+            }
+            let answer = auth.is_some();
+            if let Some(auth) = auth {
+                builder = builder.header(AUTHORIZATION, auth);
+            }
+            let mut request = builder
+                .body(body.clone().map(Body::from).unwrap_or_else(Body::empty))
+                .expect("request builder");
+            // Only the answer to the challenge can be answered with a 304
+            if answer {
+                request.headers_mut().extend(headers.clone());
+            }
+            trace_context::inject(request.headers_mut());
+            request
+        };
+
         log::debug!("getting initial response {}", &uri);
-        let req = Request::builder()
-            .method("GET")
-            .uri(&uri)
-            .body(Body::empty())
-            .expect("request builder");
+        let req = build(None);
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire().await;
+        }
 
         // Send initial request
         let response = match self.client.request(req).await {
             Ok(s) => s,
             Err(e) => {
-                log::error!("{{\"error\":\"{}\"", e);
+                log::error!("{{\"error\":{}}}", Value::String(e.to_string()));
                 return Err(RestError::Hyper(e));
             }
         };
@@ -171,173 +1008,537 @@ impl State {
                     return Err(RestError::MissingHeader);
                 }
             },
-            _ => return Err(RestError::UnexpectedCode),
+            code => return Err(RestError::UnexpectedCode(code)),
         };
 
         // Generate Digest Header Context
-        let context = AuthContext::new(self.public_key.clone(), self.private_key.clone(), path);
+        let context = AuthContext::new_with_method(
+            self.public_key.clone(),
+            self.private_key.clone(),
+            path,
+            body.as_deref().map(str::as_bytes),
+            HttpMethod(method.as_str().to_string().into()),
+        );
 
         // Use context and compute with www_auth_header returned from API
         let answer = www_auth_header.respond(&context)?;
         let header_digest_auth = HeaderValue::from_str(&answer.to_string())?;
 
         log::debug!("Using digest header for authenticated request{}", &uri);
-        let mut req2 = Request::builder()
-            .method("GET")
-            .uri(&uri)
-            .body(Body::empty())
-            .expect("request builder");
-
-        // Add auth header to second request
-        req2.headers_mut().insert(AUTHORIZATION, header_digest_auth);
+        let req2 = build(Some(header_digest_auth));
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire().await;
+        }
 
-        // Send initial request
+        // Send authenticated request
         let response2 = match self.client.request(req2).await {
             Ok(s) => s,
             Err(e) => {
-                log::error!("{{\"error\":\"{}\"", e);
+                log::error!("{{\"error\":{}}}", Value::String(e.to_string()));
                 return Err(RestError::Hyper(e));
             }
         };
 
-        match response2.status().as_u16() {
-            404 => Err(RestError::NotFound),
-            403 => Err(RestError::Forbidden),
-            401 => Err(RestError::Unauthorized),
-            200 => Ok(response2),
-            _ => {
-                log::error!(
-                    "Got bad status code getting config: {}",
-                    response.status().as_u16()
-                );
-                Err(RestError::UnknownCode)
-            }
-        }
+        check_status(response2)
     }
 
-    pub async fn get_metrics(&self) -> Result<(), RestError> {
-        let day = Utc::now().date_naive().day();
+    // Fetch the current invoice, and aggregate its line items into totals and rates
+    #[tracing::instrument(name = "fetch", skip_all, fields(org = %self.orgs.join(",")))]
+    pub async fn get_billing(&self) -> Result<Arc<Billing>, RestError> {
+        // Replays ask for the invoices of the day they were recorded on
+        let now = match &self.replay {
+            Some(replay) => replay.started(),
+            None => Utc::now(),
+        };
+        let day = now.day();
 
         log::debug!("We are on the {} day of the month", day);
 
-        let data = match day {
-            1 => self.get_last_invoice().await?,
-            _ => self.get_pending().await?,
+        // Invoices that have not changed since the last fetch are carried over from it
+        let previous = self.cache.read().expect("billing cache poisoned").clone();
+        let validators = previous.as_ref().map(|p| &p.validators);
+
+        // Fetch the invoices of several orgs at once, keeping them in the configured order
+        let fetched: Vec<(String, Result<Invoice, RestError>)> =
+            futures::stream::iter(self.orgs.clone())
+                .map(|org| async move {
+                    let invoice = match (&self.invoice_file, day) {
+                        (Some(path), _) => self
+                            .read_invoice_file(path)
+                            .await
+                            .map(|data| Some((data, None))),
+                        (None, 1) => self
+                            .get_last_invoice(&org)
+                            .await
+                            .map(|data| Some((data, None))),
+                        (None, _) => {
+                            self.get_pending(&org, validators.and_then(|v| v.get(&org)))
+                                .await
+                        }
+                    };
+                    (org, invoice)
+                })
+                .buffered(self.org_concurrency)
+                .collect()
+                .await;
+
+        // An org that could not be fetched keeps its previous invoice, if there is one, so
+        // that the orgs which could still be fetched are exported
+        let mut failure = None;
+        let mut invoices = Vec::new();
+        for (org, invoice) in fetched {
+            match invoice {
+                Ok(invoice) => invoices.push((org, invoice)),
+                Err(e) => {
+                    log::error!("Failed to get the invoice of org {}: {}", org, e);
+                    if previous
+                        .as_ref()
+                        .is_some_and(|p| p.fingerprints.contains_key(&org))
+                    {
+                        invoices.push((org, None));
+                    }
+                    failure = Some(e);
+                }
+            }
+        }
+        if invoices.is_empty() {
+            if let Some(e) = failure {
+                self.fetch_health.failure(&e);
+                return Err(e);
+            }
+        }
+        let fetch_health = || match &failure {
+            Some(e) => self.fetch_health.failure(e),
+            None => self.fetch_health.success(),
         };
 
-        log::debug!("data: {:?}", data);
+        // Invoices answered with a 304, or with the same content as last time, are carried
+        // over from the previous fetch rather than aggregated again
+        let invoices: Vec<Fetched> = invoices
+            .into_iter()
+            .map(|(org, invoice)| match invoice {
+                Some((data, org_validators)) => {
+                    let fingerprint = data.fingerprint();
+                    let unchanged = previous.as_ref().and_then(|p| p.fingerprints.get(&org))
+                        == Some(&fingerprint);
+                    match unchanged {
+                        true => (org, org_validators, None),
+                        false => (org, org_validators, Some((data, fingerprint))),
+                    }
+                }
+                None => {
+                    let org_validators = previous
+                        .as_ref()
+                        .and_then(|p| p.validators.get(&org))
+                        .cloned();
+                    (org, org_validators, None)
+                }
+            })
+            .collect();
 
-        let mut map_total: HashMap<String, Compressed> = HashMap::new();
-        let mut map_rate: HashMap<String, Compressed> = HashMap::new();
+        // Nothing changed, so there is nothing to aggregate
+        if let Some(previous) = &previous {
+            if invoices.iter().all(|(_, _, invoice)| invoice.is_none()) {
+                log::debug!("No invoice has changed since the last fetch");
+                self.fetched
+                    .store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+                fetch_health();
+                return Ok(previous.clone());
+            }
+        }
 
-        // Get most recent metric date across all metrics
-        let current_date = match data.line_items.iter().max_by_key(|y| y.end_date.clone()) {
-            Some(i) => i.end_date.clone(),
-            None => return Ok(()),
-        };
+        let mut period: Option<(String, String)> = None;
+        let mut channels: HashMap<String, &'static str> = HashMap::new();
+        let mut sources: HashMap<String, String> = HashMap::new();
+        let mut validators: HashMap<String, Validators> = HashMap::new();
+        let mut fingerprints: HashMap<String, u64> = HashMap::new();
+        let mut line_items: Vec<LineItem> = Vec::new();
+        // Line items are summed up one at a time as they are taken from the invoices
+        // The time an invoice file was saved is not known, so its usage is taken as current
+        let mut aggregator = Aggregator::new(
+            self.invoice_file.is_none().then_some(now),
+            &Options {
+                max_series: self.max_series,
+            },
+        );
+        for (org, org_validators, invoice) in invoices {
+            if let Some(v) = org_validators {
+                validators.insert(org.clone(), v);
+            }
 
-        for item in data.line_items {
-            let name = match &item.cluster_name {
-                Some(e) => format!("{}_{}", e, item.sku),
-                None => item.sku.to_string(),
+            let (data, fingerprint) = match (invoice, &previous) {
+                (Some(invoice), _) => invoice,
+                // Validators are only sent along when there is a previous fetch
+                (None, Some(previous)) => {
+                    log::debug!("Invoice of org {} has not changed", org);
+                    period.get_or_insert_with(|| {
+                        (previous.period_start.clone(), previous.period_end.clone())
+                    });
+                    for item in previous.line_items.iter().filter(|item| {
+                        item.org_id
+                            .as_ref()
+                            .and_then(|org_id| previous.sources.get(org_id))
+                            == Some(&org)
+                    }) {
+                        let org_id = item.org_id.clone().unwrap_or_default();
+                        channels.insert(org_id.clone(), previous.channel(Some(&org_id)));
+                        sources.insert(org_id, org.clone());
+                        aggregator.push(item);
+                        line_items.push(item.clone());
+                    }
+                    channels.insert(org.clone(), previous.channel(Some(&org)));
+                    if let Some(fingerprint) = previous.fingerprints.get(&org) {
+                        fingerprints.insert(org.clone(), *fingerprint);
+                    }
+                    continue;
+                }
+                (None, None) => return Err(RestError::UnexpectedCode(304)),
             };
+            log::debug!("data: {:?}", data);
 
-            log::debug!("Working on {} from {}", name, item.end_date);
+            // Orgs share the calendar month as billing period
+            period.get_or_insert_with(|| (data.start_date.clone(), data.end_date.clone()));
+            fingerprints.insert(org.clone(), fingerprint);
 
-            // Add metric to the total HashMap
-            match map_total.get_mut(&name) {
-                Some(k) => {
-                    log::debug!("Found existing {} in map_total, adding up total", &name);
+            let billing_channel = match data.billing_channel() {
+                Some(billing_channel) => billing_channel,
+                None => self.closed_channel(&org).await,
+            };
+            log::debug!("Invoice {} is billed via {}", data.id, billing_channel);
 
-                    // Atlas prices sku's per region, so we need to get the sum
-                    k.total_price_cents += item.total_price_cents;
-                    k.quantity += item.quantity;
-                }
-                None => {
-                    log::debug!("Did not find existing {} in map_total", &name);
-                    let value = Compressed {
-                        cluster_name: item.cluster_name.clone(),
-                        quantity: item.quantity,
-                        sku: item.sku.clone(),
-                        group_name: item.group_name.clone(),
-                        total_price_cents: item.total_price_cents,
-                        unit: item.unit.clone(),
-                        unit_price_dollars: item.unit_price_dollars,
-                        start_date: item.start_date.clone(),
-                        end_date: item.end_date.clone(),
-                    };
-                    map_total.insert(name.clone(), value);
+            // Linked invoices of child orgs are billed through the paying org
+            channels.insert(org.clone(), billing_channel);
+            sources.insert(org.clone(), org.clone());
+            for item in data.flatten(&org) {
+                if let Some(org_id) = &item.org_id {
+                    if !channels.contains_key(org_id) {
+                        channels.insert(org_id.clone(), billing_channel);
+                        sources.insert(org_id.clone(), org.clone());
+                    }
                 }
+                aggregator.push(&item);
+                line_items.push(item);
+            }
+        }
+        let (period_start, period_end) = period.unwrap_or_default();
+
+        // Orgs billed through a marketplace only mark the whole fetch when all of them are
+        let billing_channel = match channels.values().all(|c| *c == "marketplace") {
+            true => "marketplace",
+            false => "direct",
+        };
+
+        let aggregation = aggregator.finish();
+
+        log::debug!("Total: {:?}", aggregation.totals);
+        log::debug!("Rates: {:?}", aggregation.rates);
+
+        // Shared with the cache, rather than copying every line item for each reader
+        let billing = Arc::new(Billing {
+            billing_channel,
+            period_start,
+            period_end,
+            totals: aggregation.totals,
+            rates: aggregation.rates,
+            credits: aggregation.credits,
+            line_items,
+            channels,
+            sources,
+            validators,
+            fingerprints,
+        });
+
+        // Keep the last successful aggregation around for the dashboard
+        *self.cache.write().expect("billing cache poisoned") = Some(billing.clone());
+        let fetched = Utc::now().timestamp_millis();
+        self.fetched.store(fetched, Ordering::SeqCst);
+        fetch_health();
+
+        if let Some(path) = &self.cache_file {
+            if let Err(e) = crate::cache::save(path, &billing, fetched).await {
+                log::error!("Failed to write cache file {}: {}", path, e);
+            }
+        }
+
+        Ok(billing)
+    }
+
+    // Get the last aggregation, only fetching the invoice when nothing has been cached yet
+    pub async fn cached_billing(&self) -> Result<Arc<Billing>, RestError> {
+        let cached = self.cache.read().expect("billing cache poisoned").clone();
+        match cached {
+            Some(billing) => Ok(billing),
+            None => self.get_billing().await,
+        }
+    }
+
+    // Check that the api key is accepted and can read the org, caching the outcome for a while.
+    // Only a definite rejection by Atlas is reported, as an unreachable Atlas says nothing
+    // about the credentials.
+    pub async fn validate_credentials(&self) -> Result<(), String> {
+        if self.invoice_file.is_some() {
+            return Ok(());
+        }
+        if let Some((checked, result)) = self
+            .credentials
+            .read()
+            .expect("credentials poisoned")
+            .clone()
+        {
+            if checked.elapsed() < CREDENTIALS_TTL {
+                return result;
+            }
+        }
+
+        let result = match self.get(&format!("orgs/{}", self.org)).await {
+            Ok(_) => Ok(()),
+            Err(RestError::Unauthorized) => {
+                Err("Atlas rejected the api key, it may have been revoked".to_string())
             }
+            Err(RestError::Forbidden) => Err(format!(
+                "The api key has no access to org {}, or the access list blocks this host",
+                self.org
+            )),
+            Err(RestError::NotFound) => Err(format!("Org {} was not found", self.org)),
+            Err(e) => {
+                log::warn!("Could not validate credentials: {}", e);
+                return Ok(());
+            }
+        };
+
+        *self.credentials.write().expect("credentials poisoned") =
+            Some((Instant::now(), result.clone()));
+        result
+    }
+
+    pub fn check_fresh(&self) -> Result<(), String> {
+        let fetched = self.fetched.load(Ordering::SeqCst);
+        if self.max_data_age > 0 && fetched == 0 {
+            return Err("Billing data has not been fetched yet".to_string());
+        }
+        let age = (Utc::now().timestamp_millis() - fetched) / 1000;
+        match self.max_data_age > 0 && age > self.max_data_age {
+            true => Err(format!("Billing data was last fetched {age} seconds ago")),
+            false => Ok(()),
+        }
+    }
 
-            // Only include metric if the end_date is today
-            if item.end_date == current_date {
-                // Add most recent metrics to hashmap
-                match map_rate.get_mut(&name) {
-                    Some(k) => {
-                        log::debug!("Found existing {} in map_rate", &name);
-                        // This metric has the same start date, indicating a SKU present in multiple regions
-                        // Therefore, get the sum of all
-                        // Atlas prices sku's per region, so we need to get the sum
-                        k.unit_price_dollars += item.unit_price_dollars;
-                        log::debug!("{} is already set in map_rate, and has the same end_date. Adding up total price to get {}", &name, k.unit_price_dollars);
+    // Tick in the background, so that a stalled runtime shows up in the liveness probe
+    pub fn start_heartbeat(&self) {
+        let heartbeat = self.heartbeat.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT);
+            loop {
+                interval.tick().await;
+                heartbeat.store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+            }
+        });
+    }
+
+    pub fn check_heartbeat(&self) -> Result<(), String> {
+        let since = Utc::now().timestamp_millis() - self.heartbeat.load(Ordering::SeqCst);
+        match since > 10 * HEARTBEAT.as_millis() as i64 {
+            true => Err(format!("Runtime has not ticked for {since} ms")),
+            false => Ok(()),
+        }
+    }
+
+    // Fetch from Atlas in the background at startup, retrying until it succeeds or the timeout
+    // passes, before reporting ready. A disk cache is served as stale data in the meantime.
+    pub fn warm_up(&self, timeout: Duration) {
+        if self.from_disk.load(Ordering::SeqCst) {
+            self.ready.store(true, Ordering::SeqCst);
+        }
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            let deadline = Instant::now() + timeout;
+            loop {
+                match tokio::time::timeout_at(deadline, state.get_billing()).await {
+                    Ok(Ok(_)) => {
+                        log::info!("{{\"fn\": \"warm_up\", \"msg\":\"initial fetch complete\"}}");
+                        break;
+                    }
+                    Ok(Err(e)) => log::error!("Initial fetch failed: {}", e),
+                    Err(_) => {
+                        log::warn!("Initial fetch did not complete within the warm up timeout");
+                        break;
                     }
-                    None => {
-                        log::debug!("Did not find existing {} in map_rate", &name);
-                        let value = Compressed {
-                            cluster_name: item.cluster_name.clone(),
-                            quantity: item.quantity,
-                            sku: item.sku.clone(),
-                            group_name: item.group_name.clone(),
-                            total_price_cents: item.total_price_cents,
-                            unit: item.unit.clone(),
-                            unit_price_dollars: item.unit_price_dollars,
-                            start_date: item.start_date.clone(),
-                            end_date: item.end_date.clone(),
-                        };
-                        map_rate.insert(name, value);
+                }
+                if Instant::now() + WARM_UP_RETRY >= deadline {
+                    log::warn!("Giving up on initial fetch, reporting ready");
+                    break;
+                }
+                tokio::time::sleep(WARM_UP_RETRY).await;
+            }
+            state.from_disk.store(false, Ordering::SeqCst);
+            state.ready.store(true, Ordering::SeqCst);
+        });
+    }
+
+    pub async fn get_metrics(&self) -> Result<(), RestError> {
+        self.refresh()
+            .instrument(tracing::info_span!(
+                "refresh",
+                refresh = tracing::field::Empty
+            ))
+            .await
+    }
+
+    // The billing series set by the refreshes so far, in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.registry.render(Format::Prometheus, Family::All)
+    }
+
+    // Rename the cost series when --currency_agnostic_names is set
+    pub(crate) fn named(&self, mut points: Vec<Point>) -> Vec<Point> {
+        if self.currency_agnostic_names {
+            for point in points.iter_mut() {
+                point.name = agnostic(point.name);
+            }
+        }
+        points
+    }
+
+    fn circuit_point(&self) -> Option<Point> {
+        self.breaker.as_ref().map(|breaker| {
+            Point::new(
+                "atlas_api_circuit_open",
+                vec![],
+                breaker.is_open() as u8 as f64,
+                None,
+            )
+        })
+    }
+
+    // The series about the exporter itself, and the freshness of the data it serves
+    fn status_points(&self, billing: &Billing, stale: bool) -> Vec<Point> {
+        let mut points: Vec<Point> = self.circuit_point().into_iter().collect();
+        points.push(Point::new(
+            "atlas_billing_data_stale",
+            vec![],
+            stale as u8 as f64,
+            None,
+        ));
+        points.push(Point::new(
+            "atlas_billing_scrape_success",
+            vec![],
+            !stale as u8 as f64,
+            None,
+        ));
+        // Age of the newest usage record, which keeps growing when Atlas stops metering
+        // even though the api itself answers fine
+        if let Some(newest) = billing.newest_end_date() {
+            let age = Utc::now().timestamp_millis() - newest;
+            points.push(Point::new(
+                "atlas_billing_data_age_seconds",
+                vec![],
+                age as f64 / 1000.0,
+                None,
+            ));
+        }
+        let fetched = self.fetched.load(Ordering::SeqCst);
+        points.push(Point::new(
+            "atlas_billing_last_successful_fetch_timestamp_seconds",
+            vec![],
+            fetched as f64 / 1000.0,
+            None,
+        ));
+        points
+    }
+
+    async fn refresh(&self) -> Result<(), RestError> {
+        let cached = || self.cache.read().expect("billing cache poisoned").clone();
+        let result = match self.from_disk.load(Ordering::SeqCst) {
+            // The first fetch after a restart is still in flight, so serve the disk cache
+            true => match cached() {
+                Some(billing) => Ok((billing, true)),
+                None => self.get_billing().await.map(|billing| (billing, false)),
+            },
+            false => match self.get_billing().await {
+                Ok(billing) => Ok((billing, false)),
+                // Keep serving the last successful aggregation while Atlas can not be reached
+                Err(e) => match cached() {
+                    // Including while the circuit breaker pauses calls to Atlas
+                    Some(billing) if self.serve_stale => {
+                        log::error!("Failed to get billing, serving stale data: {}", e);
+                        Ok((billing, true))
                     }
+                    _ => Err(e),
+                },
+            },
+        };
+        let (billing, stale) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                let mut points: Vec<Point> = self.circuit_point().into_iter().collect();
+                points.extend(self.usage_points());
+                points.push(Point::new(
+                    "atlas_billing_scrape_success",
+                    vec![],
+                    0.0,
+                    None,
+                ));
+                self.registry.set_points(&self.named(points));
+                return Err(e);
+            }
+        };
+
+        // The billing series are only computed again for an aggregation that has changed
+        let (unchanged, series) = {
+            let mut rendered = self.rendered.lock().expect("rendered billing poisoned");
+            match &*rendered {
+                Some((r, series)) if Arc::ptr_eq(r, &billing) => (true, series.clone()),
+                _ => {
+                    let series = Arc::new(self.series.points(&billing));
+                    *rendered = Some((billing.clone(), series.clone()));
+                    (false, series)
                 }
             }
+        };
+
+        // Only an aggregation that has changed starts a new refresh, so only then do the
+        // series that are left out of it start to expire
+        if unchanged {
+            // Stale data served after a failed fetch is not a refresh that was skipped
+            if !stale {
+                log::debug!("Billing has not changed since the last refresh, skipping");
+                metrics::increment_counter!("atlas_billing_refresh_skipped_total");
+            }
+        } else {
+            let refresh = self.registry.start_refresh();
+            tracing::Span::current().record("refresh", refresh);
         }
 
-        log::debug!("Total: {:?}", map_total);
-        log::debug!("Rates: {:?}", map_rate);
-
-        for (_key, value) in map_total {
-            let labels = [
-                ("cluster_name", value.cluster_name.unwrap_or("".to_string())),
-                ("group_name", value.group_name.unwrap_or("".to_string())),
-                ("sku", value.sku.clone()),
-            ];
-            metrics::gauge!(
-                "atlas_billing_item_cents_total",
-                value.total_price_cents as f64,
-                &labels
-            );
-        }
-
-        for (_key, value) in map_rate {
-            let labels = [
-                ("cluster_name", value.cluster_name.unwrap_or("".to_string())),
-                ("group_name", value.group_name.unwrap_or("".to_string())),
-                ("sku", value.sku.clone()),
-            ];
-
-            if value.unit == "GB hours" || value.unit == "server hours" {
-                // Get overall rate in cents per hour
-                metrics::gauge!(
-                    "atlas_billing_item_cents_rate",
-                    value.unit_price_dollars,
-                    &labels
-                );
-            } else {
-                // Convert cents per day to cents per hour
-                // Get overall rate in cents per hour
-                let rate = value.total_price_cents as f64 / value.quantity / 100.0 / 24.0;
-                metrics::gauge!("atlas_billing_item_cents_rate", rate, &labels);
+        let mut points = series.to_vec();
+        if let Some(exchange) = &self.exchange {
+            match exchange.points(&series).await {
+                Ok(normalized) => points.extend(normalized),
+                Err(e) => log::error!("Failed to normalize currency: {}", e),
             }
         }
+        points.extend(self.usage_points());
+        points.extend(self.status_points(&billing, stale));
+        let mut points = self.named(points);
+        points.sort_by(|a, b| (a.name, &a.labels).cmp(&(b.name, &b.labels)));
+
+        let snapshot = Snapshot {
+            billing: &billing,
+            points: &points,
+            unchanged,
+            stale,
+        };
+        for sink in &self.sinks {
+            if let Err(e) = sink.emit(&snapshot).await {
+                log::error!("Failed to emit billing to {}: {}", sink.name(), e);
+            }
+        }
+
+        // Remove series that have not appeared in the invoice for a while, once every sink
+        // has had its turn
+        if !unchanged {
+            self.registry.expire();
+        }
 
         Ok(())
     }
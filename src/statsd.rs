@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::metrics::Point;
+use crate::sink::{Emit, Sink, Snapshot};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Keep datagrams below the common ethernet MTU, as recommended for dogstatsd
+const MAX_PACKET: usize = 1432;
+
+// Emits the billing gauges as dogstatsd gauges with tags
+#[derive(Debug, Clone)]
+pub struct Statsd {
+    address: String,
+    tags: HashMap<String, String>,
+}
+
+impl Statsd {
+    // Tag mapping is given as comma separated label=tag pairs, other labels keep their name
+    pub fn new(host: &str, port: &str, tags: Option<&str>) -> Self {
+        let tags = tags
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect();
+
+        Statsd {
+            address: format!("{host}:{port}"),
+            tags,
+        }
+    }
+
+    fn line(&self, point: &Point) -> String {
+        let tags: Vec<String> = point
+            .labels
+            .iter()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(k, v)| {
+                let key = self.tags.get(*k).map(|t| t.as_str()).unwrap_or(k);
+                format!("{}:{}", key, v.replace([',', '|', '#'], "_"))
+            })
+            .collect();
+
+        match tags.is_empty() {
+            true => format!("{}:{}|g", point.name, point.value),
+            false => format!("{}:{}|g|#{}", point.name, point.value, tags.join(",")),
+        }
+    }
+
+    pub async fn push(&self, points: &[Point]) -> BoxResult<()> {
+        let addr = lookup_host(&self.address)
+            .await?
+            .next()
+            .ok_or_else(|| format!("could not resolve {}", self.address))?;
+        let socket = match addr.is_ipv4() {
+            true => UdpSocket::bind("0.0.0.0:0").await?,
+            false => UdpSocket::bind("[::]:0").await?,
+        };
+        socket.connect(addr).await?;
+
+        // Pack as many lines as fit into each datagram
+        let mut packet = String::new();
+        for point in points {
+            let line = self.line(point);
+            if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET {
+                socket.send(packet.as_bytes()).await?;
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            socket.send(packet.as_bytes()).await?;
+        }
+
+        log::info!(
+            "{{\"fn\": \"statsd_push\", \"address\":\"{}\", \"points\":{}}}",
+            self.address,
+            points.len()
+        );
+        Ok(())
+    }
+}
+
+impl Sink for Statsd {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    fn emit<'a>(&'a self, snapshot: &'a Snapshot<'a>) -> Emit<'a> {
+        Box::pin(async move { self.push(snapshot.points).await })
+    }
+}
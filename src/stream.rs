@@ -0,0 +1,76 @@
+// Parse json responses as they arrive, rather than buffering the whole body first, so that
+// invoices with tens of thousands of line items don't need the raw bytes and the parsed
+// invoice in memory at the same time
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use serde::de::DeserializeOwned;
+use std::io::{BufReader, Read};
+use tokio::sync::mpsc;
+
+use crate::error::Error as RestError;
+
+// Chunks read ahead of the parser
+const CHUNKS: usize = 4;
+
+// Blocking reader over the chunks received from the body. serde_json reads a byte at a time,
+// so it is read through a BufReader.
+struct ChunkReader {
+    rx: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+// Parse a response body, failing once more than max bytes have been received
+pub async fn parse<T>(mut body: Body, max: u64) -> Result<T, RestError>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(CHUNKS);
+    let parser = tokio::task::spawn_blocking(move || {
+        serde_json::from_reader::<_, T>(BufReader::new(ChunkReader {
+            rx,
+            chunk: Bytes::new(),
+        }))
+    });
+
+    let mut read: u64 = 0;
+    let mut failure = None;
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                failure = Some(RestError::Hyper(e));
+                break;
+            }
+        };
+        read += chunk.len() as u64;
+        if read > max {
+            failure = Some(RestError::TooLarge(max));
+            break;
+        }
+        // The parser stops early on invalid json, and reports why below
+        if tx.send(chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+
+    let parsed = parser.await.expect("json parser panicked");
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(parsed?),
+    }
+}
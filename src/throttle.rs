@@ -0,0 +1,113 @@
+// Pace requests to Atlas with a token bucket shared by every refresh and org, so that the
+// exporter stays within the rate limit of the api key. Requests wait for a token rather
+// than failing.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+#[derive(Debug)]
+pub struct Throttle {
+    // Tokens per second
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Goes negative while requests are waiting for tokens
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(config: &Config) -> Option<Arc<Throttle>> {
+        match config.atlas_rate_limit > 0 {
+            true => Some(Arc::new(Throttle::with_rate(
+                config.atlas_rate_limit,
+                config.atlas_rate_limit_burst,
+                Instant::now(),
+            ))),
+            false => None,
+        }
+    }
+
+    fn with_rate(per_minute: u32, burst: u32, now: Instant) -> Throttle {
+        let burst = f64::from(burst.max(1));
+        Throttle {
+            rate: f64::from(per_minute) / 60.0,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: now,
+            }),
+        }
+    }
+
+    // Take a token, returning how long to wait for it when the bucket has run out
+    fn take(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().expect("throttle poisoned");
+        bucket.tokens = (bucket.tokens
+            + now.saturating_duration_since(bucket.updated).as_secs_f64() * self.rate)
+            .min(self.burst);
+        bucket.updated = now;
+        bucket.tokens -= 1.0;
+        match bucket.tokens < 0.0 {
+            true => Some(Duration::from_secs_f64(-bucket.tokens / self.rate)),
+            false => None,
+        }
+    }
+
+    // Take a token, waiting for one to become available. Tokens are handed out in the
+    // order they were asked for.
+    pub async fn acquire(&self) {
+        if let Some(wait) = self.take(Instant::now()) {
+            log::debug!("Waiting {:?} for the Atlas rate limit", wait);
+            metrics::increment_counter!("atlas_api_throttled_total");
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_waits() {
+        let start = Instant::now();
+        let throttle = Throttle::with_rate(60, 2, start);
+
+        assert_eq!(throttle.take(start), None);
+        assert_eq!(throttle.take(start), None);
+
+        // At one request per second, each request beyond the burst waits a second longer
+        assert_eq!(throttle.take(start), Some(Duration::from_secs(1)));
+        assert_eq!(throttle.take(start), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn refills_up_to_burst() {
+        let start = Instant::now();
+        let throttle = Throttle::with_rate(120, 2, start);
+        assert_eq!(throttle.take(start), None);
+        assert_eq!(throttle.take(start), None);
+
+        // Two requests per second refill the bucket in a second, but no further than the burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(throttle.take(later), None);
+        assert_eq!(throttle.take(later), None);
+        assert_eq!(throttle.take(later), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn slowest_rate_waits_a_minute() {
+        let start = Instant::now();
+        let throttle = Throttle::with_rate(1, 0, start);
+
+        // A burst of 0 still lets a single request through
+        assert_eq!(throttle.take(start), None);
+        assert_eq!(throttle.take(start), Some(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,83 @@
+// W3C trace context propagation, so that traces passing through the exporter are joined up
+// from the inbound request to the outbound Atlas requests
+#[cfg(feature = "cli")]
+use axum::extract::ConnectInfo;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+#[cfg(feature = "cli")]
+use hyper::Request;
+use opentelemetry::global;
+#[cfg(feature = "cli")]
+use opentelemetry::propagation::Extractor;
+use opentelemetry::propagation::Injector;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+#[cfg(feature = "cli")]
+use crate::listener::Peer;
+#[cfg(feature = "cli")]
+use crate::request_id::X_REQUEST_ID;
+
+#[cfg(feature = "cli")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "cli")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+// Span for an inbound request, continuing the trace of its traceparent header if any
+#[cfg(feature = "cli")]
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id = request
+            .headers()
+            .get(X_REQUEST_ID)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default(),
+        client_subject = tracing::field::Empty,
+    );
+    // Only set for clients that presented a certificate
+    if let Some(ConnectInfo(Peer {
+        subject: Some(subject),
+        ..
+    })) = request.extensions().get::<ConnectInfo<Peer>>()
+    {
+        span.record("client_subject", subject.as_str());
+    }
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
+// Add the traceparent of the current span to an outbound request
+pub fn inject(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
@@ -0,0 +1,67 @@
+// Check the configuration and the api key against Atlas, and print a diagnosis for each org,
+// so that misconfigurations show up before deploying rather than as 401's and 403's at runtime
+use serde_json::Value;
+use std::error::Error;
+
+use crate::error::Error as RestError;
+use crate::State;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+fn diagnose(org: &str, e: &RestError) -> String {
+    match e {
+        RestError::Unauthorized => "Atlas rejected the api key, check the public and private key, and that the key has not been deleted".to_string(),
+        RestError::Forbidden => format!("the api key has no access to org {org}, or its access list does not include the address of this host"),
+        RestError::NotFound => format!("org {org} was not found, check the org id"),
+        e if e.upstream_status() == Some(429) => {
+            "Atlas is rate limiting the api key, try again in a minute".to_string()
+        }
+        e if e.unavailable() => format!("Atlas could not be reached, check the network, proxy and dns settings: {e}"),
+        e => format!("unexpected response from Atlas: {e}"),
+    }
+}
+
+pub async fn validate(state: State) -> BoxResult<()> {
+    // State has already parsed and checked the options, such as certificates and proxies
+    println!("Configuration: ok, checking {} org(s)", state.orgs.len());
+
+    let mut failed = 0;
+    for org in &state.orgs {
+        let name = match state.get(&format!("orgs/{org}")).await {
+            Ok(response) => {
+                let org: Value = serde_json::from_slice(&state.read(response).await?)?;
+                org["name"].as_str().map(|name| format!(" ({name})"))
+            }
+            Err(e) => {
+                println!("Org {org}: error, {}", diagnose(org, &e));
+                failed += 1;
+                continue;
+            }
+        };
+        println!(
+            "Org {org}{}: ok, the api key can read the org",
+            name.unwrap_or_default()
+        );
+
+        // Invoices need the Organization Billing Viewer role, or one that includes it
+        match state
+            .get(&format!("orgs/{org}/invoices?itemsPerPage=1"))
+            .await
+        {
+            Ok(_) => println!("Org {org}: ok, the api key can read invoices"),
+            Err(RestError::Forbidden) => {
+                println!("Org {org}: error, the api key can not read invoices, grant it the Organization Billing Viewer role");
+                failed += 1;
+            }
+            Err(e) => {
+                println!("Org {org}: error, {}", diagnose(org, &e));
+                failed += 1;
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{failed} check(s) failed").into()),
+    }
+}